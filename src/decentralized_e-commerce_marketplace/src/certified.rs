@@ -0,0 +1,68 @@
+// Certified data: a Merkle tree over every product and order whose root hash is set as the
+// canister's certified data, so that query responses can be verified against the subnet's
+// signature.
+//
+// The tree has two labeled subtrees, `orders` and `products`, each mapping a record's id
+// (8 bytes, big-endian) to the SHA-256 of its Candid encoding. It lives on the heap and is
+// rebuilt from stable memory after an upgrade.
+use candid::CandidType;
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+pub const ORDERS: &str = "orders";
+pub const PRODUCTS: &str = "products";
+
+thread_local! {
+    static TREE: RefCell<RbTree<&'static str, RbTree<[u8; 8], Hash>>> = RefCell::new(empty_tree());
+}
+
+fn empty_tree() -> RbTree<&'static str, RbTree<[u8; 8], Hash>> {
+    let mut tree = RbTree::default();
+    tree.insert(ORDERS, RbTree::default());
+    tree.insert(PRODUCTS, RbTree::default());
+    tree
+}
+
+// Records the value of record `id` under `label` without publishing the new root; used while
+// rebuilding the tree
+pub fn insert<T: CandidType>(label: &'static str, id: u64, record: &T) {
+    let encoded = candid::encode_one(record).expect("Records always encode to Candid");
+    let hash: Hash = Sha256::digest(encoded).into();
+    TREE.with(|tree| tree.borrow_mut().modify(label.as_bytes(), |records| records.insert(id.to_be_bytes(), hash)));
+}
+
+// Records the value of record `id` under `label` and publishes the new root
+pub fn certify<T: CandidType>(label: &'static str, id: u64, record: &T) {
+    insert(label, id, record);
+    publish();
+}
+
+pub fn uncertify(label: &'static str, id: u64) {
+    TREE.with(|tree| tree.borrow_mut().modify(label.as_bytes(), |records| records.delete(&id.to_be_bytes())));
+    publish();
+}
+
+// Sets the root hash as the canister's certified data. Only allowed outside of queries.
+pub fn publish() {
+    let root = TREE.with(|tree| tree.borrow().root_hash());
+    crate::system::set_certified_data(&root);
+}
+
+// CBOR encoding of the witness for record `id` under `label`: a proof of the record's hash,
+// or of its absence
+pub fn witness(label: &'static str, id: u64) -> Result<Vec<u8>, String> {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = tree.nested_witness(label.as_bytes(), |records| records.witness(&id.to_be_bytes()));
+        encode(&witness)
+    })
+}
+
+fn encode(tree: &HashTree) -> Result<Vec<u8>, String> {
+    let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+    serializer.self_describe().map_err(|err| err.to_string())?;
+    tree.serialize(&mut serializer).map_err(|err| err.to_string())?;
+    Ok(serializer.into_inner())
+}
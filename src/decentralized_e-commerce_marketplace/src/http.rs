@@ -0,0 +1,116 @@
+// Types of the HTTP gateway interface (`http_request`) and small helpers for building responses
+use candid::CandidType;
+use std::collections::BTreeMap;
+
+pub type HeaderField = (String, String);
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>, // Asks the gateway to repeat the request as `http_request_update`
+}
+
+impl HttpResponse {
+    pub fn ok(content_type: &str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+            ],
+            body,
+            upgrade: None,
+        }
+    }
+
+    pub fn json(status_code: u16, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+            ],
+            body,
+            upgrade: None,
+        }
+    }
+
+    pub fn error(status_code: u16, msg: &str) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+            body: msg.as_bytes().to_vec(),
+            upgrade: None,
+        }
+    }
+
+    // Answer to a query that must be made again as an update call
+    pub fn upgrade() -> Self {
+        HttpResponse {
+            status_code: 200,
+            headers: Vec::new(),
+            body: Vec::new(),
+            upgrade: Some(true),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+// Splits a request URL into its path segments and query parameters. Percent-encoding is only
+// decoded in parameter values; paths served by the canister are plain ASCII.
+pub fn parse_url(url: &str) -> (Vec<String>, BTreeMap<String, String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.to_string())
+        .collect();
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key.to_string(), percent_decode(value))
+        })
+        .collect();
+    (segments, params)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let high = (bytes[i + 1] as char).to_digit(16);
+                let low = (bytes[i + 2] as char).to_digit(16);
+                match (high, low) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,402 @@
+// ICRC-1/ICRC-2 token ledger integration backing the escrow funds
+use candid::{CandidType, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, fmt};
+
+pub type Subaccount = [u8; 32];
+
+// An ICRC-1 account: an owner principal plus an optional 32-byte subaccount
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+// Fixed 63-byte layout so accounts can key the mock ledger's stable maps:
+// principal length, principal padded to 29 bytes, subaccount flag, subaccount
+impl Storable for Account {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let owner = self.owner.as_slice();
+        let mut bytes = vec![0u8; 63];
+        bytes[0] = owner.len() as u8;
+        bytes[1..1 + owner.len()].copy_from_slice(owner);
+        if let Some(subaccount) = self.subaccount {
+            bytes[30] = 1;
+            bytes[31..].copy_from_slice(&subaccount);
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let owner = Principal::from_slice(&bytes[1..1 + bytes[0] as usize]);
+        let subaccount = (bytes[30] == 1).then(|| bytes[31..63].try_into().expect("subaccount is 32 bytes"));
+        Account { owner, subaccount }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 63,
+        is_fixed_size: true,
+    };
+}
+
+// Which ledger holds the escrowed tokens; chosen at init, or on the upgrade of a canister
+// installed before payments existed
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum LedgerConfig {
+    Icrc { canister_id: Principal },
+    Mock,
+}
+
+// Arguments of `icrc1_transfer`
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Arguments of `icrc2_transfer_from`
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Subaccount>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Why a transfer did not go through, which decides whether it may be sent again unchanged
+#[derive(Debug)]
+pub enum TransferFailure {
+    Rejected(String), // The ledger answered with an error; nothing moved
+    TooOld,           // Sent after the ledger's deduplication window; an earlier attempt may have landed
+    Unknown(String),  // No answer came back, so the transfer may or may not have landed
+}
+
+impl fmt::Display for TransferFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferFailure::Rejected(msg) | TransferFailure::Unknown(msg) => f.write_str(msg),
+            TransferFailure::TooOld => f.write_str("The ledger no longer accepts a transfer created this long ago"),
+        }
+    }
+}
+
+// How long ledgers deduplicate transfers by their creation time, as for the ICP ledger
+pub const TRANSACTION_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// The operations the escrow flow needs from a token ledger. Amounts and block indexes are
+// plain `u64`s here; implementations convert to and from `Nat` at the boundary.
+pub trait Ledger {
+    async fn fee(&self) -> Result<u64, String>;
+
+    async fn balance(&self, account: Account) -> Result<u64, String>;
+
+    // Moves `amount` out of one of the canister's own subaccounts, paying `fee`. A transfer sent
+    // again with the same arguments, including `created_at_time`, within the ledger's
+    // deduplication window answers with the block of the first one instead of moving the
+    // amount twice.
+    async fn transfer(&self, from_subaccount: Option<Subaccount>, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure>;
+
+    // Pulls `amount` from `from` using an ICRC-2 allowance granted to the canister
+    // Pulls `amount` from an account that approved the canister, deduplicated like `transfer`
+    async fn transfer_from(&self, from: Account, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure>;
+}
+
+// Talks to a real ICRC-1/ICRC-2 ledger canister (or a local one deployed with dfx)
+pub struct IcrcLedger {
+    canister_id: Principal,
+}
+
+impl Ledger for IcrcLedger {
+    async fn fee(&self) -> Result<u64, String> {
+        let (fee,): (Nat,) = ic_cdk::call(self.canister_id, "icrc1_fee", ())
+            .await
+            .map_err(|(code, msg)| format!("icrc1_fee call failed ({:?}): {}", code, msg))?;
+        nat_to_u64(&fee)
+    }
+
+    async fn balance(&self, account: Account) -> Result<u64, String> {
+        let (balance,): (Nat,) = ic_cdk::call(self.canister_id, "icrc1_balance_of", (account,))
+            .await
+            .map_err(|(code, msg)| format!("icrc1_balance_of call failed ({:?}): {}", code, msg))?;
+        nat_to_u64(&balance)
+    }
+
+    async fn transfer(&self, from_subaccount: Option<Subaccount>, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure> {
+        let arg = TransferArg {
+            from_subaccount,
+            to,
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(fee)),
+            memo: Some(memo),
+            created_at_time: Some(created_at_time),
+        };
+        let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(self.canister_id, "icrc1_transfer", (arg,))
+            .await
+            .map_err(|(code, msg)| TransferFailure::Unknown(format!("icrc1_transfer call failed ({:?}): {}", code, msg)))?;
+        match result {
+            Ok(block) => nat_to_u64(&block).map_err(TransferFailure::Unknown),
+            // An earlier attempt landed, but its answer never made it back
+            Err(TransferError::Duplicate { duplicate_of }) => nat_to_u64(&duplicate_of).map_err(TransferFailure::Unknown),
+            Err(TransferError::TooOld) => Err(TransferFailure::TooOld),
+            Err(err) => Err(TransferFailure::Rejected(format!("icrc1_transfer rejected: {:?}", err))),
+        }
+    }
+
+    async fn transfer_from(&self, from: Account, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure> {
+        let arg = TransferFromArgs {
+            spender_subaccount: None,
+            from,
+            to,
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(fee)),
+            memo: Some(memo),
+            created_at_time: Some(created_at_time),
+        };
+        let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(self.canister_id, "icrc2_transfer_from", (arg,))
+            .await
+            .map_err(|(code, msg)| TransferFailure::Unknown(format!("icrc2_transfer_from call failed ({:?}): {}", code, msg)))?;
+        match result {
+            Ok(block) => nat_to_u64(&block).map_err(TransferFailure::Unknown),
+            Err(TransferFromError::Duplicate { duplicate_of }) => nat_to_u64(&duplicate_of).map_err(TransferFailure::Unknown),
+            Err(TransferFromError::TooOld) => Err(TransferFailure::TooOld),
+            Err(err) => Err(TransferFailure::Rejected(format!("icrc2_transfer_from rejected: {:?}", err))),
+        }
+    }
+}
+
+fn nat_to_u64(value: &Nat) -> Result<u64, String> {
+    u64::try_from(&value.0).map_err(|_| format!("Ledger value {} does not fit in u64", value))
+}
+
+// Fee charged by the mock ledger on every transfer, mirroring the ICP ledger
+pub const MOCK_LEDGER_FEE: u64 = 10_000;
+
+// In-canister ledger for local testing. Balances, allowances and the block index live in
+// stable memory, so tokens held in escrow survive an upgrade like the escrow records do.
+pub struct MockLedger;
+
+thread_local! {
+    static MOCK_BALANCES: RefCell<StableBTreeMap<Account, u64, crate::Memory>> = RefCell::new(
+        StableBTreeMap::init(crate::MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))))
+    );
+
+    // (owner, spender) -> remaining allowance
+    static MOCK_ALLOWANCES: RefCell<StableBTreeMap<(Account, Account), u64, crate::Memory>> = RefCell::new(
+        StableBTreeMap::init(crate::MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))))
+    );
+
+    static MOCK_BLOCK_INDEX: RefCell<Cell<u64, crate::Memory>> = RefCell::new(
+        Cell::init(crate::MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))), 0)
+            .expect("Cannot create the mock ledger block index")
+    );
+
+    // Candid encoding of each transfer's arguments -> its block, for deduplication. Kept on the
+    // heap: an upgrade forgets them, which a retry then treats like an expired window.
+    static MOCK_RECENT_TRANSFERS: RefCell<BTreeMap<Vec<u8>, u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[cfg(test)]
+thread_local! {
+    static LOSE_NEXT_REPLY: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+impl MockLedger {
+    pub fn mint(to: Account, amount: u64) -> Result<u64, String> {
+        let balance = Self::balance_of(&to);
+        let credited = balance.checked_add(amount).ok_or_else(|| overflow(balance, amount))?;
+        MOCK_BALANCES.with(|balances| balances.borrow_mut().insert(to, credited));
+        Ok(Self::next_block())
+    }
+
+    pub fn approve(from: Account, spender: Account, amount: u64) -> u64 {
+        MOCK_ALLOWANCES.with(|allowances| allowances.borrow_mut().insert((from, spender), amount));
+        Self::next_block()
+    }
+
+    pub fn balance_of(account: &Account) -> u64 {
+        MOCK_BALANCES.with(|balances| balances.borrow().get(account).unwrap_or(0))
+    }
+
+    fn debit(from: &Account, amount: u64) -> Result<(), String> {
+        MOCK_BALANCES.with(|balances| {
+            let mut balances = balances.borrow_mut();
+            let balance = balances.get(from).unwrap_or(0);
+            if balance < amount {
+                return Err(format!("Insufficient funds: balance {}, needed {}", balance, amount));
+            }
+            balances.insert(*from, balance - amount);
+            Ok(())
+        })
+    }
+
+    // Checked before debiting the sender, so that a transfer the recipient cannot hold fails
+    // without moving anything
+    fn check_credit(to: &Account, amount: u64) -> Result<(), String> {
+        let balance = Self::balance_of(to);
+        balance.checked_add(amount).map(|_| ()).ok_or_else(|| overflow(balance, amount))
+    }
+
+    // Makes the next transfer land without its answer reaching the caller, as when a call
+    // to a real ledger fails after the ledger executed it
+    #[cfg(test)]
+    pub fn lose_next_reply() {
+        LOSE_NEXT_REPLY.with(|lose| lose.set(true));
+    }
+
+    // Checks the creation time and the fee, then looks the transfer up among the recent ones,
+    // in the order an ICRC-1 ledger does
+    fn check_transfer(key: &[u8], fee: u64, created_at_time: u64) -> Result<Option<u64>, TransferFailure> {
+        if created_at_time.saturating_add(TRANSACTION_WINDOW_NANOS) < crate::system::time() {
+            return Err(TransferFailure::TooOld);
+        }
+        if fee != MOCK_LEDGER_FEE {
+            return Err(TransferFailure::Rejected(format!("Bad fee {}, expected {}", fee, MOCK_LEDGER_FEE)));
+        }
+        Ok(MOCK_RECENT_TRANSFERS.with(|recent| recent.borrow().get(key).copied()))
+    }
+
+    fn reply(block: u64) -> Result<u64, TransferFailure> {
+        #[cfg(test)]
+        if LOSE_NEXT_REPLY.with(|lose| lose.replace(false)) {
+            return Err(TransferFailure::Unknown("The mock ledger's reply was lost".to_string()));
+        }
+        Ok(block)
+    }
+
+    fn next_block() -> u64 {
+        MOCK_BLOCK_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            let next = index.get() + 1;
+            index.set(next).expect("Cannot update the mock ledger block index");
+            next
+        })
+    }
+}
+
+impl Ledger for MockLedger {
+    async fn fee(&self) -> Result<u64, String> {
+        Ok(MOCK_LEDGER_FEE)
+    }
+
+    async fn balance(&self, account: Account) -> Result<u64, String> {
+        Ok(Self::balance_of(&account))
+    }
+
+    async fn transfer(&self, from_subaccount: Option<Subaccount>, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure> {
+        let key = candid::encode_args(("icrc1_transfer", from_subaccount, to, amount, fee, memo, created_at_time)).expect("Transfer arguments encode to Candid");
+        if let Some(block) = Self::check_transfer(&key, fee, created_at_time)? {
+            return Self::reply(block);
+        }
+
+        let from = Account { owner: crate::system::id(), subaccount: from_subaccount };
+        let needed = amount.checked_add(MOCK_LEDGER_FEE).ok_or_else(|| overflow(amount, MOCK_LEDGER_FEE)).map_err(TransferFailure::Rejected)?;
+        Self::check_credit(&to, amount).map_err(TransferFailure::Rejected)?;
+        Self::debit(&from, needed).map_err(TransferFailure::Rejected)?;
+        let block = Self::mint(to, amount).map_err(TransferFailure::Rejected)?;
+        MOCK_RECENT_TRANSFERS.with(|recent| recent.borrow_mut().insert(key, block));
+        Self::reply(block)
+    }
+
+    async fn transfer_from(&self, from: Account, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure> {
+        let key = candid::encode_args(("icrc2_transfer_from", from, to, amount, fee, memo, created_at_time)).expect("Transfer arguments encode to Candid");
+        if let Some(block) = Self::check_transfer(&key, fee, created_at_time)? {
+            return Self::reply(block);
+        }
+
+        let spender = Account { owner: crate::system::id(), subaccount: None };
+        let needed = amount.checked_add(MOCK_LEDGER_FEE).ok_or_else(|| overflow(amount, MOCK_LEDGER_FEE)).map_err(TransferFailure::Rejected)?;
+        let allowance = MOCK_ALLOWANCES.with(|allowances| allowances.borrow().get(&(from, spender)).unwrap_or(0));
+        if allowance < needed {
+            return Err(TransferFailure::Rejected(format!("Insufficient allowance: allowance {}, needed {}", allowance, needed)));
+        }
+        Self::check_credit(&to, amount).map_err(TransferFailure::Rejected)?;
+        Self::debit(&from, needed).map_err(TransferFailure::Rejected)?;
+        MOCK_ALLOWANCES.with(|allowances| allowances.borrow_mut().insert((from, spender), allowance - needed));
+        let block = Self::mint(to, amount).map_err(TransferFailure::Rejected)?;
+        MOCK_RECENT_TRANSFERS.with(|recent| recent.borrow_mut().insert(key, block));
+        Self::reply(block)
+    }
+}
+
+fn overflow(value: u64, amount: u64) -> String {
+    format!("Amount {} cannot be added to {} without overflowing", amount, value)
+}
+
+// Dispatches to whichever ledger the canister was initialised with
+pub enum ConfiguredLedger {
+    Icrc(IcrcLedger),
+    Mock(MockLedger),
+}
+
+impl From<LedgerConfig> for ConfiguredLedger {
+    fn from(config: LedgerConfig) -> Self {
+        match config {
+            LedgerConfig::Icrc { canister_id } => ConfiguredLedger::Icrc(IcrcLedger { canister_id }),
+            LedgerConfig::Mock => ConfiguredLedger::Mock(MockLedger),
+        }
+    }
+}
+
+impl Ledger for ConfiguredLedger {
+    async fn fee(&self) -> Result<u64, String> {
+        match self {
+            ConfiguredLedger::Icrc(ledger) => ledger.fee().await,
+            ConfiguredLedger::Mock(ledger) => ledger.fee().await,
+        }
+    }
+
+    async fn balance(&self, account: Account) -> Result<u64, String> {
+        match self {
+            ConfiguredLedger::Icrc(ledger) => ledger.balance(account).await,
+            ConfiguredLedger::Mock(ledger) => ledger.balance(account).await,
+        }
+    }
+
+    async fn transfer(&self, from_subaccount: Option<Subaccount>, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure> {
+        match self {
+            ConfiguredLedger::Icrc(ledger) => ledger.transfer(from_subaccount, to, amount, fee, memo, created_at_time).await,
+            ConfiguredLedger::Mock(ledger) => ledger.transfer(from_subaccount, to, amount, fee, memo, created_at_time).await,
+        }
+    }
+
+    async fn transfer_from(&self, from: Account, to: Account, amount: u64, fee: u64, memo: Vec<u8>, created_at_time: u64) -> Result<u64, TransferFailure> {
+        match self {
+            ConfiguredLedger::Icrc(ledger) => ledger.transfer_from(from, to, amount, fee, memo, created_at_time).await,
+            ConfiguredLedger::Mock(ledger) => ledger.transfer_from(from, to, amount, fee, memo, created_at_time).await,
+        }
+    }
+}
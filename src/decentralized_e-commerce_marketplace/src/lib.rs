@@ -1,15 +1,26 @@
 #[macro_use]
 extern crate serde;
-use regex;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::Principal;
+use system::{caller, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell, collections::{BTreeMap, BTreeSet}, ops, thread::LocalKey, time::Duration};
 use regex::Regex;
 
+mod certified;
+mod http;
+mod ledger;
+mod schema;
+mod system;
+#[cfg(test)]
+mod tests;
+use http::{HttpRequest, HttpResponse};
+use ledger::{Account, ConfiguredLedger, Ledger, LedgerConfig, MockLedger, Subaccount, TransferFailure};
+
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type CartKey = ((u64, u64), u64); // ((buyer id, product id), variant id or NO_VARIANT)
 
 // Represents a product listed by a seller
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -18,35 +29,251 @@ struct Product {
     name: String,
     description: String,
     price: u64,
-    stock_quantity: u32,
+    shipping_fee: u64, // Flat shipping charged once per order
+    discount_bps: u32, // Seller discount in basis points of the subtotal
+    stock_quantity: u32,    // Units available for new orders
+    reserved_quantity: u32, // Units held by unpaid orders, already taken out of `stock_quantity`
     seller_id: u64,
+    category_id: Option<u64>,       // Primary category
+    secondary_category_ids: Vec<u64>, // Further categories the product is also listed under
+    media_ids: Vec<u64>,              // Uploaded images in display order, thumbnails excluded
+    rating: RatingSummary,
     created_at: u64,
     updated_at: Option<u64>,
 }
 
-// Represents a user in the marketplace (buyer or seller)
+// Aggregate of the review ratings of a product
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RatingSummary {
+    count: u64,
+    total: u64,   // Sum of all ratings
+    average: f64, // 0 while there are no reviews
+    histogram: [u64; 5], // Number of 1- to 5-star reviews
+}
+
+impl RatingSummary {
+    fn add(&mut self, rating: u8) {
+        self.count += 1;
+        self.total += rating as u64;
+        self.histogram[rating as usize - 1] += 1;
+        self.average = self.total as f64 / self.count as f64;
+    }
+
+    // Average in hundredths of a star, for sorting
+    fn sort_key(&self) -> u64 {
+        (self.total * 100).checked_div(self.count).unwrap_or(0)
+    }
+}
+
+// A buyer's rating and review of one item of a completed order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Review {
+    id: u64,
+    product_id: u64,
+    variant_id: Option<u64>,
+    order_id: u64,
+    buyer_id: u64,
+    seller_id: u64,
+    rating: u8, // 1 to 5 stars
+    text: String,
+    reply: Option<ReviewReply>, // The seller's public answer
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReviewReply {
+    text: String,
+    created_at: u64,
+}
+
+// A purchasable version of a product, e.g. one size and color of a shirt. Once a product has
+// variants, its stock lives on the variants and `Product.stock_quantity` and
+// `reserved_quantity` hold the totals across them.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProductVariant {
+    id: u64,
+    product_id: u64,
+    sku: String, // Seller's stock keeping unit, unique within the product
+    attributes: Vec<VariantAttribute>,
+    price: Option<u64>, // Overrides the product's price when set
+    stock_quantity: u32,
+    reserved_quantity: u32,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct VariantAttribute {
+    name: String,  // e.g. "size"
+    value: String, // e.g. "M"
+}
+
+// An image attached to a product. Its bytes live in MEDIA_CHUNKS, split into chunks of
+// MEDIA_CHUNK_BYTES.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MediaAsset {
+    id: u64,
+    product_id: u64,
+    content_type: String,
+    size: u64, // Total bytes of the image
+    width: u32,
+    height: u32,
+    alt_text: String,
+    thumbnail_of: Option<u64>, // Set when this image is a smaller rendition of another asset
+    status: MediaStatus,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum MediaStatus {
+    Uploading, // Chunks are still being uploaded; not served yet
+    Ready,
+}
+
+// A node of the admin-managed category tree
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Category {
+    id: u64,
+    name: String,
+    parent_id: Option<u64>, // None for top-level categories
+    archived: bool,         // Archived categories keep their products but take no new ones
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Represents a user in the marketplace (buyer or seller)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct User {
     id: u64,
+    principal: Principal, // The caller identity that owns this user
     name: String,
     email: String,
-    role: String, // "buyer", "seller", or "admin"
-    reputation: u8, // Reputation score out of 100
+    role: Role,
+    reputation: u8, // Reputation score out of 100, rewritten whenever the user's reputation counters change
     created_at: u64,
     updated_at: Option<u64>,
 }
 
+// Roles a user can hold; see the permission matrix in the authorization layer below
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum Role {
+    Buyer,
+    Seller,
+    Admin,
+    Arbiter,
+}
+
+impl Role {
+    // Roles users may pick for themselves; the rest are granted by an admin
+    fn is_self_assignable(&self) -> bool {
+        matches!(self, Role::Buyer | Role::Seller)
+    }
+}
+
 // Represents an order placed by a buyer
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Order {
     id: u64,
-    product_id: u64,
     buyer_id: u64,
-    quantity: u32,
+    seller_id: u64,
+    items: Vec<OrderItem>, // All items of an order come from the same seller
     total_price: u64,
-    status: String, // "pending", "completed", "canceled"
+    reserved_until: Option<u64>, // Set while the items are reserved for an unpaid order
+    auto_release_at: Option<u64>, // Set while a delivered order waits for the buyer to confirm
+    status: OrderStatus,
+    status_history: Vec<OrderStatusChange>,
     created_at: u64,
     updated_at: Option<u64>,
+    funding_transfer: Option<LedgerTransfer>, // Saved before `pay_order` pulls the funds, until the ledger's answer is known
+}
+
+// A single product line of an order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OrderItem {
+    product_id: u64,
+    variant_id: Option<u64>, // Set for products sold in variants
+    quantity: u32,
+    price_breakdown: PriceBreakdown,
+}
+
+// Itemized price of an order line, always computed by the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PriceBreakdown {
+    unit_price: u64,
+    quantity: u32,
+    subtotal: u64,
+    discount: u64,
+    shipping: u64,
+    tax: u64,
+    total: u64,
+}
+
+// Lifecycle of an order; legal moves are defined by `OrderStatus::can_transition_to`
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+enum OrderStatus {
+    #[default]
+    Pending,
+    Paid,
+    Shipped,
+    Delivered,
+    Completed,
+    Cancelled,
+    InDispute,
+    Refunded,
+}
+
+impl OrderStatus {
+    const ALL: [OrderStatus; 8] = [
+        OrderStatus::Pending,
+        OrderStatus::Paid,
+        OrderStatus::Shipped,
+        OrderStatus::Delivered,
+        OrderStatus::Completed,
+        OrderStatus::Cancelled,
+        OrderStatus::InDispute,
+        OrderStatus::Refunded,
+    ];
+
+    // Key of the status in `ORDERS_BY_STATUS`
+    fn index_key(&self) -> u8 {
+        *self as u8
+    }
+
+    // Whether the order can still move; completed, cancelled and refunded orders cannot
+    fn is_open(&self) -> bool {
+        !matches!(self, OrderStatus::Completed | OrderStatus::Cancelled | OrderStatus::Refunded)
+    }
+
+    fn can_transition_to(&self, next: OrderStatus) -> bool {
+        use OrderStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Paid)
+                | (Pending, Cancelled)
+                | (Paid, Shipped)
+                | (Paid, Cancelled)
+                | (Paid, InDispute)
+                | (Paid, Refunded)
+                | (Shipped, Delivered)
+                | (Shipped, InDispute)
+                | (Shipped, Refunded)
+                | (Delivered, Completed)
+                | (Delivered, InDispute)
+                | (Delivered, Refunded)
+                | (InDispute, Completed)
+                | (InDispute, Refunded)
+        )
+    }
+}
+
+// A single recorded move in an order's lifecycle
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderStatusChange {
+    from: OrderStatus,
+    to: OrderStatus,
+    changed_by: Option<u64>, // User id, or None when the canister made the change itself
+    changed_at: u64,
 }
 
 // Represents funds held in escrow during a transaction
@@ -55,608 +282,5763 @@ struct Escrow {
     id: u64,
     order_id: u64,
     amount: u64,
-    status: String, // "held", "released", or "refunded"
+    status: EscrowStatus,
+    funding_block: Option<u64>,    // Ledger block that moved the buyer's tokens into escrow
+    settlement_block: Option<u64>, // Ledger block that paid the escrow out on release or refund
+    split: Option<EscrowSplit>,    // How the amount is being or was paid out
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+enum EscrowStatus {
+    #[default]
+    Held,
+    Released,
+    Refunded,
+    Split, // Paid out partly to the buyer and partly to the seller
+}
+
+// Division of an escrow's amount between buyer and seller. Each share pays its own ledger fee.
+// A share's block is set once it has been transferred, so a payout interrupted between the
+// two transfers resumes with the missing one.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct EscrowSplit {
+    buyer_amount: u64,
+    seller_amount: u64,
+    buyer_block: Option<u64>,
+    seller_block: Option<u64>,
+    reason: Option<String>,  // Why the escrow was split; None for full releases and refunds
+    decided_by: Option<u64>, // User who settled the escrow
+    buyer_transfer: Option<LedgerTransfer>,  // As last attempted, while the buyer's share may be in flight
+    seller_transfer: Option<LedgerTransfer>, // Likewise for the seller's share
+}
+
+impl EscrowSplit {
+    fn transfer_mut(&mut self, to_buyer: bool) -> &mut Option<LedgerTransfer> {
+        if to_buyer { &mut self.buyer_transfer } else { &mut self.seller_transfer }
+    }
+
+    // What is still owed out of the escrow account, fees included
+    fn unpaid(&self) -> u64 {
+        let buyer = if self.buyer_block.is_none() { self.buyer_amount } else { 0 };
+        let seller = if self.seller_block.is_none() { self.seller_amount } else { 0 };
+        buyer + seller
+    }
+}
+
+// A ledger transfer exactly as it was sent, whether funding an escrow or paying it out. Retries
+// after a lost answer send it unchanged, so that the ledger recognises one that landed as a
+// duplicate rather than moving the funds twice. It is dropped once the ledger has turned it down.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct LedgerTransfer {
+    amount: u64, // Net of the ledger fee
+    fee: u64,
+    memo: Vec<u8>,
+    created_at_time: u64,
+}
+
+// How the funds of a held escrow are paid out when its order is settled
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Payout {
+    ToSeller,
+    ToBuyer,
+    Split { buyer_amount: u64, reason: String }, // The rest goes to the seller
+}
+
+// A movement of tokens between a user's account and an order's escrow
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Transaction {
+    id: u64,
+    user_id: u64,
+    order_id: u64,
+    escrow_id: u64,
+    kind: TransactionKind,
+    amount: u64,          // Share of the escrow this transaction moved
+    fee: Option<u64>,     // Ledger fee taken out of `amount` on payouts; the user received the rest
+    ledger_block: u64,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum TransactionKind {
+    EscrowFunded, // The buyer paid for the order
+    Refund,       // The buyer got all or part of the escrow back
+    Payout,       // The seller was paid all or part of the escrow
+}
+
+// A disagreement over an order, raised by its buyer or seller and decided by an admin or
+// arbiter. While it is open the order is InDispute and its escrow stays held.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Dispute {
+    id: u64,
+    order_id: u64,
+    opened_by: u64, // User id of the buyer or seller who opened it
+    reason: DisputeReason,
+    status: DisputeStatus,
+    messages: Vec<DisputeMessage>, // Starts with the opening statement
+    assigned_to: Option<u64>,      // Admin or arbiter handling the dispute
+    respond_by: u64, // Until then the other party can answer before a decision is taken without them
+    resolve_by: u64, // When the assignee is expected to have decided
+    resolution: Option<DisputeResolution>,
+    resolved_by: Option<u64>,
     created_at: u64,
     updated_at: Option<u64>,
 }
 
-// Implementing Storable and BoundedStorable for Product, User, Escrow, and Order structs
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum DisputeReason {
+    NotReceived,
+    NotAsDescribed,
+    Damaged,
+    MissingItems,
+    Other,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum DisputeStatus {
+    AwaitingResponse, // The other party has not answered yet
+    UnderReview,
+    Resolved,
+}
+
+// A statement in a dispute's thread, with optional evidence such as links to photos or
+// tracking pages
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DisputeMessage {
+    author_id: u64,
+    body: String,
+    evidence: Vec<String>,
+    created_at: u64,
+}
+
+// Decision closing a dispute
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum DisputeResolution {
+    ReleaseToSeller,                 // The order completes and the seller is paid in full
+    RefundBuyer,                     // The order is refunded in full
+    Split { refund_amount: u64 }, // The buyer gets `refund_amount` back, the seller the rest
+}
+
+// A single change to a product's available stock. The log of a product is append-only and
+// its deltas add up to the current `stock_quantity`.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StockMovement {
+    id: u64,
+    product_id: u64,
+    variant_id: Option<u64>,
+    delta: i64,
+    reason: StockMovementReason,
+    balance_after: u32, // Of the variant for variant movements, of the product otherwise
+    actor_id: Option<u64>, // User who made the change; None for changes driven by an order
+    order_id: Option<u64>,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum StockMovementReason {
+    Listed,
+    Restock,
+    Shrinkage,
+    Correction,
+    OrderReserved,
+    OrderReleased,
+    OrderReturned,
+}
+
+// Reasons a seller can give for adjusting stock by hand
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum StockAdjustmentReason {
+    Restock,    // Delta must be positive
+    Shrinkage,  // Delta must be negative
+    Correction, // Delta can go either way
+}
+
+impl From<StockAdjustmentReason> for StockMovementReason {
+    fn from(reason: StockAdjustmentReason) -> Self {
+        match reason {
+            StockAdjustmentReason::Restock => StockMovementReason::Restock,
+            StockAdjustmentReason::Shrinkage => StockMovementReason::Shrinkage,
+            StockAdjustmentReason::Correction => StockMovementReason::Correction,
+        }
+    }
+}
+
+// Implementing Storable for Product, User, Escrow, and Order structs. Records are wrapped in a
+// versioned envelope, see `schema`, and have no size bound: their fields are limited by the
+// validation of the endpoints that write them instead.
 impl Storable for Product {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match schema::open(bytes.as_ref()) {
+            // Versions 1 and 2 predate `rating`
+            (0..=2, payload) => schema::decode::<ProductV0>(payload).into(),
+            (_, payload) => schema::decode(payload),
+        }
     }
-}
 
-impl BoundedStorable for Product {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 impl Storable for User {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match schema::open(bytes.as_ref()) {
+            (0, payload) => schema::decode_either::<UserV0, UserV0<String>, _>(payload),
+            (_, payload) => schema::decode(payload),
+        }
     }
-}
 
-impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 impl Storable for Order {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match schema::open(bytes.as_ref()) {
+            (0, payload) => schema::decode_either::<OrderV0, OrderV0<String>, _>(payload),
+            (_, payload) => schema::decode(payload),
+        }
     }
-}
 
-impl BoundedStorable for Order {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 impl Storable for Escrow {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match schema::open(bytes.as_ref()) {
+            (0, payload) => schema::decode_either::<EscrowV0, EscrowV0<String>, _>(payload),
+            (_, payload) => schema::decode(payload),
+        }
     }
-}
 
-impl BoundedStorable for Escrow {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-// Thread-local storage for Products, Users, Escrows, and Orders
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+impl Storable for ProductVariant {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
 
-    static PRODUCT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a product ID counter")
-    );
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
 
-    static PRODUCTS_STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+    const BOUND: Bound = Bound::Unbounded;
+}
 
-    static ORDER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))), 0)
-            .expect("Cannot create an order ID counter")
-    );
+impl Storable for MediaAsset {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
 
-    static ORDERS_STORAGE: RefCell<StableBTreeMap<u64, Order, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-    ));
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
 
-    static USER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), 0)
-            .expect("Cannot create a user ID counter")
-    );
+    const BOUND: Bound = Bound::Unbounded;
+}
 
-    static USERS_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-    ));
+// Raw bytes of one chunk of a media asset
+struct MediaChunk(Vec<u8>);
 
-    static ESCROW_ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), 0)
-            .expect("Cannot create an escrow ID counter")
-    );
+impl Storable for MediaChunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
 
-    static ESCROW_STORAGE: RefCell<StableBTreeMap<u64, Escrow, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
-    ));
-}
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        MediaChunk(bytes.into_owned())
+    }
 
-// Structs for payloads
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct ProductPayload {
-    name: String,
-    description: String,
-    price: u64,
-    stock_quantity: u32,
-    seller_id: u64,
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MEDIA_CHUNK_BYTES as u32,
+        is_fixed_size: false,
+    };
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct UserPayload {
-    name: String,
-    email: String,
-    role: String,
-}
+impl Storable for Category {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
 
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct OrderPayload {
-    user_id: u64,
-    product_id: u64,
-    quantity: u32,
-    total_price: u64,
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
 }
 
-// CRUD operations for Products
-#[ic_cdk::update]
-fn create_product(payload: ProductPayload) -> Result<Product, Error> {
-    // Validate inputs
-    validate_product_payload(&payload)?;
+impl Storable for Dispute {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
 
-    // Ensure the seller exists and is a seller
-    let seller = match _get_user(&payload.seller_id) {
-        Some(user) => {
-            if user.role == "seller" {
-                user
-            } else {
-                return Err(Error::Unauthorized {
-                    msg: format!("User with id={} is not authorized to add products", payload.seller_id),
-                });
-            }
-        }
-        None => {
-            return Err(Error::NotFound {
-                msg: format!("Seller with id={} not found", payload.seller_id),
-            });
-        }
-    };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
 
-    // Generate a new product ID using thread-local storage access
-    let id = PRODUCT_ID_COUNTER.with(|counter| {
-        generate_id(counter)
-    })?;
+    const BOUND: Bound = Bound::Unbounded;
+}
 
-    // Create the product
-    let product = Product {
-        id,
-        name: payload.name,
-        description: payload.description,
-        price: payload.price,
-        stock_quantity: payload.stock_quantity,
-        seller_id: seller.id,
-        created_at: time(),
-        updated_at: None,
-    };
-    do_insert_product(&product);
-    Ok(product)
+impl Storable for Review {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
 }
 
+impl Storable for ReputationCounters {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
 
-#[ic_cdk::update]
-fn update_product(id: u64, payload: ProductPayload) -> Result<Product, Error> {
-    // Validate inputs
-    validate_product_payload(&payload)?;
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
 
-    // Get the existing product
-    let mut product = match _get_product(&id) {
-        Some(prod) => prod,
-        None => return Err(Error::NotFound {
-            msg: format!("Product with id={} not found", id),
-        }),
-    };
+    const BOUND: Bound = Bound::Unbounded;
+}
 
-    // Ensure that only the seller who owns the product can modify the product data
-    if product.seller_id != payload.seller_id {
-        return Err(Error::Unauthorized {
-            msg: format!("User with id={} is not authorized to update this product", payload.seller_id),
-        });
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StockMovement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Canister-wide settings provided at init
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MarketplaceConfig {
+    ledger: Option<LedgerConfig>,
+    tax_rate_bps: u32, // Marketplace-wide tax in basis points of the discounted subtotal
+    reservation_window_secs: u64, // How long unpaid orders hold their stock
+    auto_release_window_secs: u64, // How long after delivery the escrow is released unless disputed
+}
+
+const DEFAULT_RESERVATION_WINDOW_SECS: u64 = 30 * 60;
+const DEFAULT_AUTO_RELEASE_WINDOW_SECS: u64 = 14 * 24 * 60 * 60;
+
+impl Default for MarketplaceConfig {
+    fn default() -> Self {
+        MarketplaceConfig {
+            ledger: None,
+            tax_rate_bps: 0,
+            reservation_window_secs: DEFAULT_RESERVATION_WINDOW_SECS,
+            auto_release_window_secs: DEFAULT_AUTO_RELEASE_WINDOW_SECS,
+        }
+    }
+}
+
+impl Storable for MarketplaceConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match schema::open(bytes.as_ref()) {
+            // Version 1 configs predate `auto_release_window_secs`
+            (0 | 1, payload) => schema::decode::<MarketplaceConfigV0>(payload).into(),
+            (_, payload) => schema::decode(payload),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Schema version of the stored records and progress of migrating them to the current one
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SchemaStatus {
+    schema_version: u8, // Version every stored record is known to be in
+    target_version: u8, // Version being migrated to; equals `schema_version` once done
+    stores: Vec<StoreMigration>,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+}
+
+// Progress of rewriting the records of one stable store
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoreMigration {
+    store: String,
+    migrated: u64,
+    total: u64, // Records in the store when the migration started
+    cursor: Option<(u64, u64)>, // Key of the last rewritten record
+    done: bool,
+}
+
+impl Storable for SchemaStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Progress of bringing what is derived from the stored records up to date after an upgrade
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct BackfillStatus {
+    jobs: Vec<Backfill>, // Empty until the first upgrade that keeps track of them
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+}
+
+// Progress of one of the `BACKFILLS`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Backfill {
+    job: String,
+    processed: u64,
+    cursor: Option<(u64, u64, u64)>, // Key of the last record processed
+    done: bool,
+}
+
+impl Storable for BackfillStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (_, payload) = schema::open(bytes.as_ref());
+        schema::decode(payload)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Layouts of records written before the versioned envelopes (schema version 0). Fields that
+// were added over time are optional here, so records of every earlier release still decode.
+#[derive(candid::CandidType, Deserialize)]
+struct ProductV0 {
+    id: u64,
+    name: String,
+    description: String,
+    price: u64,
+    shipping_fee: Option<u64>,
+    discount_bps: Option<u32>,
+    stock_quantity: u32,
+    reserved_quantity: Option<u32>,
+    seller_id: u64,
+    category_id: Option<u64>,
+    secondary_category_ids: Option<Vec<u64>>,
+    media_ids: Option<Vec<u64>>,
+    rating: Option<RatingSummary>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl From<ProductV0> for Product {
+    fn from(product: ProductV0) -> Self {
+        Product {
+            id: product.id,
+            name: product.name,
+            description: product.description,
+            price: product.price,
+            shipping_fee: product.shipping_fee.unwrap_or_default(),
+            discount_bps: product.discount_bps.unwrap_or_default(),
+            stock_quantity: product.stock_quantity,
+            reserved_quantity: product.reserved_quantity.unwrap_or_default(),
+            seller_id: product.seller_id,
+            category_id: product.category_id,
+            secondary_category_ids: product.secondary_category_ids.unwrap_or_default(),
+            media_ids: product.media_ids.unwrap_or_default(),
+            rating: product.rating.unwrap_or_default(),
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+        }
+    }
+}
+
+// Orders used to hold a single product; those are read back as an order with one item. The
+// first release had no `seller_id` either; it is taken from the product where that still exists.
+// Its statuses were text, read with `S = String`.
+#[derive(candid::CandidType, Deserialize)]
+struct OrderV0<S = OrderStatus> {
+    id: u64,
+    buyer_id: u64,
+    seller_id: Option<u64>,
+    items: Option<Vec<OrderItem>>,
+    product_id: Option<u64>,
+    quantity: Option<u32>,
+    price_breakdown: Option<PriceBreakdown>,
+    total_price: u64,
+    reserved_until: Option<u64>,
+    status: S,
+    status_history: Option<Vec<OrderStatusChange>>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl<S: LegacyLabel<OrderStatus>> From<OrderV0<S>> for Order {
+    fn from(order: OrderV0<S>) -> Self {
+        let items = match (order.items, order.product_id) {
+            (Some(items), _) => items,
+            (None, Some(product_id)) => vec![OrderItem {
+                product_id,
+                variant_id: None,
+                quantity: order.quantity.unwrap_or_default(),
+                price_breakdown: order.price_breakdown.unwrap_or_default(),
+            }],
+            (None, None) => Vec::new(),
+        };
+        let seller_id = order.seller_id.or_else(|| {
+            let product_id = items.first()?.product_id;
+            _get_product(&product_id).map(|product| product.seller_id)
+        });
+        Order {
+            id: order.id,
+            buyer_id: order.buyer_id,
+            seller_id: seller_id.unwrap_or_default(),
+            items,
+            total_price: order.total_price,
+            reserved_until: order.reserved_until,
+            auto_release_at: None,
+            status: order.status.resolve(),
+            status_history: order.status_history.unwrap_or_default(),
+            created_at: order.created_at,
+            updated_at: order.updated_at,
+            funding_transfer: None,
+        }
+    }
+}
+
+// Users of the first release had no principal and their role as text (`R = String`). They are
+// read back with the anonymous principal, which no caller can present, until an admin assigns
+// the owner's principal with `assign_user_principal`.
+#[derive(candid::CandidType, Deserialize)]
+struct UserV0<R = Role> {
+    id: u64,
+    principal: Option<Principal>,
+    name: String,
+    email: String,
+    role: R,
+    reputation: u8,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl<R: LegacyLabel<Role>> From<UserV0<R>> for User {
+    fn from(user: UserV0<R>) -> Self {
+        User {
+            id: user.id,
+            principal: user.principal.unwrap_or_else(Principal::anonymous),
+            name: user.name,
+            email: user.email,
+            role: user.role.resolve(),
+            reputation: user.reputation,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+// The first release stored escrow statuses as text (`S = String`)
+#[derive(candid::CandidType, Deserialize)]
+struct EscrowV0<S = EscrowStatus> {
+    id: u64,
+    order_id: u64,
+    amount: u64,
+    status: S,
+    funding_block: Option<u64>,
+    settlement_block: Option<u64>,
+    split: Option<EscrowSplit>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl<S: LegacyLabel<EscrowStatus>> From<EscrowV0<S>> for Escrow {
+    fn from(escrow: EscrowV0<S>) -> Self {
+        Escrow {
+            id: escrow.id,
+            order_id: escrow.order_id,
+            amount: escrow.amount,
+            status: escrow.status.resolve(),
+            funding_block: escrow.funding_block,
+            settlement_block: escrow.settlement_block,
+            split: escrow.split,
+            created_at: escrow.created_at,
+            updated_at: escrow.updated_at,
+        }
+    }
+}
+
+// A status or role as stored: one of the variants of `T`, or the free text the first release
+// wrote, such as "pending", "In Dispute" or "canceled"
+trait LegacyLabel<T> {
+    fn resolve(self) -> T;
+}
+
+impl<T: LegacyVariants> LegacyLabel<T> for T {
+    fn resolve(self) -> T {
+        self
+    }
+}
+
+// Text is matched against the variant names and aliases ignoring case, spaces and underscores.
+// Text that matches none of them reads as `T::fallback()`.
+impl<T: LegacyVariants> LegacyLabel<T> for String {
+    fn resolve(self) -> T {
+        let normalize = |name: &str| name.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase();
+        let text = normalize(&self);
+        T::VARIANTS
+            .iter()
+            .chain(T::ALIASES)
+            .find(|(name, _)| normalize(name) == text)
+            .map_or_else(T::fallback, |(_, value)| *value)
+    }
+}
+
+trait LegacyVariants: Copy + 'static {
+    const VARIANTS: &'static [(&'static str, Self)];
+    // Other text the first release wrote for a variant
+    const ALIASES: &'static [(&'static str, Self)] = &[];
+    fn fallback() -> Self;
+}
+
+impl LegacyVariants for OrderStatus {
+    const VARIANTS: &'static [(&'static str, Self)] = &[
+        ("Pending", OrderStatus::Pending),
+        ("Paid", OrderStatus::Paid),
+        ("Shipped", OrderStatus::Shipped),
+        ("Delivered", OrderStatus::Delivered),
+        ("Completed", OrderStatus::Completed),
+        ("Cancelled", OrderStatus::Cancelled),
+        ("InDispute", OrderStatus::InDispute),
+        ("Refunded", OrderStatus::Refunded),
+    ];
+    const ALIASES: &'static [(&'static str, Self)] = &[("complete", OrderStatus::Completed), ("canceled", OrderStatus::Cancelled)];
+
+    // An order in an unknown state cannot move any further
+    fn fallback() -> Self {
+        OrderStatus::Cancelled
+    }
+}
+
+impl LegacyVariants for EscrowStatus {
+    const VARIANTS: &'static [(&'static str, Self)] = &[
+        ("Held", EscrowStatus::Held),
+        ("Released", EscrowStatus::Released),
+        ("Refunded", EscrowStatus::Refunded),
+        ("Split", EscrowStatus::Split),
+    ];
+
+    // Keeps the funds where they are until someone settles them
+    fn fallback() -> Self {
+        EscrowStatus::Held
+    }
+}
+
+impl LegacyVariants for Role {
+    const VARIANTS: &'static [(&'static str, Self)] = &[
+        ("Buyer", Role::Buyer),
+        ("Seller", Role::Seller),
+        ("Admin", Role::Admin),
+        ("Arbiter", Role::Arbiter),
+    ];
+
+    fn fallback() -> Self {
+        Role::Buyer
+    }
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct MarketplaceConfigV0 {
+    ledger: Option<LedgerConfig>,
+    tax_rate_bps: Option<u32>,
+    reservation_window_secs: Option<u64>,
+    auto_release_window_secs: Option<u64>,
+}
+
+impl From<MarketplaceConfigV0> for MarketplaceConfig {
+    fn from(config: MarketplaceConfigV0) -> Self {
+        MarketplaceConfig {
+            ledger: config.ledger,
+            tax_rate_bps: config.tax_rate_bps.unwrap_or_default(),
+            reservation_window_secs: config.reservation_window_secs.unwrap_or(DEFAULT_RESERVATION_WINDOW_SECS),
+            auto_release_window_secs: config.auto_release_window_secs.unwrap_or(DEFAULT_AUTO_RELEASE_WINDOW_SECS),
+        }
+    }
+}
+
+// Wrapper that lets a Principal be used as a stable map key
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct StorablePrincipal(Principal);
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
+}
+
+// A case-folded token of the full-text search index
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct SearchTerm(String);
+
+impl Storable for SearchTerm {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        SearchTerm(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_SEARCH_TERM_BYTES as u32,
+        is_fixed_size: false,
+    };
+}
+
+// Thread-local storage for Products, Users, Escrows, and Orders
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static PRODUCT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a product ID counter")
+    );
+
+    static PRODUCTS_STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static ORDER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))), 0)
+            .expect("Cannot create an order ID counter")
+    );
+
+    static ORDERS_STORAGE: RefCell<StableBTreeMap<u64, Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static USER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), 0)
+            .expect("Cannot create a user ID counter")
+    );
+
+    static USERS_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static ESCROW_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), 0)
+            .expect("Cannot create an escrow ID counter")
+    );
+
+    static ESCROW_STORAGE: RefCell<StableBTreeMap<u64, Escrow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static USER_PRINCIPALS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // Order id -> escrow id, enforcing a single escrow per order
+    static ORDER_ESCROWS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    // Quantity of each product (variant) in each buyer's cart
+    static CART_ITEMS: RefCell<StableBTreeMap<CartKey, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    // (product id, buyer id, variant id or NO_VARIANT) for every cart line, kept in sync by
+    // `do_insert_cart_item` and `do_remove_cart_item`, so a product's lines can be found
+    static CART_ITEMS_BY_PRODUCT: RefCell<StableBTreeMap<(u64, u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    // Order id -> reservation expiry, for every unpaid order holding stock
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // Order id -> when its escrow is released, for every delivered order awaiting confirmation
+    static AUTO_RELEASES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static STOCK_MOVEMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))), 0)
+            .expect("Cannot create a stock movement ID counter")
+    );
+
+    // (product id, movement id) -> movement, so each product's log is a contiguous range
+    static STOCK_MOVEMENTS: RefCell<StableBTreeMap<(u64, u64), StockMovement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // (term, product id) -> relevance weight of the term in that product
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(SearchTerm, u64), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Secondary indexes over ORDERS_STORAGE, kept in sync by `do_insert_order`
+    static ORDERS_BY_BUYER: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static ORDERS_BY_SELLER: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static ORDERS_BY_STATUS: RefCell<StableBTreeMap<(u8, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // (buyer id, status, order id) and (seller id, status, order id), so that a party's orders
+    // in given statuses are paged without looking at the others
+    static ORDERS_BY_BUYER_STATUS: RefCell<StableBTreeMap<(u64, u8, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    static ORDERS_BY_SELLER_STATUS: RefCell<StableBTreeMap<(u64, u8, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    // (product id, order id) for every open order with an item of the product
+    static OPEN_ORDERS_BY_PRODUCT: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    // (`OrderScope` key, status) -> number of orders, counted as entries enter and leave the
+    // status indexes above and `ORDERS_BY_STATUS`
+    static ORDER_COUNTS: RefCell<StableBTreeMap<(u8, u64, u8), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    static CATEGORY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))), 0)
+            .expect("Cannot create a category ID counter")
+    );
+
+    static CATEGORIES: RefCell<StableBTreeMap<u64, Category, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // (category id, product id) for every primary and secondary assignment
+    static PRODUCTS_BY_CATEGORY: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static VARIANT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), 0)
+            .expect("Cannot create a variant ID counter")
+    );
+
+    // (product id, variant id) -> variant
+    static PRODUCT_VARIANTS: RefCell<StableBTreeMap<(u64, u64), ProductVariant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static MEDIA_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), 0)
+            .expect("Cannot create a media ID counter")
+    );
+
+    static MEDIA_ASSETS: RefCell<StableBTreeMap<u64, MediaAsset, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // (asset id, chunk index) -> chunk bytes
+    static MEDIA_CHUNKS: RefCell<StableBTreeMap<(u64, u32), MediaChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // (product id, asset id) for every media asset of a product
+    static PRODUCT_MEDIA: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    static DISPUTE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))), 0)
+            .expect("Cannot create a dispute ID counter")
+    );
+
+    static DISPUTES: RefCell<StableBTreeMap<u64, Dispute, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Order id -> its dispute. An order is disputed at most once.
+    static ORDER_DISPUTES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    static TRANSACTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))), 0)
+            .expect("Cannot create a transaction ID counter")
+    );
+
+    // (user id, transaction id) -> transaction, so each user's history is one range
+    static TRANSACTIONS: RefCell<StableBTreeMap<(u64, u64), Transaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    static REVIEW_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), 0)
+            .expect("Cannot create a review ID counter")
+    );
+
+    static REVIEWS: RefCell<StableBTreeMap<u64, Review, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    // (product id, review id) set, so each product's reviews are one range
+    static PRODUCT_REVIEWS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    // (order id, position of the item in the order) -> its review. Each item is reviewed once.
+    static ORDER_ITEM_REVIEWS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    // (sort field, sort key, product id) for every product and `ProductSortField`, kept in
+    // sync by `do_insert_product`, so the catalog can be paged in any order without sorting it
+    static PRODUCTS_BY_SORT_KEY: RefCell<StableBTreeMap<(u8, u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    // (seller id, product id) for every product, kept in sync by `do_insert_product`
+    static PRODUCTS_BY_SELLER: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // User id -> the counts their reputation is computed from, kept up to date as their orders,
+    // disputes and reviews change
+    static REPUTATION_COUNTERS: RefCell<StableBTreeMap<u64, ReputationCounters, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    static CONFIG: RefCell<Cell<MarketplaceConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), MarketplaceConfig::default())
+            .expect("Cannot create the marketplace config")
+    );
+
+    // Starts out at version 0 on canisters installed before records were versioned
+    static SCHEMA_STATUS: RefCell<Cell<SchemaStatus, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))), SchemaStatus::default())
+            .expect("Cannot create the schema status")
+    );
+
+    static BACKFILL_STATUS: RefCell<Cell<BackfillStatus, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))), BackfillStatus::default())
+            .expect("Cannot create the backfill status")
+    );
+}
+
+// Orders with a ledger transfer in flight. This is heap state on purpose: a lock must never
+// outlive the call that took it, and an upgrade can only happen once no calls are running.
+thread_local! {
+    static LOCKED_ORDERS: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Times of each buyer's recent attempts to place orders, for rate limiting. Losing them on an
+// upgrade merely restarts the window.
+thread_local! {
+    static RECENT_ORDER_ATTEMPTS: RefCell<BTreeMap<u64, Vec<u64>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Init arguments for the canister; upgrades accept them too
+#[derive(candid::CandidType, Deserialize)]
+struct InitArgs {
+    ledger: LedgerConfig,
+}
+
+#[ic_cdk::init]
+fn init(args: InitArgs) {
+    CONFIG.with(|config| {
+        config
+            .borrow_mut()
+            .set(MarketplaceConfig {
+                ledger: Some(args.ledger),
+                ..MarketplaceConfig::default()
+            })
+            .expect("Cannot store the marketplace config")
+    });
+    set_schema_status(SchemaStatus {
+        schema_version: schema::SCHEMA_VERSION,
+        target_version: schema::SCHEMA_VERSION,
+        ..SchemaStatus::default()
+    });
+    // There is nothing to index yet
+    set_backfill_status(BackfillStatus {
+        jobs: BACKFILLS
+            .iter()
+            .map(|job| Backfill {
+                job: job.to_string(),
+                processed: 0,
+                cursor: None,
+                done: true,
+            })
+            .collect(),
+        started_at: Some(time()),
+        finished_at: Some(time()),
+    });
+    certified::publish();
+}
+
+// Timers do not survive upgrades, so the reservation expiries and escrow auto-releases are
+// re-armed from stable memory
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    // Canisters installed before payments existed have no ledger until an upgrade names one.
+    // Once set, the ledger cannot change: escrows held on it would be stranded.
+    if let Some(args) = args {
+        let current = CONFIG.with(|config| config.borrow().get().ledger);
+        match current {
+            None => CONFIG.with(|config| {
+                let mut updated = config.borrow().get().clone();
+                updated.ledger = Some(args.ledger);
+                config.borrow_mut().set(updated).expect("Cannot store the marketplace config");
+            }),
+            Some(ledger) if ledger == args.ledger => {}
+            Some(ledger) => panic!("The canister already uses the ledger {:?}; it cannot be changed to {:?}", ledger, args.ledger),
+        }
+    }
+
+    // Decides which indexes need building before the migration changes any records, so that
+    // the reputation of orders it changes is left for the backfill to count
+    start_backfills();
+
+    // Records written by older releases are rewritten in the current layout, starting right
+    // away and continuing in timer calls if there are too many for this one
+    if start_migration() {
+        run_migration_batch();
+    }
+
+    let reservations: Vec<(u64, u64)> = RESERVATIONS.with(|reservations| reservations.borrow().iter().collect());
+    for (order_id, expires_at) in reservations {
+        schedule_reservation_expiry(order_id, expires_at);
+    }
+    let auto_releases: Vec<(u64, u64)> = AUTO_RELEASES.with(|releases| releases.borrow().iter().collect());
+    for (order_id, release_at) in auto_releases {
+        schedule_auto_release(order_id, release_at);
+    }
+
+    // The certification tree and any missing indexes are built the same way as the migration
+    run_backfill_batch();
+}
+
+// Structs for payloads
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductPayload {
+    name: String,
+    description: String,
+    price: u64,
+    shipping_fee: u64,
+    discount_bps: u32,
+    category_id: Option<u64>,
+    secondary_category_ids: Vec<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct VariantPayload {
+    sku: String,
+    attributes: Vec<VariantAttribute>,
+    price: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct MediaUploadPayload {
+    content_type: String, // One of ALLOWED_MEDIA_TYPES
+    size: u64,            // Total bytes that will be uploaded
+    width: u32,
+    height: u32,
+    alt_text: String,
+    thumbnail_of: Option<u64>, // Asset of the same product this is a thumbnail of
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct UserPayload {
+    name: String,
+    email: String,
+    role: Role,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ReviewPayload {
+    order_id: u64,
+    product_id: u64,
+    variant_id: Option<u64>, // The variant ordered, for products sold in variants
+    rating: u8,
+    text: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DisputePayload {
+    order_id: u64,
+    reason: DisputeReason,
+    description: String,
+    evidence: Vec<String>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct DisputeMessagePayload {
+    body: String,
+    evidence: Vec<String>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct OrderPayload {
+    product_id: u64,
+    variant_id: Option<u64>, // Required for products sold in variants
+    quantity: u32,
+    expected_total: Option<u64>, // Rejects the order if the computed total is higher
+}
+
+// CRUD operations for Products
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn create_product(payload: ProductPayload, stock_quantity: u32) -> Result<Product, Error> {
+    // Validate inputs
+    validate_product_payload(&payload, &BTreeSet::new())?;
+
+    // The seller is always the registered user behind the caller
+    let seller = authorize(&[Role::Seller])?;
+
+    // Generate a new product ID using thread-local storage access
+    let id = PRODUCT_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+
+    // Create the product
+    let mut product = Product {
+        id,
+        name: payload.name,
+        description: payload.description,
+        price: payload.price,
+        shipping_fee: payload.shipping_fee,
+        discount_bps: payload.discount_bps,
+        stock_quantity: 0,
+        reserved_quantity: 0,
+        seller_id: seller.id,
+        category_id: payload.category_id,
+        secondary_category_ids: payload.secondary_category_ids,
+        media_ids: Vec::new(),
+        rating: RatingSummary::default(),
+        created_at: time(),
+        updated_at: None,
+    };
+    // Stock may be zero: the product is out of stock or will be stocked through its variants
+    record_stock_movement(&mut product, None, stock_quantity as i64, StockMovementReason::Listed, Some(seller.id), None)?;
+    do_insert_product(&product);
+    update_search_index(product.id, &BTreeMap::new(), &search_terms(&product));
+    update_category_index(product.id, &BTreeSet::new(), &product_categories(&product));
+    Ok(product)
+}
+
+// Updates a product's listing. Its stock only changes through orders and `manage_inventory`.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn update_product(id: u64, payload: ProductPayload) -> Result<Product, Error> {
+    // Get the existing product
+    let mut product = match _get_product(&id) {
+        Some(prod) => prod,
+        None => return Err(Error::not_found(EntityKind::Product, id)),
+    };
+
+    // Validate inputs; the product may keep categories that were archived since
+    validate_product_payload(&payload, &product_categories(&product))?;
+
+    // Ensure that only the seller who owns the product can modify the product data
+    let seller = authorize(&[Role::Seller])?;
+    if product.seller_id != seller.id {
+        return Err(Error::Unauthorized {
+            msg: format!("User with id={} is not authorized to update this product", seller.id),
+        });
     }
 
     // Update the product
+    let previous_terms = search_terms(&product);
+    let previous_categories = product_categories(&product);
     product.name = payload.name;
     product.description = payload.description;
     product.price = payload.price;
-    product.stock_quantity = payload.stock_quantity;
+    product.shipping_fee = payload.shipping_fee;
+    product.discount_bps = payload.discount_bps;
+    product.category_id = payload.category_id;
+    product.secondary_category_ids = payload.secondary_category_ids;
+    product.updated_at = Some(time());
+    do_insert_product(&product);
+    update_search_index(product.id, &previous_terms, &search_terms(&product));
+    update_category_index(product.id, &previous_categories, &product_categories(&product));
+    Ok(product)
+}
+
+#[ic_cdk::query]
+fn view_product(product_id: u64) -> Result<Product, Error> {
+    match _get_product(&product_id) {
+        Some(product) => Ok(product),
+        None => Err(Error::not_found(EntityKind::Product, product_id)),
+    }
+}
+
+// A record together with the proof that the subnet certified it. Clients check `certificate`
+// as described in the IC interface specification, check that `witness` reconstructs the
+// certified data, and that its leaf at `/<products|orders>/<id as 8 big-endian bytes>` is the
+// SHA-256 of the Candid encoding of `data`.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Certified<T> {
+    data: T,
+    certificate: Vec<u8>,
+    witness: Vec<u8>, // CBOR-encoded hash tree
+}
+
+#[ic_cdk::query]
+fn view_product_certified(product_id: u64) -> Result<Certified<Product>, Error> {
+    let product = view_product(product_id)?;
+    certify_response(certified::PRODUCTS, product_id, product)
+}
+
+#[ic_cdk::query]
+fn view_order_certified(order_id: u64) -> Result<Certified<Order>, Error> {
+    let order = view_order(order_id)?;
+    certify_response(certified::ORDERS, order_id, order)
+}
+
+fn certify_response<T>(label: &'static str, id: u64, data: T) -> Result<Certified<T>, Error> {
+    // Only non-replicated queries receive a certificate
+    let certificate = ic_cdk::api::data_certificate().ok_or_else(|| Error::invalid_input("Certified reads must be made as query calls."))?;
+    let witness = certified::witness(label, id).map_err(|msg| Error::Internal {
+        msg: format!("Cannot build the witness: {}", msg),
+    })?;
+    Ok(Certified {
+        data,
+        certificate,
+        witness,
+    })
+}
+
+// Catalog browsing
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductFilter {
+    seller_id: Option<u64>,
+    min_price: Option<u64>, // Inclusive bounds on the unit price
+    max_price: Option<u64>,
+    in_stock: Option<bool>, // Some(true) keeps only products with available stock
+    category_id: Option<u64>, // Products in the category or any of its descendants
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+enum ProductSortField {
+    Price,
+    #[default]
+    CreatedAt,
+    Rating, // The product's average review rating
+}
+
+impl ProductSortField {
+    const ALL: [ProductSortField; 3] = [ProductSortField::Price, ProductSortField::CreatedAt, ProductSortField::Rating];
+
+    // Key of the field in `PRODUCTS_BY_SORT_KEY`
+    fn index_key(&self) -> u8 {
+        *self as u8
+    }
+
+    fn sort_key(&self, product: &Product) -> u64 {
+        match self {
+            ProductSortField::Price => product.price,
+            ProductSortField::CreatedAt => product.created_at,
+            ProductSortField::Rating => product.rating.sort_key(),
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+enum SortDirection {
+    Ascending,
+    #[default]
+    Descending,
+}
+
+// Position after the last product of a page. Holding the sort key rather than an offset keeps
+// paging stable while products are added or removed between calls.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct ProductCursor {
+    sort_key: u64,
+    id: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductQuery {
+    filter: ProductFilter,
+    sort_by: ProductSortField,
+    direction: SortDirection,
+    cursor: Option<ProductCursor>, // `next_cursor` of the previous page
+    limit: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ProductPage {
+    products: Vec<Product>,
+    total: Option<u64>, // Matches across all pages; None where `count_products` cannot count them from an index
+    next_cursor: Option<ProductCursor>, // None on the last page
+}
+
+#[ic_cdk::query]
+fn list_products(query: ProductQuery) -> Result<ProductPage, Error> {
+    let filter = &query.filter;
+    if let (Some(min), Some(max)) = (filter.min_price, filter.max_price) {
+        if min > max {
+            return Err(Error::invalid_input(format!("min_price {} is above max_price {}", min, max)));
+        }
+    }
+    let in_category: Option<BTreeSet<u64>> = match filter.category_id {
+        Some(category_id) => {
+            if _get_category(&category_id).is_none() {
+                return Err(Error::not_found(EntityKind::Category, category_id));
+            }
+            Some(products_in_categories(&category_subtree(category_id)))
+        }
+        None => None,
+    };
+
+    let total = count_products(filter, in_category.as_ref());
+    let matches = |product: &Product| {
+        filter.seller_id.is_none_or(|seller_id| product.seller_id == seller_id)
+            && filter.min_price.is_none_or(|min| product.price >= min)
+            && filter.max_price.is_none_or(|max| product.price <= max)
+            && filter.in_stock.is_none_or(|in_stock| (product.stock_quantity > 0) == in_stock)
+    };
+
+    // Walks the index from the cursor in the requested order. Ties on the sort key are broken
+    // by product id so every product has a unique position. A page ends once it is full or
+    // MAX_PRODUCT_SCAN entries were looked at, so a selective filter can return a short, even
+    // empty, page that still has a `next_cursor` to carry on from.
+    let field = query.sort_by.index_key();
+    let first = (field, 0, 0);
+    let last = (field, u64::MAX, u64::MAX);
+    let limit = query.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let (page, next_cursor) = PRODUCTS_BY_SORT_KEY.with(|index| {
+        let index = index.borrow();
+        let positions: Box<dyn Iterator<Item = ProductCursor>> = match (query.direction, query.cursor) {
+            (SortDirection::Ascending, cursor) => {
+                let start = cursor.map_or(ops::Bound::Included(first), |cursor| ops::Bound::Excluded((field, cursor.sort_key, cursor.id)));
+                Box::new(index.range((start, ops::Bound::Included(last))).map(|((_, sort_key, id), _)| ProductCursor { sort_key, id }))
+            }
+            (SortDirection::Descending, cursor) => {
+                let end = cursor.map_or(ops::Bound::Included(last), |cursor| ops::Bound::Excluded((field, cursor.sort_key, cursor.id)));
+                Box::new(index.range((ops::Bound::Included(first), end)).rev().map(|((_, sort_key, id), _)| ProductCursor { sort_key, id }))
+            }
+        };
+
+        let mut page = Vec::new();
+        let (mut scanned, mut last_position, mut exhausted) = (0, None, true);
+        for position in positions {
+            if page.len() == limit || scanned == MAX_PRODUCT_SCAN {
+                exhausted = false;
+                break;
+            }
+            scanned += 1;
+            last_position = Some(position);
+            if in_category.as_ref().is_some_and(|product_ids| !product_ids.contains(&position.id)) {
+                continue;
+            }
+            if let Some(product) = _get_product(&position.id).filter(|product| matches(product)) {
+                page.push(product);
+            }
+        }
+        (page, if exhausted { None } else { last_position })
+    });
+
+    Ok(ProductPage {
+        products: page,
+        total,
+        next_cursor,
+    })
+}
+
+// Counts the products matching `filter` from the seller or price index alone, narrowed to
+// `in_category` if given. Stock changes with every order and has no index, so lists filtered
+// by stock are not counted; neither are those filtered by both seller and price, nor those
+// that would need more than MAX_PRODUCT_COUNT_SCAN index entries counted.
+fn count_products(filter: &ProductFilter, in_category: Option<&BTreeSet<u64>>) -> Option<u64> {
+    let count = |product_ids: &mut dyn Iterator<Item = u64>| {
+        let mut total = 0;
+        for (scanned, product_id) in product_ids.enumerate() {
+            if scanned == MAX_PRODUCT_COUNT_SCAN {
+                return None;
+            }
+            if in_category.is_none_or(|product_ids| product_ids.contains(&product_id)) {
+                total += 1;
+            }
+        }
+        Some(total)
+    };
+
+    let priced = filter.min_price.is_some() || filter.max_price.is_some();
+    match (filter.seller_id, priced, filter.in_stock) {
+        (_, _, Some(_)) | (Some(_), true, _) => None,
+        (Some(seller_id), false, None) => PRODUCTS_BY_SELLER.with(|index| {
+            count(&mut index.borrow().range((seller_id, 0)..=(seller_id, u64::MAX)).map(|((_, product_id), _)| product_id))
+        }),
+        (None, true, None) => {
+            let field = ProductSortField::Price.index_key();
+            let (min, max) = (filter.min_price.unwrap_or(0), filter.max_price.unwrap_or(u64::MAX));
+            PRODUCTS_BY_SORT_KEY.with(|index| {
+                count(&mut index.borrow().range((field, min, 0)..=(field, max, u64::MAX)).map(|((_, _, product_id), _)| product_id))
+            })
+        }
+        (None, false, None) => match in_category {
+            Some(product_ids) => Some(product_ids.len() as u64),
+            None => Some(PRODUCTS_STORAGE.with(|products| products.borrow().len())),
+        },
+    }
+}
+
+// Full-text search over product names and descriptions. Every query term must match
+// (AND); a term of at least MIN_SEARCH_PREFIX_CHARS matches any indexed word it is a prefix
+// of, and whole-word matches rank higher. Results are ordered by relevance, best first, and
+// paged like `list_products`. A term matching more than MAX_SEARCH_TERM_SCAN index entries
+// is refused rather than scanned.
+#[ic_cdk::query]
+fn search_products(query: String, cursor: Option<ProductCursor>, limit: u32) -> Result<ProductPage, Error> {
+    let terms: BTreeSet<String> = tokenize(&query).into_iter().collect();
+    if terms.is_empty() || terms.len() > MAX_SEARCH_QUERY_TERMS {
+        return Err(Error::invalid_input(format!("Search query must contain between 1 and {} words", MAX_SEARCH_QUERY_TERMS)));
+    }
+
+    // Relevance of every product matching all terms so far
+    let mut scores: Option<BTreeMap<u64, u64>> = None;
+    for term in &terms {
+        let prefix = term.chars().count() >= MIN_SEARCH_PREFIX_CHARS;
+        let term_scores = SEARCH_INDEX.with(|index| {
+            let mut term_scores: BTreeMap<u64, u64> = BTreeMap::new();
+            for (scanned, ((indexed, product_id), weight)) in index.borrow().range((SearchTerm(term.clone()), 0)..).enumerate() {
+                let exact = indexed.0 == *term;
+                let matched = exact || (prefix && indexed.0.starts_with(term.as_str()));
+                if !matched {
+                    break;
+                }
+                if scanned == MAX_SEARCH_TERM_SCAN {
+                    return Err(Error::invalid_field(
+                        "query",
+                        format!("\"{}\" matches too many words; make it longer or more specific", term),
+                    ));
+                }
+                let exact_bonus = if exact { 2 } else { 1 };
+                *term_scores.entry(product_id).or_insert(0) += weight as u64 * exact_bonus;
+            }
+            Ok(term_scores)
+        })?;
+        scores = Some(match scores {
+            None => term_scores,
+            Some(scores) => scores
+                .into_iter()
+                .filter_map(|(product_id, score)| term_scores.get(&product_id).map(|term_score| (product_id, score + term_score)))
+                .collect(),
+        });
+    }
+
+    let mut matches: Vec<ProductCursor> = scores
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(id, sort_key)| ProductCursor { sort_key, id })
+        .collect();
+    matches.sort_by(|a, b| b.cmp(a));
+
+    let total = matches.len() as u64;
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut remaining = matches.into_iter().filter(|position| cursor.is_none_or(|cursor| *position < cursor));
+    let page: Vec<ProductCursor> = remaining.by_ref().take(limit).collect();
+    let next_cursor = match remaining.next() {
+        Some(_) => page.last().copied(),
+        None => None,
+    };
+
+    Ok(ProductPage {
+        products: page.iter().filter_map(|position| _get_product(&position.id)).collect(),
+        total: Some(total),
+        next_cursor,
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn delete_product(product_id: u64) -> Result<Product, Error> {
+    // Only the owning seller or an admin can delist a product
+    if let Some(product) = _get_product(&product_id) {
+        authorize_owner_or(product.seller_id, &[Role::Admin])?;
+
+        // The indexes below are complete only once an upgrade has finished building them
+        if backfill_pending("order_indexes") || backfill_pending("cart_index") {
+            return Err(Error::Conflict {
+                msg: "Orders and carts are still being indexed after an upgrade; try again later".to_string(),
+            });
+        }
+
+        // Open orders still need the product to be restocked, reviewed or shown
+        if product.reserved_quantity > 0 {
+            return Err(Error::Conflict {
+                msg: format!("Product with id={} has {} units reserved by unpaid orders", product_id, product.reserved_quantity),
+            });
+        }
+        let open_order = OPEN_ORDERS_BY_PRODUCT.with(|index| {
+            index.borrow().range((product_id, 0)..=(product_id, u64::MAX)).next().map(|((_, order_id), _)| order_id)
+        });
+        if let Some(order_id) = open_order {
+            return Err(Error::Conflict {
+                msg: format!("Product with id={} is part of open order with id={}", product_id, order_id),
+            });
+        }
+    }
+
+    match PRODUCTS_STORAGE.with(|products| products.borrow_mut().remove(&product_id)) {
+        Some(product) => {
+            for variant in _get_variants(product_id) {
+                PRODUCT_VARIANTS.with(|variants| variants.borrow_mut().remove(&(product_id, variant.id)));
+            }
+            for asset in _get_product_media(product_id) {
+                remove_media_asset(&asset);
+            }
+            let cart_lines: Vec<CartKey> = CART_ITEMS_BY_PRODUCT.with(|index| {
+                index
+                    .borrow()
+                    .range((product_id, 0, 0)..=(product_id, u64::MAX, u64::MAX))
+                    .map(|((_, buyer_id, variant_id), _)| ((buyer_id, product_id), variant_id))
+                    .collect()
+            });
+            for key in &cart_lines {
+                do_remove_cart_item(key);
+            }
+            update_search_index(product_id, &search_terms(&product), &BTreeMap::new());
+            update_category_index(product_id, &product_categories(&product), &BTreeSet::new());
+            unindex_product(&product);
+            certified::uncertify(certified::PRODUCTS, product_id);
+            if product.rating.count > 0 && reputation_counts("rating_reputation", product_id) {
+                update_reputation(product.seller_id, |counters| {
+                    counters.review_count = counters.review_count.saturating_sub(product.rating.count);
+                    counters.rating_total = counters.rating_total.saturating_sub(product.rating.total);
+                });
+            }
+            Ok(product)
+        }
+        None => Err(Error::not_found(EntityKind::Product, product_id)),
+    }
+}
+
+// Product variants
+//
+// A product without variants is bought as is. Once a seller adds variants, every order and
+// cart line must name one of them, and stock is kept per variant.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn add_variant(product_id: u64, payload: VariantPayload, stock_quantity: u32) -> Result<ProductVariant, Error> {
+    let mut product = match _get_product(&product_id) {
+        Some(product) => product,
+        None => return Err(Error::not_found(EntityKind::Product, product_id)),
+    };
+    let seller = authorize_owner_or(product.seller_id, &[])?;
+    validate_variant_payload(&payload, product_id, None)?;
+
+    let variants = _get_variants(product_id);
+    if variants.is_empty() && (product.stock_quantity > 0 || product.reserved_quantity > 0) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Product with id={} still holds stock of its own; bring it to zero before adding variants",
+                product_id
+            ),
+        });
+    }
+    if variants.len() >= MAX_VARIANTS_PER_PRODUCT {
+        return Err(Error::invalid_input(format!("A product can have at most {} variants.", MAX_VARIANTS_PER_PRODUCT)));
+    }
+
+    let id = VARIANT_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let mut variant = ProductVariant {
+        id,
+        product_id,
+        sku: payload.sku,
+        attributes: payload.attributes,
+        price: payload.price,
+        stock_quantity: 0,
+        reserved_quantity: 0,
+        created_at: time(),
+        updated_at: None,
+    };
+    record_stock_movement(&mut product, Some(&mut variant), stock_quantity as i64, StockMovementReason::Listed, Some(seller.id), None)?;
+    product.updated_at = Some(time());
+    do_insert_product(&product);
+    do_insert_variant(&variant);
+    Ok(variant)
+}
+
+// Changes a variant's SKU, attributes and price; its stock goes through `manage_inventory`
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn update_variant(product_id: u64, variant_id: u64, payload: VariantPayload) -> Result<ProductVariant, Error> {
+    let (product, variant) = resolve_listing(product_id, Some(variant_id))?;
+    authorize_owner_or(product.seller_id, &[])?;
+    validate_variant_payload(&payload, product_id, Some(variant_id))?;
+
+    let mut variant = match variant {
+        Some(variant) => variant,
+        None => return Err(Error::NotFound {
+            entity: EntityKind::Variant,
+            id: variant_id,
+            msg: format!("Variant with id={} of product with id={} not found", variant_id, product_id),
+        }),
+    };
+    variant.sku = payload.sku;
+    variant.attributes = payload.attributes;
+    variant.price = payload.price;
+    variant.updated_at = Some(time());
+    do_insert_variant(&variant);
+    Ok(variant)
+}
+
+// Takes a variant off sale together with its remaining stock. Variants held by unpaid orders
+// cannot be removed.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn remove_variant(product_id: u64, variant_id: u64) -> Result<ProductVariant, Error> {
+    let (mut product, variant) = resolve_listing(product_id, Some(variant_id))?;
+    let seller = authorize_owner_or(product.seller_id, &[])?;
+
+    let mut variant = match variant {
+        Some(variant) => variant,
+        None => return Err(Error::NotFound {
+            entity: EntityKind::Variant,
+            id: variant_id,
+            msg: format!("Variant with id={} of product with id={} not found", variant_id, product_id),
+        }),
+    };
+    if variant.reserved_quantity > 0 {
+        return Err(Error::Conflict {
+            msg: format!("Variant with id={} is reserved by unpaid orders", variant_id),
+        });
+    }
+
+    if variant.stock_quantity > 0 {
+        let delta = -(variant.stock_quantity as i64);
+        record_stock_movement(&mut product, Some(&mut variant), delta, StockMovementReason::Correction, Some(seller.id), None)?;
+    }
+    product.updated_at = Some(time());
+    do_insert_product(&product);
+    PRODUCT_VARIANTS.with(|variants| variants.borrow_mut().remove(&(product_id, variant_id)));
+    Ok(variant)
+}
+
+#[ic_cdk::query]
+fn list_variants(product_id: u64) -> Result<Vec<ProductVariant>, Error> {
+    match _get_product(&product_id) {
+        Some(_) => Ok(_get_variants(product_id)),
+        None => Err(Error::not_found(EntityKind::Product, product_id)),
+    }
+}
+
+// Product media
+//
+// Images are uploaded in three steps so that they can exceed the ingress message limit:
+// `begin_media_upload` declares the image, `upload_media_chunk` sends each chunk of
+// MEDIA_CHUNK_BYTES (the last one may be shorter), and `finish_media_upload` checks that the
+// bytes are complete and match the declared content type before the image is served.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn begin_media_upload(product_id: u64, payload: MediaUploadPayload) -> Result<MediaAsset, Error> {
+    let product = match _get_product(&product_id) {
+        Some(product) => product,
+        None => return Err(Error::not_found(EntityKind::Product, product_id)),
+    };
+    authorize_owner_or(product.seller_id, &[])?;
+    validate_media_payload(&payload)?;
+
+    if let Some(original_id) = payload.thumbnail_of {
+        match _get_media(&original_id) {
+            Some(original) if original.product_id == product_id && original.thumbnail_of.is_none() => {}
+            Some(_) => return Err(Error::invalid_input(format!("Media with id={} cannot have thumbnails for product with id={}", original_id, product_id))),
+            None => return Err(Error::not_found(EntityKind::Media, original_id)),
+        }
+    }
+    if _get_product_media(product_id).len() >= MAX_MEDIA_PER_PRODUCT {
+        return Err(Error::invalid_input(format!("A product can have at most {} images, thumbnails included.", MAX_MEDIA_PER_PRODUCT)));
+    }
+
+    let id = MEDIA_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let asset = MediaAsset {
+        id,
+        product_id,
+        content_type: payload.content_type,
+        size: payload.size,
+        width: payload.width,
+        height: payload.height,
+        alt_text: payload.alt_text,
+        thumbnail_of: payload.thumbnail_of,
+        status: MediaStatus::Uploading,
+        created_at: time(),
+        updated_at: None,
+    };
+    do_insert_media(&asset);
+    Ok(asset)
+}
+
+// Stores chunk `chunk_index` of an upload; sending a chunk again replaces it
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn upload_media_chunk(media_id: u64, chunk_index: u32, data: Vec<u8>) -> Result<MediaAsset, Error> {
+    let asset = match _get_media(&media_id) {
+        Some(asset) => asset,
+        None => return Err(Error::not_found(EntityKind::Media, media_id)),
+    };
+    authorize_media_owner(&asset)?;
+    if asset.status != MediaStatus::Uploading {
+        return Err(Error::Conflict {
+            msg: format!("Media with id={} is already uploaded", media_id),
+        });
+    }
+
+    let chunk_count = media_chunk_count(asset.size);
+    if chunk_index >= chunk_count {
+        return Err(Error::invalid_input(format!("Media with id={} has {} chunks; index {} is out of range", media_id, chunk_count, chunk_index)));
+    }
+    let expected_len = if chunk_index + 1 < chunk_count {
+        MEDIA_CHUNK_BYTES as u64
+    } else {
+        asset.size - (chunk_count as u64 - 1) * MEDIA_CHUNK_BYTES as u64
+    };
+    if data.len() as u64 != expected_len {
+        return Err(Error::invalid_input(format!("Chunk {} of media with id={} must be {} bytes, got {}", chunk_index, media_id, expected_len, data.len())));
+    }
+
+    MEDIA_CHUNKS.with(|chunks| chunks.borrow_mut().insert((media_id, chunk_index), MediaChunk(data)));
+    Ok(asset)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn finish_media_upload(media_id: u64) -> Result<MediaAsset, Error> {
+    let mut asset = match _get_media(&media_id) {
+        Some(asset) => asset,
+        None => return Err(Error::not_found(EntityKind::Media, media_id)),
+    };
+    authorize_media_owner(&asset)?;
+    if asset.status != MediaStatus::Uploading {
+        return Err(Error::Conflict {
+            msg: format!("Media with id={} is already uploaded", media_id),
+        });
+    }
+
+    let chunk_count = media_chunk_count(asset.size);
+    let uploaded = MEDIA_CHUNKS.with(|chunks| chunks.borrow().range((media_id, 0)..(media_id, chunk_count)).count());
+    if uploaded as u32 != chunk_count {
+        return Err(Error::invalid_input(format!("Media with id={} has {} of {} chunks uploaded", media_id, uploaded, chunk_count)));
+    }
+
+    // The declared content type must match the file's signature
+    let head = MEDIA_CHUNKS.with(|chunks| chunks.borrow().get(&(media_id, 0))).map(|chunk| chunk.0).unwrap_or_default();
+    if !media_signature_matches(&asset.content_type, &head) {
+        return Err(Error::invalid_input(format!("Media with id={} is not a valid {} file", media_id, asset.content_type)));
+    }
+
+    asset.status = MediaStatus::Ready;
+    asset.updated_at = Some(time());
+    do_insert_media(&asset);
+    if asset.thumbnail_of.is_none() {
+        if let Some(mut product) = _get_product(&asset.product_id) {
+            product.media_ids.push(asset.id);
+            product.updated_at = Some(time());
+            do_insert_product(&product);
+        }
+    }
+    Ok(asset)
+}
+
+// Deletes an image, or abandons an upload, together with its thumbnails
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn delete_media(media_id: u64) -> Result<MediaAsset, Error> {
+    let asset = match _get_media(&media_id) {
+        Some(asset) => asset,
+        None => return Err(Error::not_found(EntityKind::Media, media_id)),
+    };
+    let product_id = asset.product_id;
+    match _get_product(&product_id) {
+        Some(product) => authorize_owner_or(product.seller_id, &[Role::Admin])?,
+        None => authorize(&[Role::Admin])?,
+    };
+
+    for thumbnail in _get_product_media(product_id).iter().filter(|other| other.thumbnail_of == Some(media_id)) {
+        remove_media_asset(thumbnail);
+    }
+    remove_media_asset(&asset);
+    if let Some(mut product) = _get_product(&product_id) {
+        product.media_ids.retain(|id| *id != media_id);
+        product.updated_at = Some(time());
+        do_insert_product(&product);
+    }
+    Ok(asset)
+}
+
+#[ic_cdk::query]
+fn view_media(media_id: u64) -> Result<MediaAsset, Error> {
+    match _get_media(&media_id) {
+        Some(asset) => Ok(asset),
+        None => Err(Error::not_found(EntityKind::Media, media_id)),
+    }
+}
+
+// Every uploaded image of a product, thumbnails included; pending uploads are left out
+#[ic_cdk::query]
+fn list_product_media(product_id: u64) -> Result<Vec<MediaAsset>, Error> {
+    if _get_product(&product_id).is_none() {
+        return Err(Error::not_found(EntityKind::Product, product_id));
+    }
+    Ok(_get_product_media(product_id)
+        .into_iter()
+        .filter(|asset| asset.status == MediaStatus::Ready)
+        .collect())
+}
+
+// Bytes of one chunk of an uploaded image, for agents that do not go through HTTP
+#[ic_cdk::query]
+fn get_media_chunk(media_id: u64, chunk_index: u32) -> Result<Vec<u8>, Error> {
+    match _get_media(&media_id) {
+        Some(asset) if asset.status == MediaStatus::Ready => {}
+        _ => return Err(Error::not_found(EntityKind::Media, media_id)),
+    }
+    match MEDIA_CHUNKS.with(|chunks| chunks.borrow().get(&(media_id, chunk_index))) {
+        Some(chunk) => Ok(chunk.0),
+        None => Err(Error::NotFound {
+            entity: EntityKind::MediaChunk,
+            id: media_id,
+            msg: format!("Chunk {} of media with id={} not found", chunk_index, media_id),
+        }),
+    }
+}
+
+// HTTP interface. `GET /media/{id}` serves an uploaded image; `/products`,
+// `/products/{id}`, `/sellers/{id}` and `/categories` form a read-only JSON API.
+//
+// Query responses would have to be certified to pass the gateway, and the JSON pages depend
+// on every query parameter, so they cannot be certified ahead of time. Instead the gateway is
+// asked to repeat those requests as `http_request_update`, whose responses the subnet signs.
+// Images never change and are served by the query, uncertified, on the `raw` domain.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::error(405, "Method not allowed").with_header("Allow", "GET");
+    }
+    let (segments, _) = http::parse_url(&request.url);
+    match segments.first().map(String::as_str) {
+        Some("media") => route_http_request(&request),
+        _ => HttpResponse::upgrade(),
+    }
+}
+
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::error(405, "Method not allowed").with_header("Allow", "GET");
+    }
+    route_http_request(&request)
+}
+
+fn route_http_request(request: &HttpRequest) -> HttpResponse {
+    let (segments, params) = http::parse_url(&request.url);
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    match segments.as_slice() {
+        ["media", id] => match id.parse::<u64>() {
+            Ok(media_id) => serve_media(media_id),
+            Err(_) => HttpResponse::error(400, "Invalid media id"),
+        },
+        ["products"] => json_result(http_list_products(&params)),
+        ["products", id] => json_result(parse_id(id).and_then(view_product)),
+        ["products", id, "reviews"] => json_result(parse_id(id).and_then(|product_id| {
+            let limit = query_param(&params, "limit")?.unwrap_or(DEFAULT_HTTP_PAGE_SIZE);
+            list_product_reviews(product_id, query_param(&params, "start_after")?, limit)
+        })),
+        ["sellers", id] => json_result(parse_id(id).and_then(view_seller)),
+        ["categories"] => json_result(Ok(list_categories(false))),
+        _ => HttpResponse::error(404, &format!("No route for {}", request.url)),
+    }
+}
+
+// Page of products as returned over HTTP, with the cursor in its query-string form
+#[derive(Serialize)]
+struct HttpProductPage {
+    products: Vec<Product>,
+    total: Option<u64>,
+    next_cursor: Option<String>,
+}
+
+// `GET /products` lists the catalog like `list_products`, or searches it like
+// `search_products` when `q` is given. Parameters: q, seller_id, min_price, max_price,
+// in_stock, category_id, sort (price | created_at | rating), order (asc | desc), cursor, limit.
+fn http_list_products(params: &BTreeMap<String, String>) -> Result<HttpProductPage, Error> {
+    let cursor = match params.get("cursor") {
+        Some(cursor) => Some(parse_product_cursor(cursor)?),
+        None => None,
+    };
+    let limit = query_param(params, "limit")?.unwrap_or(DEFAULT_HTTP_PAGE_SIZE);
+
+    let page = match params.get("q") {
+        Some(query) => search_products(query.clone(), cursor, limit)?,
+        None => {
+            let sort_by = match params.get("sort").map(String::as_str) {
+                None | Some("created_at") => ProductSortField::CreatedAt,
+                Some("price") => ProductSortField::Price,
+                Some("rating") => ProductSortField::Rating,
+                Some(other) => return Err(Error::invalid_input(format!("Unknown sort field {}", other))),
+            };
+            let direction = match params.get("order").map(String::as_str) {
+                None | Some("desc") => SortDirection::Descending,
+                Some("asc") => SortDirection::Ascending,
+                Some(other) => return Err(Error::invalid_input(format!("Unknown sort order {}", other))),
+            };
+            list_products(ProductQuery {
+                filter: ProductFilter {
+                    seller_id: query_param(params, "seller_id")?,
+                    min_price: query_param(params, "min_price")?,
+                    max_price: query_param(params, "max_price")?,
+                    in_stock: query_param(params, "in_stock")?,
+                    category_id: query_param(params, "category_id")?,
+                },
+                sort_by,
+                direction,
+                cursor,
+                limit,
+            })?
+        }
+    };
+
+    Ok(HttpProductPage {
+        products: page.products,
+        total: page.total,
+        next_cursor: page.next_cursor.map(|cursor| format!("{}.{}", cursor.sort_key, cursor.id)),
+    })
+}
+
+// What anyone may see about a seller; contact details stay private
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SellerProfile {
+    id: u64,
+    name: String,
+    reputation: u8,
+    product_count: u64,
+    created_at: u64,
+}
+
+#[ic_cdk::query]
+fn view_seller(seller_id: u64) -> Result<SellerProfile, Error> {
+    let seller = match _get_user(&seller_id) {
+        Some(user) if user.role == Role::Seller => with_reputation(user),
+        _ => return Err(Error::not_found(EntityKind::Seller, seller_id)),
+    };
+    let product_count = PRODUCTS_BY_SELLER.with(|index| {
+        index.borrow().range((seller_id, 0)..=(seller_id, u64::MAX)).count() as u64
+    });
+    Ok(SellerProfile {
+        id: seller.id,
+        name: seller.name,
+        reputation: seller.reputation,
+        product_count,
+        created_at: seller.created_at,
+    })
+}
+
+// Serializes `result` as JSON: the value with 200, or `{"code": ..., "error": ...}` with the
+// error's status
+fn json_result<T: serde::Serialize>(result: Result<T, Error>) -> HttpResponse {
+    let (status_code, body) = match result {
+        Ok(value) => (200, serde_json::to_vec(&value)),
+        Err(error) => (
+            error.http_status_code(),
+            serde_json::to_vec(&serde_json::json!({ "code": error.code(), "error": error })),
+        ),
+    };
+    match body {
+        Ok(body) => HttpResponse::json(status_code, body),
+        Err(err) => HttpResponse::error(500, &format!("Failed to encode response: {}", err)),
+    }
+}
+
+fn parse_id(segment: &str) -> Result<u64, Error> {
+    segment.parse().map_err(|_| Error::invalid_input(format!("Invalid id {}", segment)))
+}
+
+fn query_param<T: std::str::FromStr>(params: &BTreeMap<String, String>, name: &str) -> Result<Option<T>, Error> {
+    match params.get(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| Error::invalid_input(format!("Invalid value {} for query parameter {}", value, name))),
+        None => Ok(None),
+    }
+}
+
+// Cursors travel as "<sort_key>.<id>"
+fn parse_product_cursor(value: &str) -> Result<ProductCursor, Error> {
+    let invalid = || Error::invalid_input(format!("Invalid cursor {}", value));
+    let (sort_key, id) = value.split_once('.').ok_or_else(invalid)?;
+    Ok(ProductCursor {
+        sort_key: sort_key.parse().map_err(|_| invalid())?,
+        id: id.parse().map_err(|_| invalid())?,
+    })
+}
+
+fn serve_media(media_id: u64) -> HttpResponse {
+    let asset = match _get_media(&media_id) {
+        Some(asset) if asset.status == MediaStatus::Ready => asset,
+        _ => return HttpResponse::error(404, &format!("Media with id={} not found", media_id)),
+    };
+    let body: Vec<u8> = MEDIA_CHUNKS.with(|chunks| {
+        chunks
+            .borrow()
+            .range((media_id, 0)..(media_id, media_chunk_count(asset.size)))
+            .flat_map(|(_, chunk)| chunk.0)
+            .collect()
+    });
+    // Uploaded images never change; a replaced image gets a new id
+    HttpResponse::ok(&asset.content_type, body).with_header("Cache-Control", "public, max-age=31536000, immutable")
+}
+
+// Category management
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn create_category(name: String, parent_id: Option<u64>) -> Result<Category, Error> {
+    authorize(&[Role::Admin])?;
+    validate_category_name(&name)?;
+    if let Some(parent_id) = parent_id {
+        match _get_category(&parent_id) {
+            Some(parent) if !parent.archived => {}
+            Some(_) => return Err(Error::invalid_input(format!("Category with id={} is archived", parent_id))),
+            None => return Err(Error::not_found(EntityKind::Category, parent_id)),
+        }
+    }
+    ensure_unique_sibling_name(&name, parent_id, None)?;
+
+    let id = CATEGORY_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let category = Category {
+        id,
+        name,
+        parent_id,
+        archived: false,
+        created_at: time(),
+        updated_at: None,
+    };
+    do_insert_category(&category);
+    Ok(category)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn rename_category(category_id: u64, name: String) -> Result<Category, Error> {
+    authorize(&[Role::Admin])?;
+    validate_category_name(&name)?;
+    let mut category = match _get_category(&category_id) {
+        Some(category) => category,
+        None => return Err(Error::not_found(EntityKind::Category, category_id)),
+    };
+    ensure_unique_sibling_name(&name, category.parent_id, Some(category_id))?;
+
+    category.name = name;
+    category.updated_at = Some(time());
+    do_insert_category(&category);
+    Ok(category)
+}
+
+// Re-parents a category together with its whole subtree; None makes it top-level
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn move_category(category_id: u64, parent_id: Option<u64>) -> Result<Category, Error> {
+    authorize(&[Role::Admin])?;
+    let mut category = match _get_category(&category_id) {
+        Some(category) => category,
+        None => return Err(Error::not_found(EntityKind::Category, category_id)),
+    };
+    if let Some(parent_id) = parent_id {
+        match _get_category(&parent_id) {
+            Some(parent) if !parent.archived => {}
+            Some(_) => return Err(Error::invalid_input(format!("Category with id={} is archived", parent_id))),
+            None => return Err(Error::not_found(EntityKind::Category, parent_id)),
+        }
+        // A category cannot become its own ancestor
+        if category_subtree(category_id).contains(&parent_id) {
+            return Err(Error::invalid_input(format!("Category with id={} cannot be moved under its own subtree", category_id)));
+        }
+    }
+    ensure_unique_sibling_name(&category.name, parent_id, Some(category_id))?;
+
+    category.parent_id = parent_id;
+    category.updated_at = Some(time());
+    do_insert_category(&category);
+    Ok(category)
+}
+
+// Archives a category and all of its descendants. Their products stay assigned and browsable,
+// but no product can be newly assigned to them.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn archive_category(category_id: u64) -> Result<Category, Error> {
+    authorize(&[Role::Admin])?;
+    if _get_category(&category_id).is_none() {
+        return Err(Error::not_found(EntityKind::Category, category_id));
+    }
+
+    let now = time();
+    for id in category_subtree(category_id) {
+        if let Some(mut category) = _get_category(&id) {
+            if !category.archived {
+                category.archived = true;
+                category.updated_at = Some(now);
+                do_insert_category(&category);
+            }
+        }
+    }
+    match _get_category(&category_id) {
+        Some(category) => Ok(category),
+        None => Err(Error::not_found(EntityKind::Category, category_id)),
+    }
+}
+
+#[ic_cdk::query]
+fn view_category(category_id: u64) -> Result<Category, Error> {
+    match _get_category(&category_id) {
+        Some(category) => Ok(category),
+        None => Err(Error::not_found(EntityKind::Category, category_id)),
+    }
+}
+
+// A category with the number of distinct products in it or any of its descendants
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CategorySummary {
+    category: Category,
+    product_count: u64,
+}
+
+// The whole category tree in id order; clients rebuild the hierarchy from `parent_id`. Each
+// product is counted once in every category above any of its own, so the tree is counted in
+// one pass over the category index.
+#[ic_cdk::query]
+fn list_categories(include_archived: bool) -> Vec<CategorySummary> {
+    let categories: Vec<Category> = CATEGORIES.with(|categories| categories.borrow().iter().map(|(_, category)| category).collect());
+    let parents: BTreeMap<u64, u64> = categories
+        .iter()
+        .filter_map(|category| category.parent_id.map(|parent_id| (category.id, parent_id)))
+        .collect();
+
+    let mut product_categories: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    PRODUCTS_BY_CATEGORY.with(|index| {
+        for ((category_id, product_id), _) in index.borrow().iter() {
+            product_categories.entry(product_id).or_default().push(category_id);
+        }
+    });
+    let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+    for category_ids in product_categories.values() {
+        let mut counted = BTreeSet::new();
+        for category_id in category_ids {
+            let mut next = Some(*category_id);
+            // Stops at the root, or where another of the product's categories already went
+            while let Some(id) = next.filter(|id| counted.insert(*id)) {
+                *counts.entry(id).or_insert(0) += 1;
+                next = parents.get(&id).copied();
+            }
+        }
+    }
+
+    categories
+        .into_iter()
+        .filter(|category| include_archived || !category.archived)
+        .map(|category| {
+            let product_count = counts.get(&category.id).copied().unwrap_or(0);
+            CategorySummary { category, product_count }
+        })
+        .collect()
+}
+
+// CRUD operations for Users
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn register(payload: UserPayload) -> Result<User, Error> {
+    // Validate inputs
+    validate_user_payload(&payload)?;
+
+    // Privileged roles are only granted through `set_user_role`
+    if !payload.role.is_self_assignable() {
+        return Err(Error::Unauthorized {
+            msg: format!("Role {:?} cannot be self-assigned", payload.role),
+        });
+    }
+
+    // Each caller identity can own exactly one user
+    let principal = caller();
+    if let Some(user_id) = _get_user_id_by_principal(&principal) {
+        return Err(Error::Conflict {
+            msg: format!("Caller is already registered as user with id={}", user_id),
+        });
+    }
+
+    // Generate a new user ID
+    let id = USER_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+
+    // Create the user
+    let user = User {
+        id,
+        principal,
+        name: payload.name,
+        email: payload.email,
+        role: payload.role,
+        reputation: REPUTATION_BASE as u8, // New users start in the middle
+        created_at: time(),
+        updated_at: None,
+    };
+    do_insert_user(&user);
+    USER_PRINCIPALS.with(|index| index.borrow_mut().insert(StorablePrincipal(principal), id));
+    Ok(user)
+}
+
+#[ic_cdk::query]
+fn whoami() -> Result<User, Error> {
+    _get_caller_user().map(with_reputation)
+}
+
+// Users carry contact details, so only they and the staff can read them
+#[ic_cdk::query]
+fn view_user(user_id: u64) -> Result<User, Error> {
+    authorize_owner_or(user_id, &[Role::Admin, Role::Arbiter])?;
+    match _get_user(&user_id) {
+        Some(user) => Ok(with_reputation(user)),
+        None => Err(Error::not_found(EntityKind::User, user_id)),
+    }
+}
+
+// Running counts behind a user's reputation. Only account age is added when it is read.
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct ReputationCounters {
+    total_orders: u64, // As buyer or seller
+    completed_orders: u64,
+    cancelled_orders: u64, // Cancelled by the user themself
+    disputes_lost: u64,
+    review_count: u64, // Reviews of the user's products
+    rating_total: u64, // Sum of their ratings
+}
+
+// What a user's reputation is made of. Every signal adds or takes off points from a neutral
+// base; the sum is capped to 0..=100.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReputationBreakdown {
+    user_id: u64,
+    score: u8,
+    completed_orders: u64, // As buyer or seller
+    total_orders: u64,
+    cancelled_orders: u64, // Cancelled by the user themself
+    cancellation_rate_bps: u32,
+    disputes_lost: u64,
+    review_count: u64,   // Reviews of the user's products
+    average_rating: f64, // 0 without reviews
+    account_age_days: u64,
+    factors: Vec<ReputationFactor>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReputationFactor {
+    signal: ReputationSignal,
+    points: i64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+enum ReputationSignal {
+    Base,
+    CompletedOrders,
+    AccountAge,
+    ReviewScores,
+    CancellationRate,
+    DisputesLost,
+}
+
+#[ic_cdk::query]
+fn view_reputation(user_id: u64) -> Result<ReputationBreakdown, Error> {
+    match _get_user(&user_id) {
+        Some(user) => Ok(reputation_breakdown(&user)),
+        None => Err(Error::not_found(EntityKind::User, user_id)),
+    }
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn update_user(payload: UserPayload) -> Result<User, Error> {
+    // Validate inputs
+    validate_user_payload(&payload)?;
+
+    // Callers can only update their own profile
+    let mut user = _get_caller_user()?;
+
+    // Users may switch between buyer and seller, anything else goes through `set_user_role`
+    if payload.role != user.role && !(user.role.is_self_assignable() && payload.role.is_self_assignable()) {
+        return Err(Error::Unauthorized {
+            msg: format!("User with id={} cannot change role from {:?} to {:?}", user.id, user.role, payload.role),
+        });
+    }
+
+    // Update the user
+    user.name = payload.name;
+    user.email = payload.email;
+    user.role = payload.role;
+    user.updated_at = Some(time());
+    do_insert_user(&user);
+    Ok(with_reputation(user))
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn set_user_role(user_id: u64, role: Role) -> Result<User, Error> {
+    // Controllers can grant roles so that the first admin can be bootstrapped
+    if !system::is_controller(&caller()) {
+        authorize(&[Role::Admin])?;
+    }
+
+    let mut user = match _get_user(&user_id) {
+        Some(user) => user,
+        None => return Err(Error::not_found(EntityKind::User, user_id)),
+    };
+
+    user.role = role;
+    user.updated_at = Some(time());
+    do_insert_user(&user);
+    Ok(with_reputation(user))
+}
+
+// Hands a user carried over from the first release, which had no principals, to its owner
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn assign_user_principal(user_id: u64, principal: Principal) -> Result<User, Error> {
+    // Controllers can assign principals so that legacy admins can be claimed first
+    if !system::is_controller(&caller()) {
+        authorize(&[Role::Admin])?;
+    }
+
+    let mut user = match _get_user(&user_id) {
+        Some(user) => user,
+        None => return Err(Error::not_found(EntityKind::User, user_id)),
+    };
+    if user.principal != Principal::anonymous() {
+        return Err(Error::Conflict {
+            msg: format!("User with id={} already belongs to a principal", user_id),
+        });
+    }
+    if principal == Principal::anonymous() {
+        return Err(Error::invalid_field("principal", "Cannot be the anonymous principal."));
+    }
+    if let Some(owner_id) = _get_user_id_by_principal(&principal) {
+        return Err(Error::Conflict {
+            msg: format!("Principal is already registered as user with id={}", owner_id),
+        });
+    }
+
+    user.principal = principal;
+    user.updated_at = Some(time());
+    do_insert_user(&user);
+    USER_PRINCIPALS.with(|index| index.borrow_mut().insert(StorablePrincipal(principal), user.id));
+    Ok(with_reputation(user))
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn delete_user(user_id: u64) -> Result<User, Error> {
+    // Users can delete their own account, admins can delete any account
+    authorize_owner_or(user_id, &[Role::Admin])?;
+
+    // Payouts and refunds go to the parties' accounts, so both must outlive their open orders
+    if let Some(order_id) = unsettled_order_of(user_id) {
+        return Err(Error::Conflict {
+            msg: format!("User with id={} is a party to unsettled order with id={}", user_id, order_id),
+        });
+    }
+
+    match USERS_STORAGE.with(|users| users.borrow_mut().remove(&user_id)) {
+        Some(user) => {
+            USER_PRINCIPALS.with(|index| index.borrow_mut().remove(&StorablePrincipal(user.principal)));
+            let user = with_reputation(user);
+            REPUTATION_COUNTERS.with(|counters| counters.borrow_mut().remove(&user_id));
+            Ok(user)
+        }
+        None => Err(Error::not_found(EntityKind::User, user_id)),
+    }
+}
+
+// CRUD operations for Orders
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn create_order(payload: OrderPayload) -> Result<Order, Error> {
+    // Validate order payload
+    validate_order_payload(&payload)?;
+
+    // The buyer is always the registered user behind the caller
+    let buyer = authorize(&[Role::Buyer])?;
+    check_order_rate(buyer.id)?;
+
+    let (product, variant) = resolve_listing(payload.product_id, payload.variant_id)?;
+
+    // Check stock availability
+    let available = available_stock(&product, variant.as_ref());
+    if payload.quantity > available {
+        return Err(Error::insufficient_stock(product.id, payload.variant_id, payload.quantity, available));
+    }
+
+    // Price the order from the current listing
+    let price_breakdown = compute_price(&product, variant.as_ref(), payload.quantity, product.shipping_fee)?;
+    check_expected_total(price_breakdown.total, payload.expected_total)?;
+
+    let total_price = price_breakdown.total;
+    let items = vec![OrderItem {
+        product_id: product.id,
+        variant_id: payload.variant_id,
+        quantity: payload.quantity,
+        price_breakdown,
+    }];
+    place_order(buyer.id, product.seller_id, items, total_price)
+}
+
+#[ic_cdk::query]
+fn quote_order(payload: OrderPayload) -> Result<PriceBreakdown, Error> {
+    validate_order_payload(&payload)?;
+
+    let (product, variant) = resolve_listing(payload.product_id, payload.variant_id)?;
+    compute_price(&product, variant.as_ref(), payload.quantity, product.shipping_fee)
+}
+
+#[ic_cdk::query]
+fn view_order(order_id: u64) -> Result<Order, Error> {
+    let order = match _get_order(&order_id) {
+        Some(order) => order,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+    authorize_order_party(&order)?;
+    Ok(order)
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrderPage {
+    orders: Vec<Order>,
+    total: u64, // Orders matching the filter across all pages
+    next_cursor: Option<u64>, // Pass as `start_after` for the next page; None on the last page
+}
+
+// Orders the caller placed, oldest first. An empty `statuses` matches every status.
+#[ic_cdk::query]
+fn list_my_orders(statuses: Vec<OrderStatus>, start_after: Option<u64>, limit: u32) -> Result<OrderPage, Error> {
+    let buyer = _get_caller_user()?;
+    Ok(order_page(OrderScope::Buyer(buyer.id), &statuses, start_after, limit))
+}
+
+// Orders placed with the calling seller, oldest first. An empty `statuses` matches every status.
+#[ic_cdk::query]
+fn list_seller_orders(statuses: Vec<OrderStatus>, start_after: Option<u64>, limit: u32) -> Result<OrderPage, Error> {
+    let seller = authorize(&[Role::Seller])?;
+    Ok(order_page(OrderScope::Seller(seller.id), &statuses, start_after, limit))
+}
+
+// All orders in one status, oldest first, e.g. the open disputes for arbiters
+#[ic_cdk::query]
+fn list_orders_by_status(status: OrderStatus, start_after: Option<u64>, limit: u32) -> Result<OrderPage, Error> {
+    authorize(&[Role::Admin, Role::Arbiter])?;
+    Ok(order_page(OrderScope::All, &[status], start_after, limit))
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransactionPage {
+    transactions: Vec<Transaction>,
+    next_cursor: Option<u64>, // Pass as `start_after` for the next page; None on the last page
+}
+
+// Escrow payments, refunds and payouts of a user, oldest first
+#[ic_cdk::query]
+fn list_transactions(user_id: u64, start_after: Option<u64>, limit: u32) -> Result<TransactionPage, Error> {
+    authorize_owner_or(user_id, &[Role::Admin, Role::Arbiter])?;
+
+    let start = start_after.map_or(0, |id| id.saturating_add(1));
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        let mut remaining = transactions.range((user_id, start)..=(user_id, u64::MAX)).map(|(_, transaction)| transaction);
+        let page: Vec<Transaction> = remaining.by_ref().take(limit).collect();
+        let next_cursor = match remaining.next() {
+            Some(_) => page.last().map(|transaction| transaction.id),
+            None => None,
+        };
+        Ok(TransactionPage {
+            transactions: page,
+            next_cursor,
+        })
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn update_order(order_id: u64, payload: OrderPayload) -> Result<Order, Error> {
+    // Validate order payload
+    validate_order_payload(&payload)?;
+
+    let mut order = match _get_order(&order_id) {
+        Some(order) => order,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+
+    // Only the buyer who placed the order can modify it
+    authorize_owner_or(order.buyer_id, &[])?;
+
+    // Ensure the order status allows updates
+    if payment_in_progress(&order) {
+        return Err(Error::Conflict {
+            msg: format!("Order with id={} has a payment in progress", order.id),
+        });
+    }
+    if order.status != OrderStatus::Pending {
+        return Err(Error::Conflict {
+            msg: "Only pending orders can be updated.".to_string(),
+        });
+    }
+
+    // Re-price the order from the current listing
+    let (product, variant) = resolve_listing(payload.product_id, payload.variant_id)?;
+    let price_breakdown = compute_price(&product, variant.as_ref(), payload.quantity, product.shipping_fee)?;
+    check_expected_total(price_breakdown.total, payload.expected_total)?;
+
+    // Units this order already holds of the product or variant count as available to it
+    let already_reserved: u32 = order
+        .items
+        .iter()
+        .filter(|item| item.product_id == product.id && item.variant_id == payload.variant_id)
+        .map(|item| item.quantity)
+        .sum();
+    let available = available_stock(&product, variant.as_ref()).saturating_add(already_reserved);
+    if payload.quantity > available {
+        return Err(Error::insufficient_stock(product.id, payload.variant_id, payload.quantity, available));
+    }
+
+    // Swap the reservation over to the new items, keeping the original expiry
+    let reserved_until = order.reserved_until.unwrap_or_else(reservation_expiry);
+    restock_order(&mut order)?;
+
+    // Update the order, which becomes a single-item order for the new product
+    order.seller_id = product.seller_id;
+    order.total_price = price_breakdown.total;
+    order.items = vec![OrderItem {
+        product_id: product.id,
+        variant_id: payload.variant_id,
+        quantity: payload.quantity,
+        price_breakdown,
+    }];
+    reserve_stock(&mut order, reserved_until)?;
+    order.updated_at = Some(time());
+    do_insert_order(&order);
+    Ok(order)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn cancel_order(order_id: u64) -> Result<Order, Error> {
+    let mut order = match _get_order(&order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+
+    // Either party can back out before shipping; admins can cancel on their behalf
+    let actor = match authorize_owner_or(order.buyer_id, &[Role::Admin]) {
+        Ok(user) => user,
+        Err(_) => authorize_owner_or(order.seller_id, &[])?,
+    };
+
+    // Paid orders hand the escrowed funds back to the buyer
+    if order.status == OrderStatus::Paid {
+        let (order, _) = settle_order(order.id, OrderStatus::Cancelled, Payout::ToBuyer, Some(actor.id)).await?;
+        return Ok(order);
+    }
+
+    transition_order(&mut order, OrderStatus::Cancelled, Some(actor.id))?;
+    restock_order(&mut order)?;
+    do_insert_order(&order);
+    Ok(order)
+}
+
+// Removes an order that never went through: a pending order of the caller, whose reserved
+// stock is released, or any cancelled order for admins
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn delete_order(order_id: u64) -> Result<Order, Error> {
+    let mut order = match _get_order(&order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+
+    // Cancelled orders count towards reputation, so only admins clean them up
+    let actor = authorize_owner_or(order.buyer_id, &[Role::Admin])?;
+    match order.status {
+        OrderStatus::Pending => {}
+        OrderStatus::Cancelled if actor.role == Role::Admin => {}
+        OrderStatus::Cancelled => {
+            return Err(Error::Unauthorized {
+                msg: format!("User with id={} cannot delete cancelled orders", actor.id),
+            })
+        }
+        status => {
+            return Err(Error::Conflict {
+                msg: format!("Order with id={} is {:?}; only pending or cancelled orders can be deleted", order.id, status),
+            })
+        }
+    }
+    if payment_in_progress(&order) {
+        return Err(Error::Conflict {
+            msg: format!("Order with id={} has a payment in progress", order.id),
+        });
+    }
+
+    // Cancelling already put the items of cancelled orders back
+    if order.status == OrderStatus::Pending {
+        restock_order(&mut order)?;
+    }
+    ORDERS_STORAGE.with(|orders| orders.borrow_mut().remove(&order.id));
+    unindex_order(&order);
+    if reputation_counts("order_reputation", order.id) {
+        count_order_reputation(Some(&order), None);
+    }
+    certified::uncertify(certified::ORDERS, order.id);
+    Ok(order)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn pay_order(order_id: u64) -> Result<Order, Error> {
+    let mut order = match _get_order(&order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+
+    // Only the buyer pays for their order
+    let buyer = authorize_owner_or(order.buyer_id, &[])?;
+
+    if let Some(escrow_id) = _get_escrow_id_by_order(&order.id) {
+        return Err(Error::Conflict {
+            msg: format!("Order with id={} already has escrow with id={}", order.id, escrow_id),
+        });
+    }
+    if !order.status.can_transition_to(OrderStatus::Paid) {
+        return Err(Error::invalid_transition(EntityKind::Order, order.id, order.status, OrderStatus::Paid));
+    }
+
+    // Pull the full order amount into the order's escrow subaccount. The buyer must have
+    // approved the canister for at least the amount plus the ledger fee via `icrc2_approve`.
+    let lock = OrderLock::acquire(order.id)?;
+    let ledger = configured_ledger()?;
+    let buyer_account = Account { owner: buyer.principal, subaccount: None };
+    let (funding, funding_block) = loop {
+        // An attempt whose answer was lost is sent again unchanged, so that the ledger answers
+        // one that landed as a duplicate instead of charging the buyer twice
+        let stored = order.funding_transfer.is_some();
+        let transfer = match order.funding_transfer.clone() {
+            Some(transfer) => transfer,
+            None => {
+                let fee = ledger.fee().await.map_err(|msg| Error::PaymentFailed { msg })?;
+                let transfer = LedgerTransfer {
+                    amount: order.total_price,
+                    fee,
+                    memo: order.id.to_be_bytes().to_vec(),
+                    created_at_time: time(),
+                };
+                order.funding_transfer = Some(transfer.clone());
+                do_insert_order(&order);
+                transfer
+            }
+        };
+        let outcome = ledger
+            .transfer_from(buyer_account, escrow_account(order.id), transfer.amount, transfer.fee, transfer.memo.clone(), transfer.created_at_time)
+            .await;
+        match outcome {
+            Ok(block) => break (transfer, block),
+            Err(TransferFailure::Unknown(msg)) => return Err(Error::PaymentFailed { msg }),
+            Err(failure) if !stored => {
+                order.funding_transfer = None;
+                do_insert_order(&order);
+                return Err(Error::PaymentFailed { msg: failure.to_string() });
+            }
+            // A stored attempt is refused once too old or once the fee changed, even if it landed
+            Err(_) => {
+                let balance = ledger.balance(escrow_account(order.id)).await.map_err(|msg| Error::PaymentFailed { msg })?;
+                if balance >= transfer.amount {
+                    return Err(Error::PaymentFailed {
+                        msg: format!("Payment for order with id={} landed on the ledger, but the ledger no longer reports its block", order.id),
+                    });
+                }
+                order.funding_transfer = None;
+            }
+        }
+    };
+    order.funding_transfer = None;
+    drop(lock);
+
+    let id = ESCROW_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let escrow = Escrow {
+        id,
+        order_id: order.id,
+        amount: funding.amount,
+        status: EscrowStatus::Held,
+        funding_block: Some(funding_block),
+        settlement_block: None,
+        split: None,
+        created_at: time(),
+        updated_at: None,
+    };
+    do_insert_escrow(&escrow);
+    ORDER_ESCROWS.with(|index| index.borrow_mut().insert(order.id, escrow.id));
+    record_transaction(buyer.id, &order, escrow.id, TransactionKind::EscrowFunded, escrow.amount, None, funding_block)?;
+
+    transition_order(&mut order, OrderStatus::Paid, Some(buyer.id))?;
+    commit_reservation(&mut order)?;
+    do_insert_order(&order);
+    Ok(order)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn ship_order(order_id: u64) -> Result<Order, Error> {
+    let mut order = match _get_order(&order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+
+    // Only the seller ships the order
+    let seller = authorize_owner_or(order.seller_id, &[])?;
+
+    transition_order(&mut order, OrderStatus::Shipped, Some(seller.id))?;
+    do_insert_order(&order);
+    Ok(order)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn mark_order_delivered(order_id: u64) -> Result<Order, Error> {
+    let mut order = match _get_order(&order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+
+    // The seller reports delivery; admins can do so on their behalf
+    let actor = authorize_owner_or(order.seller_id, &[Role::Admin])?;
+
+    transition_order(&mut order, OrderStatus::Delivered, Some(actor.id))?;
+    // The buyer has until then to confirm receipt or open a dispute
+    let release_at = auto_release_time();
+    order.auto_release_at = Some(release_at);
+    AUTO_RELEASES.with(|releases| releases.borrow_mut().insert(order.id, release_at));
+    schedule_auto_release(order.id, release_at);
+    do_insert_order(&order);
+    Ok(order)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn complete_order(order_id: u64) -> Result<Order, Error> {
+    let order_opt = ORDERS_STORAGE.with(|storage| storage.borrow().get(&order_id));
+    let order = match order_opt {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+
+    // The buyer confirms receipt; admins can complete on their behalf
+    let actor = authorize_owner_or(order.buyer_id, &[Role::Admin])?;
+    ensure_not_disputed(&order)?;
+
+    // Completing an order pays out the escrow to the seller
+    let (order, _) = settle_order(order.id, OrderStatus::Completed, Payout::ToSeller, Some(actor.id)).await?;
+    Ok(order)
+}
+
+// Largest page any listing query returns
+const MAX_PAGE_SIZE: u32 = 100;
+// Index entries `list_products` looks at per call, matching or not
+const MAX_PRODUCT_SCAN: usize = 1_000;
+// Index entries `list_products` counts to give the total of a filtered list
+const MAX_PRODUCT_COUNT_SCAN: usize = 10_000;
+
+// Text field limits. Stored records have no size bound, so these are what keeps them small.
+const MAX_PRODUCT_NAME_BYTES: usize = 200;
+const MAX_PRODUCT_DESCRIPTION_BYTES: usize = 10_000;
+const MAX_USER_NAME_BYTES: usize = 100;
+const MAX_EMAIL_BYTES: usize = 254;
+
+// Product variants
+const MAX_VARIANTS_PER_PRODUCT: usize = 100;
+const MAX_VARIANT_ATTRIBUTES: usize = 5;
+const MAX_SKU_BYTES: usize = 64;
+const MAX_ATTRIBUTE_BYTES: usize = 64; // Of each attribute name and value
+
+// HTTP interface
+const DEFAULT_HTTP_PAGE_SIZE: u32 = 20;
+
+// Product media
+const ALLOWED_MEDIA_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/gif", "image/webp"];
+const MAX_MEDIA_BYTES: u64 = 2 * 1024 * 1024; // Small enough to serve over HTTP in one response
+const MEDIA_CHUNK_BYTES: usize = 256 * 1024;
+const MAX_MEDIA_PER_PRODUCT: usize = 20;
+const MAX_ALT_TEXT_BYTES: usize = 200;
+
+// Product categories
+const MAX_CATEGORY_NAME_BYTES: usize = 100;
+const MAX_SECONDARY_CATEGORIES: usize = 5;
+
+// Full-text search
+const MAX_SEARCH_TERM_BYTES: usize = 32;
+const MAX_SEARCH_QUERY_TERMS: usize = 10;
+const MIN_SEARCH_PREFIX_CHARS: usize = 3; // Shorter query terms only match whole words
+const MAX_SEARCH_TERM_SCAN: usize = 10_000; // Index entries one query term may match
+
+// Shopping cart
+const MAX_CART_ITEMS: usize = 50;
+
+// Reviews
+const MAX_REVIEW_TEXT_BYTES: usize = 2_000;
+
+// Reputation
+const REPUTATION_BASE: i64 = 50;
+const POINTS_PER_COMPLETED_ORDER: i64 = 2;
+const MAX_COMPLETED_ORDER_POINTS: i64 = 20;
+const DAYS_PER_ACCOUNT_AGE_POINT: u64 = 30;
+const MAX_ACCOUNT_AGE_POINTS: i64 = 10;
+const POINTS_PER_REVIEW_STAR: f64 = 10.0; // Per star above or below an average of 3
+const MAX_CANCELLATION_PENALTY: i64 = 30; // At a cancellation rate of 100%
+const POINTS_PER_DISPUTE_LOST: i64 = 10;
+const MAX_DISPUTE_PENALTY: i64 = 40;
+
+// Disputes
+const DISPUTE_RESPONSE_WINDOW_SECS: u64 = 3 * 24 * 60 * 60;
+const DISPUTE_RESOLUTION_WINDOW_SECS: u64 = 7 * 24 * 60 * 60;
+const MAX_DISPUTE_MESSAGES: usize = 100;
+const MAX_DISPUTE_MESSAGE_BYTES: usize = 2_000;
+const MAX_DISPUTE_EVIDENCE: usize = 5; // Per message
+const MAX_EVIDENCE_BYTES: usize = 500;
+
+// Escrow
+const MAX_SPLIT_REASON_BYTES: usize = 500;
+const AUTO_RELEASE_RETRY_SECS: u64 = 60 * 60;
+
+// Order placement rate limit per buyer
+const ORDER_RATE_LIMIT: usize = 10;
+const ORDER_RATE_WINDOW_SECS: u64 = 60;
+
+// Variant id stored in cart keys for products bought without a variant
+const NO_VARIANT: u64 = 0;
+
+// A cart entry together with the product's current listing
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CartLine {
+    product_id: u64,
+    variant_id: Option<u64>,
+    quantity: u32,
+    product: Option<Product>, // None once the product has been delisted
+    variant: Option<ProductVariant>,
+    price_breakdown: Option<PriceBreakdown>,
+    in_stock: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CartView {
+    lines: Vec<CartLine>,
+    total: u64, // Sum of the priced lines
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn add_to_cart(product_id: u64, variant_id: Option<u64>, quantity: u32) -> Result<CartView, Error> {
+    let buyer = authorize(&[Role::Buyer])?;
+
+    if quantity == 0 {
+        return Err(Error::invalid_field("quantity", "Must be greater than zero."));
+    }
+    resolve_listing(product_id, variant_id)?;
+
+    // Adding a product that is already in the cart increases its quantity
+    let key = cart_key(buyer.id, product_id, variant_id);
+    let existing = CART_ITEMS.with(|cart| cart.borrow().get(&key));
+    if existing.is_none() && _get_cart_items(buyer.id).len() >= MAX_CART_ITEMS {
+        return Err(Error::invalid_input(format!("A cart can hold at most {} different products.", MAX_CART_ITEMS)));
+    }
+    let quantity = existing.unwrap_or(0).checked_add(quantity).ok_or_else(|| Error::invalid_field("quantity", "Is too large."))?;
+
+    do_insert_cart_item(key, quantity);
+    Ok(cart_view(buyer.id))
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn update_cart_item(product_id: u64, variant_id: Option<u64>, quantity: u32) -> Result<CartView, Error> {
+    let buyer = authorize(&[Role::Buyer])?;
+
+    let key = cart_key(buyer.id, product_id, variant_id);
+    if !CART_ITEMS.with(|cart| cart.borrow().contains_key(&key)) {
+        return Err(Error::NotFound {
+            entity: EntityKind::CartItem,
+            id: product_id,
+            msg: format!("Product with id={} is not in the cart", product_id),
+        });
+    }
+
+    // Setting the quantity to zero removes the product
+    if quantity == 0 {
+        do_remove_cart_item(&key);
+    } else {
+        do_insert_cart_item(key, quantity);
+    }
+    Ok(cart_view(buyer.id))
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn remove_from_cart(product_id: u64, variant_id: Option<u64>) -> Result<CartView, Error> {
+    let buyer = authorize(&[Role::Buyer])?;
+
+    match do_remove_cart_item(&cart_key(buyer.id, product_id, variant_id)) {
+        Some(_) => Ok(cart_view(buyer.id)),
+        None => Err(Error::NotFound {
+            entity: EntityKind::CartItem,
+            id: product_id,
+            msg: format!("Product with id={} is not in the cart", product_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn view_cart() -> Result<CartView, Error> {
+    let buyer = authorize(&[Role::Buyer])?;
+    Ok(cart_view(buyer.id))
+}
+
+// Turns the caller's cart into one pending order per seller. Every line is priced, and the
+// stock all orders reserve is checked together, before anything is written, so checkout
+// either places all orders or none.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn checkout(expected_total: Option<u64>) -> Result<Vec<Order>, Error> {
+    let buyer = authorize(&[Role::Buyer])?;
+    check_order_rate(buyer.id)?;
+
+    let cart = _get_cart_items(buyer.id);
+    if cart.is_empty() {
+        return Err(Error::invalid_input("Cart is empty."));
+    }
+
+    let mut lines = Vec::new();
+    for (product_id, variant_id, quantity) in cart {
+        let (product, variant) = resolve_listing(product_id, variant_id)?;
+        let available = available_stock(&product, variant.as_ref());
+        if quantity > available {
+            return Err(Error::insufficient_stock(product_id, variant_id, quantity, available));
+        }
+        lines.push((product, variant, variant_id, quantity));
+    }
+
+    let shipping = shipping_charges(lines.iter().map(|(product, ..)| Some(product)));
+    let mut items_by_seller: BTreeMap<u64, Vec<OrderItem>> = BTreeMap::new();
+    for ((product, variant, variant_id, quantity), shipping) in lines.into_iter().zip(shipping) {
+        let product_id = product.id;
+        let price_breakdown = compute_price(&product, variant.as_ref(), quantity, shipping)?;
+        items_by_seller.entry(product.seller_id).or_default().push(OrderItem {
+            product_id,
+            variant_id,
+            quantity,
+            price_breakdown,
+        });
+    }
+
+    let mut drafts = Vec::new();
+    for (seller_id, items) in items_by_seller {
+        let total_price = sum_totals(items.iter().map(|item| item.price_breakdown.total))?;
+        drafts.push((seller_id, items, total_price));
+    }
+    check_expected_total(sum_totals(drafts.iter().map(|draft| draft.2))?, expected_total)?;
+
+    // Lines that share a product are added up here as well, as placing an order would only
+    // find them short after the orders before it were placed
+    let listings: Vec<_> = drafts.iter().flat_map(|(_, items, _)| items).filter_map(item_listing).collect();
+    check_stock_change(&listings, -1)?;
+
+    let mut orders = Vec::new();
+    for (seller_id, items, total_price) in drafts {
+        orders.push(place_order(buyer.id, seller_id, items, total_price)?);
+    }
+
+    for order in &orders {
+        for item in &order.items {
+            do_remove_cart_item(&cart_key(buyer.id, item.product_id, item.variant_id));
+        }
+    }
+    Ok(orders)
+}
+
+// Prices the cart the way `checkout` would, shipping included once per seller
+fn cart_view(buyer_id: u64) -> CartView {
+    let entries: Vec<_> = _get_cart_items(buyer_id)
+        .into_iter()
+        .map(|(product_id, variant_id, quantity)| {
+            let product = _get_product(&product_id);
+            let variant = variant_id.and_then(|variant_id| _get_variant(product_id, variant_id));
+            (product_id, variant_id, quantity, product, variant)
+        })
+        .collect();
+    let shipping = shipping_charges(entries.iter().map(|entry| entry.3.as_ref()));
+
+    let mut total = 0u64;
+    let lines = entries
+        .into_iter()
+        .zip(shipping)
+        .map(|((product_id, variant_id, quantity, product, variant), shipping)| {
+            let price_breakdown = product.as_ref().and_then(|product| compute_price(product, variant.as_ref(), quantity, shipping).ok());
+            if let Some(breakdown) = &price_breakdown {
+                total = total.saturating_add(breakdown.total);
+            }
+            CartLine {
+                product_id,
+                variant_id,
+                quantity,
+                in_stock: product.as_ref().is_some_and(|product| quantity <= available_stock(product, variant.as_ref())),
+                product,
+                variant,
+                price_breakdown,
+            }
+        })
+        .collect();
+    CartView { lines, total }
+}
+
+// Available vs. reserved stock of a product; totals across variants if it has any
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StockLevel {
+    product_id: u64,
+    available: u32,
+    reserved: u32,
+    variants: Vec<VariantStockLevel>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct VariantStockLevel {
+    variant_id: u64,
+    sku: String,
+    available: u32,
+    reserved: u32,
+}
+
+#[ic_cdk::query]
+fn view_stock(product_id: u64) -> Result<StockLevel, Error> {
+    match _get_product(&product_id) {
+        Some(product) => Ok(StockLevel {
+            product_id,
+            available: product.stock_quantity,
+            reserved: product.reserved_quantity,
+            variants: _get_variants(product_id)
+                .into_iter()
+                .map(|variant| VariantStockLevel {
+                    variant_id: variant.id,
+                    sku: variant.sku,
+                    available: variant.stock_quantity,
+                    reserved: variant.reserved_quantity,
+                })
+                .collect(),
+        }),
+        None => Err(Error::not_found(EntityKind::Product, product_id)),
+    }
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn manage_inventory(product_id: u64, variant_id: Option<u64>, delta: i64, reason: StockAdjustmentReason) -> Result<Product, Error> {
+    // Products sold in variants are stocked per variant
+    let (mut product, mut variant) = resolve_listing(product_id, variant_id)?;
+
+    // Only the owning seller manages stock
+    let seller = authorize_owner_or(product.seller_id, &[])?;
+
+    let valid_direction = match reason {
+        StockAdjustmentReason::Restock => delta > 0,
+        StockAdjustmentReason::Shrinkage => delta < 0,
+        StockAdjustmentReason::Correction => delta != 0,
+    };
+    if !valid_direction {
+        return Err(Error::invalid_field("delta", format!("{} is not valid for reason {:?}.", delta, reason)));
+    }
+
+    // Adjustments apply on top of whatever orders have reserved in the meantime
+    record_stock_movement(&mut product, variant.as_mut(), delta, reason.into(), Some(seller.id), None)?;
     product.updated_at = Some(time());
     do_insert_product(&product);
+    if let Some(mut variant) = variant {
+        variant.updated_at = product.updated_at;
+        do_insert_variant(&variant);
+    }
     Ok(product)
 }
 
-#[ic_cdk::query]
-fn view_product(product_id: u64) -> Result<Product, Error> {
-    match _get_product(&product_id) {
-        Some(product) => Ok(product),
-        None => Err(Error::NotFound {
-            msg: format!("Product with id={} not found", product_id),
-        }),
-    }
+#[ic_cdk::query]
+fn view_stock_movements(product_id: u64, start_after: Option<u64>, limit: u32) -> Result<Vec<StockMovement>, Error> {
+    let product = match _get_product(&product_id) {
+        Some(product) => product,
+        None => return Err(Error::not_found(EntityKind::Product, product_id)),
+    };
+    authorize_owner_or(product.seller_id, &[Role::Admin])?;
+
+    let start = start_after.map_or(0, |id| id.saturating_add(1));
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    Ok(STOCK_MOVEMENTS.with(|movements| {
+        movements
+            .borrow()
+            .range((product_id, start)..=(product_id, u64::MAX))
+            .take(limit)
+            .map(|(_, movement)| movement)
+            .collect()
+    }))
+}
+
+// Checks that a product's stock movement log adds up to its current stock
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StockReconciliation {
+    product_id: u64,
+    stock_quantity: u32,
+    logged_quantity: i64,
+    consistent: bool,
+}
+
+#[ic_cdk::query]
+fn reconcile_stock(product_id: u64) -> Result<StockReconciliation, Error> {
+    let product = match _get_product(&product_id) {
+        Some(product) => product,
+        None => return Err(Error::not_found(EntityKind::Product, product_id)),
+    };
+    authorize_owner_or(product.seller_id, &[Role::Admin])?;
+
+    let logged_quantity = STOCK_MOVEMENTS.with(|movements| {
+        movements
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|(_, movement)| movement.delta)
+            .sum()
+    });
+    Ok(StockReconciliation {
+        product_id,
+        stock_quantity: product.stock_quantity,
+        logged_quantity,
+        consistent: logged_quantity == product.stock_quantity as i64,
+    })
+}
+
+#[ic_cdk::query]
+fn view_escrow(escrow_id: u64) -> Result<Escrow, Error> {
+    let escrow = match _get_escrow(&escrow_id) {
+        Some(escrow) => escrow,
+        None => return Err(Error::not_found(EntityKind::Escrow, escrow_id)),
+    };
+    match _get_order(&escrow.order_id) {
+        Some(order) => authorize_order_party(&order)?,
+        None => authorize(&[Role::Admin, Role::Arbiter])?,
+    };
+    Ok(escrow)
+}
+
+#[ic_cdk::query]
+fn view_order_escrow(order_id: u64) -> Result<Escrow, Error> {
+    match _get_order(&order_id) {
+        Some(order) => authorize_order_party(&order)?,
+        None => authorize(&[Role::Admin, Role::Arbiter])?,
+    };
+    match _get_escrow_id_by_order(&order_id).and_then(|escrow_id| _get_escrow(&escrow_id)) {
+        Some(escrow) => Ok(escrow),
+        None => Err(Error::NotFound {
+            entity: EntityKind::OrderEscrow,
+            id: order_id,
+            msg: format!("Escrow for order with id={} not found", order_id),
+        }),
+    }
+}
+
+// Reviews one item of a completed order on behalf of its buyer and adds the rating to the
+// product's summary
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn create_review(payload: ReviewPayload) -> Result<Review, Error> {
+    validate_review_payload(&payload)?;
+
+    let order = match _get_order(&payload.order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, payload.order_id)),
+    };
+    let buyer = authorize_owner_or(order.buyer_id, &[])?;
+    if order.status != OrderStatus::Completed {
+        return Err(Error::Conflict {
+            msg: format!("Order with id={} is {:?}; only completed orders can be reviewed", order.id, order.status),
+        });
+    }
+    let position = match order
+        .items
+        .iter()
+        .position(|item| item.product_id == payload.product_id && item.variant_id == payload.variant_id)
+    {
+        Some(position) => position as u64,
+        None => return Err(Error::invalid_field("product_id", format!("Order with id={} has no such item.", order.id))),
+    };
+    if let Some(review_id) = ORDER_ITEM_REVIEWS.with(|index| index.borrow().get(&(order.id, position))) {
+        return Err(Error::Conflict {
+            msg: format!("Item of order with id={} already has review with id={}", order.id, review_id),
+        });
+    }
+    let mut product = match _get_product(&payload.product_id) {
+        Some(p) => p,
+        None => return Err(Error::not_found(EntityKind::Product, payload.product_id)),
+    };
+
+    let id = REVIEW_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let review = Review {
+        id,
+        product_id: product.id,
+        variant_id: payload.variant_id,
+        order_id: order.id,
+        buyer_id: buyer.id,
+        seller_id: order.seller_id,
+        rating: payload.rating,
+        text: payload.text,
+        reply: None,
+        created_at: time(),
+    };
+    REVIEWS.with(|reviews| reviews.borrow_mut().insert(review.id, review.clone()));
+    PRODUCT_REVIEWS.with(|index| index.borrow_mut().insert((product.id, review.id), ()));
+    ORDER_ITEM_REVIEWS.with(|index| index.borrow_mut().insert((order.id, position), review.id));
+
+    product.rating.add(review.rating);
+    do_insert_product(&product);
+    if reputation_counts("rating_reputation", product.id) {
+        update_reputation(product.seller_id, |counters| {
+            counters.review_count += 1;
+            counters.rating_total += review.rating as u64;
+        });
+    }
+    Ok(review)
+}
+
+// Posts the seller's public answer to a review of one of their products. Each review gets one.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn reply_to_review(review_id: u64, text: String) -> Result<Review, Error> {
+    let mut review = match _get_review(&review_id) {
+        Some(r) => r,
+        None => return Err(Error::not_found(EntityKind::Review, review_id)),
+    };
+    authorize_owner_or(review.seller_id, &[])?;
+
+    if text.trim().is_empty() || text.len() > MAX_REVIEW_TEXT_BYTES {
+        return Err(Error::invalid_field("text", format!("Must be between 1 and {} bytes.", MAX_REVIEW_TEXT_BYTES)));
+    }
+    if review.reply.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("Review with id={} already has a reply", review.id),
+        });
+    }
+
+    review.reply = Some(ReviewReply {
+        text,
+        created_at: time(),
+    });
+    REVIEWS.with(|reviews| reviews.borrow_mut().insert(review.id, review.clone()));
+    Ok(review)
+}
+
+#[ic_cdk::query]
+fn view_review(review_id: u64) -> Result<Review, Error> {
+    match _get_review(&review_id) {
+        Some(review) => Ok(review),
+        None => Err(Error::not_found(EntityKind::Review, review_id)),
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReviewPage {
+    reviews: Vec<Review>,
+    next_cursor: Option<u64>, // Pass as `start_after` for the next page; None on the last page
+}
+
+// Reviews of a product, oldest first
+#[ic_cdk::query]
+fn list_product_reviews(product_id: u64, start_after: Option<u64>, limit: u32) -> Result<ReviewPage, Error> {
+    if _get_product(&product_id).is_none() {
+        return Err(Error::not_found(EntityKind::Product, product_id));
+    }
+
+    let start = start_after.map_or(0, |id| id.saturating_add(1));
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let review_ids: Vec<u64> = PRODUCT_REVIEWS.with(|index| {
+        index.borrow().range((product_id, start)..=(product_id, u64::MAX)).map(|((_, review_id), _)| review_id).collect()
+    });
+    let mut remaining = review_ids.into_iter().filter_map(|review_id| _get_review(&review_id));
+    let reviews: Vec<Review> = remaining.by_ref().take(limit).collect();
+    let next_cursor = match remaining.next() {
+        Some(_) => reviews.last().map(|review| review.id),
+        None => None,
+    };
+    Ok(ReviewPage {
+        reviews,
+        next_cursor,
+    })
+}
+
+// Opens a dispute over a paid order on behalf of its buyer or seller. The order moves to
+// InDispute, which keeps its escrow held until the dispute is resolved.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn open_dispute(payload: DisputePayload) -> Result<Dispute, Error> {
+    validate_dispute_message(&payload.description, &payload.evidence, "description")?;
+
+    let mut order = match _get_order(&payload.order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, payload.order_id)),
+    };
+    let opener = match authorize_owner_or(order.buyer_id, &[]) {
+        Ok(user) => user,
+        Err(_) => authorize_owner_or(order.seller_id, &[])?,
+    };
+
+    if let Some(dispute_id) = ORDER_DISPUTES.with(|index| index.borrow().get(&order.id)) {
+        return Err(Error::Conflict {
+            msg: format!("Order with id={} already has dispute with id={}", order.id, dispute_id),
+        });
+    }
+    if _get_escrow_id_by_order(&order.id).is_none() {
+        return Err(Error::invalid_transition(EntityKind::Order, order.id, order.status, OrderStatus::InDispute));
+    }
+    transition_order(&mut order, OrderStatus::InDispute, Some(opener.id))?;
+
+    let id = DISPUTE_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let now = time();
+    let dispute = Dispute {
+        id,
+        order_id: order.id,
+        opened_by: opener.id,
+        reason: payload.reason,
+        status: DisputeStatus::AwaitingResponse,
+        messages: vec![DisputeMessage {
+            author_id: opener.id,
+            body: payload.description,
+            evidence: payload.evidence,
+            created_at: now,
+        }],
+        assigned_to: None,
+        respond_by: now + DISPUTE_RESPONSE_WINDOW_SECS * 1_000_000_000,
+        resolve_by: now + DISPUTE_RESOLUTION_WINDOW_SECS * 1_000_000_000,
+        resolution: None,
+        resolved_by: None,
+        created_at: now,
+        updated_at: None,
+    };
+    do_insert_order(&order);
+    do_insert_dispute(&dispute);
+    ORDER_DISPUTES.with(|index| index.borrow_mut().insert(order.id, dispute.id));
+    Ok(dispute)
+}
+
+// Adds a statement to the thread of an open dispute. The first message from the party that
+// did not open the dispute is their response and puts the dispute under review.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn add_dispute_message(dispute_id: u64, payload: DisputeMessagePayload) -> Result<Dispute, Error> {
+    validate_dispute_message(&payload.body, &payload.evidence, "body")?;
+
+    let mut dispute = match _get_dispute(&dispute_id) {
+        Some(d) => d,
+        None => return Err(Error::not_found(EntityKind::Dispute, dispute_id)),
+    };
+    let author = authorize_dispute_participant(&dispute)?;
+    if dispute.status == DisputeStatus::Resolved {
+        return Err(Error::Conflict {
+            msg: format!("Dispute with id={} is already resolved", dispute.id),
+        });
+    }
+    if dispute.messages.len() >= MAX_DISPUTE_MESSAGES {
+        return Err(Error::Conflict {
+            msg: format!("Dispute with id={} has reached {} messages", dispute.id, MAX_DISPUTE_MESSAGES),
+        });
+    }
+
+    let now = time();
+    let order = match _get_order(&dispute.order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, dispute.order_id)),
+    };
+    let is_party = author.id == order.buyer_id || author.id == order.seller_id;
+    if is_party && author.id != dispute.opened_by {
+        dispute.status = DisputeStatus::UnderReview;
+    }
+    dispute.messages.push(DisputeMessage {
+        author_id: author.id,
+        body: payload.body,
+        evidence: payload.evidence,
+        created_at: now,
+    });
+    dispute.updated_at = Some(now);
+    do_insert_dispute(&dispute);
+    Ok(dispute)
+}
+
+// Hands a dispute to an admin or arbiter. Admins assign anyone holding either role; arbiters
+// can take unassigned disputes themselves.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn assign_dispute(dispute_id: u64, assignee_id: u64) -> Result<Dispute, Error> {
+    let actor = authorize(&[Role::Admin, Role::Arbiter])?;
+
+    let mut dispute = match _get_dispute(&dispute_id) {
+        Some(d) => d,
+        None => return Err(Error::not_found(EntityKind::Dispute, dispute_id)),
+    };
+    if actor.role == Role::Arbiter && (assignee_id != actor.id || dispute.assigned_to.is_some()) {
+        return Err(Error::Unauthorized {
+            msg: format!("User with id={} can only take unassigned disputes themself", actor.id),
+        });
+    }
+    if dispute.status == DisputeStatus::Resolved {
+        return Err(Error::Conflict {
+            msg: format!("Dispute with id={} is already resolved", dispute.id),
+        });
+    }
+    match _get_user(&assignee_id) {
+        Some(assignee) if matches!(assignee.role, Role::Admin | Role::Arbiter) => {}
+        Some(_) => return Err(Error::invalid_field("assignee_id", format!("User with id={} is not an admin or arbiter.", assignee_id))),
+        None => return Err(Error::not_found(EntityKind::User, assignee_id)),
+    }
+
+    dispute.assigned_to = Some(assignee_id);
+    dispute.updated_at = Some(time());
+    do_insert_dispute(&dispute);
+    Ok(dispute)
+}
+
+// Decides a dispute and settles its order's escrow accordingly. A dispute can be decided once
+// the other party has responded or its response deadline has passed.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn resolve_dispute(dispute_id: u64, resolution: DisputeResolution) -> Result<Dispute, Error> {
+    let arbiter = authorize(&[Role::Admin, Role::Arbiter])?;
+
+    let dispute = match _get_dispute(&dispute_id) {
+        Some(d) => d,
+        None => return Err(Error::not_found(EntityKind::Dispute, dispute_id)),
+    };
+    if arbiter.role == Role::Arbiter && dispute.assigned_to.is_some_and(|assignee| assignee != arbiter.id) {
+        return Err(Error::Unauthorized {
+            msg: format!("Dispute with id={} is assigned to another arbiter", dispute.id),
+        });
+    }
+    match dispute.status {
+        DisputeStatus::Resolved => return Err(Error::Conflict {
+            msg: format!("Dispute with id={} is already resolved", dispute.id),
+        }),
+        DisputeStatus::AwaitingResponse if time() < dispute.respond_by => return Err(Error::Conflict {
+            msg: format!("Dispute with id={} is awaiting a response until {}", dispute.id, dispute.respond_by),
+        }),
+        _ => {}
+    }
+
+    let (next, payout) = match resolution {
+        DisputeResolution::ReleaseToSeller => (OrderStatus::Completed, Payout::ToSeller),
+        DisputeResolution::RefundBuyer => (OrderStatus::Refunded, Payout::ToBuyer),
+        DisputeResolution::Split { refund_amount } => {
+            let escrow_amount = _get_escrow_id_by_order(&dispute.order_id)
+                .and_then(|escrow_id| _get_escrow(&escrow_id))
+                .map_or(0, |escrow| escrow.amount);
+            if refund_amount == 0 || refund_amount >= escrow_amount {
+                return Err(Error::invalid_field(
+                    "refund_amount",
+                    format!("Must be between 1 and {}; refund or release in full otherwise.", escrow_amount.saturating_sub(1)),
+                ));
+            }
+            let reason = format!("Resolution of dispute with id={}", dispute.id);
+            (OrderStatus::Completed, Payout::Split { buyer_amount: refund_amount, reason })
+        }
+    };
+    settle_order(dispute.order_id, next, payout, Some(arbiter.id)).await?;
+
+    // Messages may have been added while the payout was in flight
+    let mut dispute = _get_dispute(&dispute_id).unwrap_or(dispute);
+    let now = time();
+    dispute.status = DisputeStatus::Resolved;
+    dispute.resolution = Some(resolution);
+    dispute.resolved_by = Some(arbiter.id);
+    dispute.assigned_to = dispute.assigned_to.or(Some(arbiter.id));
+    dispute.updated_at = Some(now);
+    do_insert_dispute(&dispute);
+    if reputation_counts("dispute_reputation", dispute.id) {
+        count_dispute_reputation(&dispute);
+    }
+    Ok(dispute)
+}
+
+#[ic_cdk::query]
+fn view_dispute(dispute_id: u64) -> Result<Dispute, Error> {
+    let dispute = match _get_dispute(&dispute_id) {
+        Some(d) => d,
+        None => return Err(Error::not_found(EntityKind::Dispute, dispute_id)),
+    };
+    authorize_dispute_participant(&dispute)?;
+    Ok(dispute)
+}
+
+#[ic_cdk::query]
+fn view_order_dispute(order_id: u64) -> Result<Dispute, Error> {
+    match ORDER_DISPUTES.with(|index| index.borrow().get(&order_id)) {
+        Some(dispute_id) => view_dispute(dispute_id),
+        None => Err(Error::NotFound {
+            entity: EntityKind::OrderDispute,
+            id: order_id,
+            msg: format!("Dispute for order with id={} not found", order_id),
+        }),
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DisputePage {
+    disputes: Vec<Dispute>,
+    next_cursor: Option<u64>, // Pass as `start_after` for the next page; None on the last page
+}
+
+// Disputes for admins and arbiters to work through, oldest first, optionally only those in
+// `status`, assigned to `assigned_to`, or unresolved past their `resolve_by` (`overdue`)
+#[ic_cdk::query]
+fn list_disputes(
+    status: Option<DisputeStatus>,
+    assigned_to: Option<u64>,
+    overdue: Option<bool>,
+    start_after: Option<u64>,
+    limit: u32,
+) -> Result<DisputePage, Error> {
+    authorize(&[Role::Admin, Role::Arbiter])?;
+
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let now = time();
+    let is_overdue = |dispute: &Dispute| dispute.status != DisputeStatus::Resolved && now > dispute.resolve_by;
+    DISPUTES.with(|disputes| {
+        let disputes = disputes.borrow();
+        let start = start_after.map_or(ops::Bound::Unbounded, ops::Bound::Excluded);
+        let mut remaining = disputes
+            .range((start, ops::Bound::Unbounded))
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| status.is_none_or(|status| dispute.status == status))
+            .filter(|dispute| assigned_to.is_none_or(|assignee| dispute.assigned_to == Some(assignee)))
+            .filter(|dispute| overdue.is_none_or(|overdue| is_overdue(dispute) == overdue));
+        let page: Vec<Dispute> = remaining.by_ref().take(limit).collect();
+        let next_cursor = match remaining.next() {
+            Some(_) => page.last().map(|dispute| dispute.id),
+            None => None,
+        };
+        Ok(DisputePage {
+            disputes: page,
+            next_cursor,
+        })
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn release_escrow(escrow_id: u64) -> Result<Escrow, Error> {
+    let escrow_opt = ESCROW_STORAGE.with(|storage| storage.borrow().get(&escrow_id));
+    let escrow = match escrow_opt {
+        Some(e) => e,
+        None => return Err(Error::not_found(EntityKind::Escrow, escrow_id)),
+    };
+
+    // The buyer releases funds to the seller; admins and arbiters can too
+    let order = match _get_order(&escrow.order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, escrow.order_id)),
+    };
+    let actor = authorize_owner_or(order.buyer_id, &[Role::Admin, Role::Arbiter])?;
+    ensure_not_disputed(&order)?;
+
+    // Releasing the funds completes the order
+    let (_, escrow) = settle_order(order.id, OrderStatus::Completed, Payout::ToSeller, Some(actor.id)).await?;
+    Ok(escrow)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn refund_escrow(escrow_id: u64) -> Result<Escrow, Error> {
+    let escrow_opt = ESCROW_STORAGE.with(|storage| storage.borrow().get(&escrow_id));
+    let escrow = match escrow_opt {
+        Some(e) => e,
+        None => return Err(Error::not_found(EntityKind::Escrow, escrow_id)),
+    };
+
+    // The seller refunds the buyer; admins and arbiters can too
+    let order = match _get_order(&escrow.order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, escrow.order_id)),
+    };
+    let actor = authorize_owner_or(order.seller_id, &[Role::Admin, Role::Arbiter])?;
+    ensure_not_disputed(&order)?;
+
+    // Refunding the funds refunds the order
+    let (_, escrow) = settle_order(order.id, OrderStatus::Refunded, Payout::ToBuyer, Some(actor.id)).await?;
+    Ok(escrow)
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SplitEscrowPayload {
+    buyer_amount: u64,  // Refunded to the buyer
+    seller_amount: u64, // Paid to the seller
+    reason: String,     // E.g. which item was missing
+}
+
+// Settles a held escrow partly to the buyer and partly to the seller, e.g. a partial refund
+// for a missing item. The shares must add up to the escrowed amount; the order completes.
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn split_escrow(escrow_id: u64, payload: SplitEscrowPayload) -> Result<Escrow, Error> {
+    let escrow = match _get_escrow(&escrow_id) {
+        Some(e) => e,
+        None => return Err(Error::not_found(EntityKind::Escrow, escrow_id)),
+    };
+
+    // The seller grants partial refunds; admins and arbiters can split too
+    let order = match _get_order(&escrow.order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, escrow.order_id)),
+    };
+    let actor = authorize_owner_or(order.seller_id, &[Role::Admin, Role::Arbiter])?;
+    ensure_not_disputed(&order)?;
+    validate_split_payload(&payload, escrow.amount)?;
+
+    let payout = Payout::Split {
+        buyer_amount: payload.buyer_amount,
+        reason: payload.reason,
+    };
+    let (_, escrow) = settle_order(order.id, OrderStatus::Completed, payout, Some(actor.id)).await?;
+    Ok(escrow)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn set_tax_rate(tax_rate_bps: u32) -> Result<u32, Error> {
+    authorize(&[Role::Admin])?;
+
+    if tax_rate_bps > 10_000 {
+        return Err(Error::invalid_field("tax_rate_bps", "Cannot exceed 10000 basis points."));
+    }
+
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        let mut updated = config.get().clone();
+        updated.tax_rate_bps = tax_rate_bps;
+        config.set(updated)
+    })
+    .map_err(|_| Error::Internal {
+        msg: "Cannot store the marketplace config.".to_string(),
+    })?;
+    Ok(tax_rate_bps)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn set_reservation_window(reservation_window_secs: u64) -> Result<u64, Error> {
+    authorize(&[Role::Admin])?;
+
+    if reservation_window_secs == 0 {
+        return Err(Error::invalid_field("reservation_window_secs", "Must be greater than zero."));
+    }
+
+    // Applies to orders placed from now on
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        let mut updated = config.get().clone();
+        updated.reservation_window_secs = reservation_window_secs;
+        config.set(updated)
+    })
+    .map_err(|_| Error::Internal {
+        msg: "Cannot store the marketplace config.".to_string(),
+    })?;
+    Ok(reservation_window_secs)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn set_auto_release_window(auto_release_window_secs: u64) -> Result<u64, Error> {
+    authorize(&[Role::Admin])?;
+
+    if auto_release_window_secs == 0 {
+        return Err(Error::invalid_field("auto_release_window_secs", "Must be greater than zero."));
+    }
+
+    // Applies to orders delivered from now on
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        let mut updated = config.get().clone();
+        updated.auto_release_window_secs = auto_release_window_secs;
+        config.set(updated)
+    })
+    .map_err(|_| Error::Internal {
+        msg: "Cannot store the marketplace config.".to_string(),
+    })?;
+    Ok(auto_release_window_secs)
+}
+
+// Schema version of the stored records, and how far the migration to the current one got
+#[ic_cdk::query]
+fn view_schema_status() -> SchemaStatus {
+    schema_status()
+}
+
+// How far the certification tree and the indexes being built since the last upgrade got
+#[ic_cdk::query]
+fn view_backfill_status() -> BackfillStatus {
+    backfill_status()
+}
+
+// Helpers for canisters initialised with `LedgerConfig::Mock`, standing in for the calls a
+// buyer or a test harness would make against a real ledger
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn mock_ledger_mint(to: Account, amount: u64) -> Result<u64, Error> {
+    ensure_mock_ledger()?;
+    if !system::is_controller(&caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can mint on the mock ledger.".to_string(),
+        });
+    }
+    MockLedger::mint(to, amount).map_err(Error::invalid_input)
+}
+
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn mock_ledger_approve(amount: u64) -> Result<u64, Error> {
+    ensure_mock_ledger()?;
+    let from = Account { owner: caller(), subaccount: None };
+    let spender = Account { owner: system::id(), subaccount: None };
+    Ok(MockLedger::approve(from, spender, amount))
+}
+
+#[ic_cdk::query]
+fn mock_ledger_balance_of(account: Account) -> Result<u64, Error> {
+    ensure_mock_ledger()?;
+    Ok(MockLedger::balance_of(&account))
+}
+
+fn ensure_mock_ledger() -> Result<(), Error> {
+    match CONFIG.with(|config| config.borrow().get().ledger) {
+        Some(LedgerConfig::Mock) => Ok(()),
+        _ => Err(Error::invalid_input("This canister is not using the mock ledger.")),
+    }
+}
+
+fn generate_id(counter: &RefCell<IdCell>) -> Result<u64, Error> {
+    // Borrow the `IdCell` from the `RefCell` for mutable access
+    let mut counter_borrow = counter.borrow_mut();
+
+    // Get the current value and increment it
+    let current_value = *counter_borrow.get();
+    let _ = counter_borrow.set(current_value + 1);
+
+    // Return the new value
+    Ok(current_value + 1)
+}
+
+
+// Moves an order to `next`, rejecting illegal moves and recording who made the change and when.
+// Every change to `Order.status` must go through here.
+fn transition_order(order: &mut Order, next: OrderStatus, changed_by: Option<u64>) -> Result<(), Error> {
+    if payment_in_progress(order) {
+        return Err(Error::Conflict {
+            msg: format!("Order with id={} has a payment in progress", order.id),
+        });
+    }
+    if !order.status.can_transition_to(next) {
+        return Err(Error::invalid_transition(EntityKind::Order, order.id, order.status, next));
+    }
+
+    let now = time();
+    order.status_history.push(OrderStatusChange {
+        from: order.status,
+        to: next,
+        changed_by,
+        changed_at: now,
+    });
+    order.status = next;
+    order.updated_at = Some(now);
+
+    // Confirming, disputing or refunding a delivered order stops its escrow auto-release
+    if order.auto_release_at.take().is_some() {
+        AUTO_RELEASES.with(|releases| releases.borrow_mut().remove(&order.id));
+    }
+    Ok(())
+}
+
+// Pays out the held escrow of an order through the ledger and moves the order to `next`.
+// The order is locked while the transfer is in flight; both records are written once it lands.
+async fn settle_order(order_id: u64, next: OrderStatus, payout: Payout, changed_by: Option<u64>) -> Result<(Order, Escrow), Error> {
+    let mut order = match _get_order(&order_id) {
+        Some(o) => o,
+        None => return Err(Error::not_found(EntityKind::Order, order_id)),
+    };
+    // Orders that cannot move there fail the same way whether or not they were paid
+    if !order.status.can_transition_to(next) {
+        return Err(Error::invalid_transition(EntityKind::Order, order.id, order.status, next));
+    }
+    let mut escrow = match _get_escrow_id_by_order(&order.id).and_then(|escrow_id| _get_escrow(&escrow_id)) {
+        Some(e) => e,
+        None => return Err(Error::NotFound {
+            entity: EntityKind::OrderEscrow,
+            id: order.id,
+            msg: format!("Escrow for order with id={} not found", order.id),
+        }),
+    };
+
+    let (buyer_amount, outcome, reason) = match payout {
+        Payout::ToSeller => (0, EscrowStatus::Released, None),
+        Payout::ToBuyer => (escrow.amount, EscrowStatus::Refunded, None),
+        Payout::Split { buyer_amount, reason } => (buyer_amount, EscrowStatus::Split, Some(reason)),
+    };
+    if escrow.status != EscrowStatus::Held {
+        return Err(Error::invalid_transition(EntityKind::Escrow, escrow.id, escrow.status, outcome));
+    }
+    let seller_amount = match escrow.amount.checked_sub(buyer_amount) {
+        Some(amount) => amount,
+        None => return Err(Error::invalid_input(format!("Refund {} exceeds the escrowed amount {}", buyer_amount, escrow.amount))),
+    };
+
+    // An earlier attempt may have paid out one share already; it must be finished the same way
+    let mut split = match escrow.split.clone() {
+        Some(split) if split.buyer_amount != buyer_amount || split.seller_amount != seller_amount => {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "Escrow with id={} is being paid out as {} to the buyer and {} to the seller",
+                    escrow.id, split.buyer_amount, split.seller_amount
+                ),
+            })
+        }
+        Some(split) => split,
+        None => EscrowSplit {
+            buyer_amount,
+            seller_amount,
+            buyer_block: None,
+            seller_block: None,
+            reason,
+            decided_by: changed_by,
+            buyer_transfer: None,
+            seller_transfer: None,
+        },
+    };
+
+    // The ledger fee for each transfer comes out of the share it pays
+    let lock = OrderLock::acquire(order.id)?;
+    let ledger = configured_ledger()?;
+    let fee = ledger.fee().await.map_err(|msg| Error::PaymentFailed { msg })?;
+    for amount in [buyer_amount, seller_amount] {
+        if amount > 0 && amount <= fee {
+            return Err(Error::PaymentFailed {
+                msg: format!("Payout of {} does not cover the ledger fee {}", amount, fee),
+            });
+        }
+    }
+
+    if buyer_amount > 0 && split.buyer_block.is_none() {
+        let (transfer, block) = pay_share(&ledger, &order, &mut escrow, &mut split, true, fee).await?;
+        split.buyer_block = Some(block);
+        split.buyer_transfer = None;
+        escrow.split = Some(split.clone());
+        do_insert_escrow(&escrow);
+        record_transaction(order.buyer_id, &order, escrow.id, TransactionKind::Refund, buyer_amount, Some(transfer.fee), block)?;
+    }
+    if seller_amount > 0 && split.seller_block.is_none() {
+        let (transfer, block) = pay_share(&ledger, &order, &mut escrow, &mut split, false, fee).await?;
+        split.seller_block = Some(block);
+        split.seller_transfer = None;
+        escrow.split = Some(split.clone());
+        do_insert_escrow(&escrow);
+        record_transaction(order.seller_id, &order, escrow.id, TransactionKind::Payout, seller_amount, Some(transfer.fee), block)?;
+    }
+    drop(lock);
+
+    // Until the escrow is marked settled, a failure here can be retried: the payouts made so
+    // far are recorded on the split and are not repeated
+    transition_order(&mut order, next, changed_by)?;
+    if outcome == EscrowStatus::Refunded {
+        restock_order(&mut order)?;
+    }
+
+    escrow.status = outcome;
+    escrow.settlement_block = match outcome {
+        EscrowStatus::Released => split.seller_block,
+        EscrowStatus::Refunded => split.buyer_block,
+        _ => None,
+    };
+    escrow.split = Some(split);
+    escrow.updated_at = Some(time());
+    do_insert_escrow(&escrow);
+    do_insert_order(&order);
+    Ok((order, escrow))
+}
+
+// Adds a ledger transfer to the transaction history of `user_id`
+fn record_transaction(
+    user_id: u64,
+    order: &Order,
+    escrow_id: u64,
+    kind: TransactionKind,
+    amount: u64,
+    fee: Option<u64>,
+    ledger_block: u64,
+) -> Result<Transaction, Error> {
+    let id = TRANSACTION_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let transaction = Transaction {
+        id,
+        user_id,
+        order_id: order.id,
+        escrow_id,
+        kind,
+        amount,
+        fee,
+        ledger_block,
+        created_at: time(),
+    };
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().insert((user_id, id), transaction.clone()));
+    Ok(transaction)
+}
+
+// The ledger account payouts to the user `recipient_id` go to
+fn payout_account(recipient_id: u64) -> Result<Account, Error> {
+    let recipient = match _get_user(&recipient_id) {
+        Some(user) => user,
+        None => return Err(Error::not_found(EntityKind::User, recipient_id)),
+    };
+    if recipient.principal == Principal::anonymous() {
+        return Err(Error::PaymentFailed {
+            msg: format!("User with id={} has no principal to pay yet", recipient_id),
+        });
+    }
+    Ok(Account { owner: recipient.principal, subaccount: None })
+}
+
+// Sends one share of the escrow of `order` and returns the transfer that landed with its block.
+// The transfer is stored on the split before it is sent, so that a retry after a lost answer
+// repeats it exactly. One the ledger turned down is dropped, except that a stored transfer
+// refused as too old or for a changed fee may have landed before its answer was lost: the
+// escrow balance decides whether it is sent afresh.
+async fn pay_share(ledger: &ConfiguredLedger, order: &Order, escrow: &mut Escrow, split: &mut EscrowSplit, to_buyer: bool, fee: u64) -> Result<(LedgerTransfer, u64), Error> {
+    let (recipient_id, share) = if to_buyer { (order.buyer_id, split.buyer_amount) } else { (order.seller_id, split.seller_amount) };
+    let recipient = payout_account(recipient_id)?;
+    loop {
+        let stored = split.transfer_mut(to_buyer).is_some();
+        let transfer = split.transfer_mut(to_buyer).get_or_insert_with(|| payout_transfer(order, share, fee)).clone();
+        escrow.split = Some(split.clone());
+        do_insert_escrow(escrow);
+        let outcome = if transfer.fee == fee {
+            pay_out(ledger, order, recipient, &transfer).await
+        } else {
+            Err(TransferFailure::Rejected(format!("Stored transfer pays fee {}, the ledger now charges {}", transfer.fee, fee)))
+        };
+        match outcome {
+            Ok(block) => return Ok((transfer, block)),
+            // The transfer may have landed, so the retry sends it again
+            Err(TransferFailure::Unknown(msg)) => return Err(Error::PaymentFailed { msg }),
+            Err(failure) if !stored => {
+                *split.transfer_mut(to_buyer) = None;
+                escrow.split = Some(split.clone());
+                do_insert_escrow(escrow);
+                return Err(Error::PaymentFailed { msg: failure.to_string() });
+            }
+            Err(_) => {
+                let balance = ledger.balance(escrow_account(order.id)).await.map_err(|msg| Error::PaymentFailed { msg })?;
+                if balance < split.unpaid() {
+                    return Err(Error::PaymentFailed {
+                        msg: format!(
+                            "Payout of escrow with id={} landed on the ledger, but the ledger no longer reports its block",
+                            escrow.id
+                        ),
+                    });
+                }
+                // The stored transfer never landed; it is built again with the current fee and time
+                *split.transfer_mut(to_buyer) = None;
+            }
+        }
+    }
+}
+
+// Attempt at paying a `share` of the escrow of `order`, which pays `fee` out of it
+fn payout_transfer(order: &Order, share: u64, fee: u64) -> LedgerTransfer {
+    LedgerTransfer {
+        amount: share - fee,
+        fee,
+        memo: order.id.to_be_bytes().to_vec(),
+        created_at_time: time(),
+    }
+}
+
+// Sends `transfer` from the escrow subaccount of `order` to `recipient`
+async fn pay_out(ledger: &ConfiguredLedger, order: &Order, recipient: Account, transfer: &LedgerTransfer) -> Result<u64, TransferFailure> {
+    ledger
+        .transfer(Some(escrow_subaccount(order.id)), recipient, transfer.amount, transfer.fee, transfer.memo.clone(), transfer.created_at_time)
+        .await
+}
+
+// Held while a ledger transfer for an order is in flight so that no other call can move the
+// order or its escrow in the meantime. Released on drop, including when the call traps.
+struct OrderLock {
+    order_id: u64,
+}
+
+impl OrderLock {
+    fn acquire(order_id: u64) -> Result<Self, Error> {
+        LOCKED_ORDERS.with(|locked| {
+            if locked.borrow_mut().insert(order_id) {
+                Ok(OrderLock { order_id })
+            } else {
+                Err(Error::Conflict {
+                    msg: format!("Order with id={} has a payment in progress", order_id),
+                })
+            }
+        })
+    }
+}
+
+impl Drop for OrderLock {
+    fn drop(&mut self) {
+        LOCKED_ORDERS.with(|locked| locked.borrow_mut().remove(&self.order_id));
+    }
+}
+
+fn is_order_locked(order_id: &u64) -> bool {
+    LOCKED_ORDERS.with(|locked| locked.borrow().contains(order_id))
+}
+
+// Whether a ledger transfer for the order is in flight, or was sent without its answer arriving
+fn payment_in_progress(order: &Order) -> bool {
+    is_order_locked(&order.id) || order.funding_transfer.is_some()
+}
+
+fn configured_ledger() -> Result<ConfiguredLedger, Error> {
+    match CONFIG.with(|config| config.borrow().get().ledger) {
+        Some(ledger) => Ok(ledger.into()),
+        None => Err(Error::PaymentFailed {
+            msg: "No ledger has been configured for this canister.".to_string(),
+        }),
+    }
+}
+
+// Each order's escrow lives in its own subaccount of the canister, derived from the order id
+fn escrow_subaccount(order_id: u64) -> Subaccount {
+    let mut subaccount = [0u8; 32];
+    subaccount[24..].copy_from_slice(&order_id.to_be_bytes());
+    subaccount
+}
+
+fn escrow_account(order_id: u64) -> Account {
+    Account {
+        owner: system::id(),
+        subaccount: Some(escrow_subaccount(order_id)),
+    }
+}
+
+// Prices `quantity` units of `product`: the seller's discount comes off the subtotal, tax is
+// charged on the discounted subtotal, and `shipping` is added on top. Shipping is a flat fee
+// per order, so callers pass the product's fee for a single-item order.
+fn compute_price(product: &Product, variant: Option<&ProductVariant>, quantity: u32, shipping: u64) -> Result<PriceBreakdown, Error> {
+    let tax_rate_bps = CONFIG.with(|config| config.borrow().get().tax_rate_bps);
+
+    let unit_price = variant.and_then(|variant| variant.price).unwrap_or(product.price);
+    let subtotal = unit_price as u128 * quantity as u128;
+    let discount = subtotal * product.discount_bps as u128 / 10_000;
+    let tax = (subtotal - discount) * tax_rate_bps as u128 / 10_000;
+    let total = subtotal - discount + shipping as u128 + tax;
+
+    // Discount and tax never exceed the subtotal, so they fit whenever it does
+    let too_large = |_| Error::invalid_input("Order total is too large.");
+    let total = u64::try_from(total).map_err(too_large)?;
+    let subtotal = u64::try_from(subtotal).map_err(too_large)?;
+    Ok(PriceBreakdown {
+        unit_price,
+        quantity,
+        subtotal,
+        discount: discount as u64,
+        shipping,
+        tax: tax as u64,
+        total,
+    })
+}
+
+// Shipping charged on each of a cart's lines. Checkout places one order per seller and each
+// order pays one flat fee: the largest among its products, on the first line carrying it.
+// Lines whose product is gone (`None`) are charged nothing.
+fn shipping_charges<'a>(products: impl IntoIterator<Item = Option<&'a Product>>) -> Vec<u64> {
+    let products: Vec<_> = products.into_iter().collect();
+    let fee = |index: usize| products[index].map_or(0, |product| product.shipping_fee);
+
+    let mut charged_line: BTreeMap<u64, usize> = BTreeMap::new();
+    for (index, product) in products.iter().enumerate() {
+        if let Some(product) = product {
+            let line = charged_line.entry(product.seller_id).or_insert(index);
+            if product.shipping_fee > fee(*line) {
+                *line = index;
+            }
+        }
+    }
+
+    let mut charges = vec![0; products.len()];
+    for index in charged_line.into_values() {
+        charges[index] = fee(index);
+    }
+    charges
+}
+
+// Looks up what an order or cart line refers to. Products sold in variants must be bought as
+// one of their variants, other products without one.
+fn resolve_listing(product_id: u64, variant_id: Option<u64>) -> Result<(Product, Option<ProductVariant>), Error> {
+    let product = match _get_product(&product_id) {
+        Some(product) => product,
+        None => return Err(Error::not_found(EntityKind::Product, product_id)),
+    };
+    match variant_id {
+        Some(variant_id) => match _get_variant(product_id, variant_id) {
+            Some(variant) => Ok((product, Some(variant))),
+            None => Err(Error::NotFound {
+                entity: EntityKind::Variant,
+                id: variant_id,
+                msg: format!("Variant with id={} of product with id={} not found", variant_id, product_id),
+            }),
+        },
+        None if has_variants(product_id) => Err(Error::invalid_field("variant_id", format!("Product with id={} is sold in variants; a variant must be given.", product_id))),
+        None => Ok((product, None)),
+    }
+}
+
+// Units of the product, or of the variant if given, available for new orders
+fn available_stock(product: &Product, variant: Option<&ProductVariant>) -> u32 {
+    variant.map_or(product.stock_quantity, |variant| variant.stock_quantity)
+}
+
+// Guards the buyer against paying more than the total they were shown
+fn check_expected_total(total: u64, expected_total: Option<u64>) -> Result<(), Error> {
+    match expected_total {
+        Some(expected) if total > expected => Err(Error::Conflict {
+            msg: format!("Order total {} exceeds the expected total {}", total, expected),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn sum_totals(totals: impl IntoIterator<Item = u64>) -> Result<u64, Error> {
+    totals
+        .into_iter()
+        .try_fold(0u64, |sum, total| sum.checked_add(total))
+        .ok_or_else(|| Error::invalid_input("Order total is too large."))
+}
+
+// Counts an attempt by `buyer_id` to place orders, rejecting it once the buyer made
+// ORDER_RATE_LIMIT attempts within the last ORDER_RATE_WINDOW_SECS. Every unpaid order holds
+// stock, so this keeps a single buyer from tying up a seller's inventory.
+fn check_order_rate(buyer_id: u64) -> Result<(), Error> {
+    let now = time();
+    let window = ORDER_RATE_WINDOW_SECS * 1_000_000_000;
+    RECENT_ORDER_ATTEMPTS.with(|attempts| {
+        let mut attempts = attempts.borrow_mut();
+        let recent = attempts.entry(buyer_id).or_default();
+        recent.retain(|attempted_at| now.saturating_sub(*attempted_at) < window);
+        if recent.len() >= ORDER_RATE_LIMIT {
+            let retry_after = window.saturating_sub(now.saturating_sub(recent[0]));
+            return Err(Error::RateLimited {
+                retry_after_secs: retry_after.div_ceil(1_000_000_000),
+                msg: format!("At most {} orders can be placed per {} seconds", ORDER_RATE_LIMIT, ORDER_RATE_WINDOW_SECS),
+            });
+        }
+        recent.push(now);
+        Ok(())
+    })
+}
+
+// Creates a pending order over already priced and stock-checked items and reserves the
+// ordered quantities until the order is paid or the reservation expires
+fn place_order(buyer_id: u64, seller_id: u64, items: Vec<OrderItem>, total_price: u64) -> Result<Order, Error> {
+    // Generate a new order ID using thread-local storage access
+    let id = ORDER_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+
+    // Create the order
+    let mut order = Order {
+        id,
+        buyer_id,
+        seller_id,
+        items,
+        total_price,
+        reserved_until: None,
+        auto_release_at: None,
+        status: OrderStatus::Pending,
+        status_history: Vec::new(),
+        created_at: time(),
+        updated_at: None,
+        funding_transfer: None,
+    };
+    let expires_at = reservation_expiry();
+    reserve_stock(&mut order, expires_at)?;
+    do_insert_order(&order);
+    schedule_reservation_expiry(order.id, expires_at);
+
+    Ok(order)
+}
+
+// Stock reservations
+//
+// Placing an order moves its quantities from `stock_quantity` to `reserved_quantity`. Paying
+// turns the reservation into a sale, while cancelling or letting it expire puts the units
+// back on sale. Cancelled or refunded paid orders are restocked as well.
+
+fn reservation_expiry() -> u64 {
+    let window_secs = CONFIG.with(|config| config.borrow().get().reservation_window_secs);
+    time().saturating_add(window_secs.saturating_mul(1_000_000_000))
+}
+
+fn auto_release_time() -> u64 {
+    let window_secs = CONFIG.with(|config| config.borrow().get().auto_release_window_secs);
+    time().saturating_add(window_secs.saturating_mul(1_000_000_000))
+}
+
+// Holds the order's items until `expires_at`. Fails without changing anything if any item is
+// short of stock.
+fn reserve_stock(order: &mut Order, expires_at: u64) -> Result<(), Error> {
+    let order_id = order.id;
+    adjust_stock(&order.items, -1, |product, mut variant, quantity| {
+        record_stock_movement(product, variant.as_deref_mut(), -(quantity as i64), StockMovementReason::OrderReserved, None, Some(order_id))?;
+        product.reserved_quantity = product.reserved_quantity.saturating_add(quantity);
+        if let Some(variant) = variant {
+            variant.reserved_quantity = variant.reserved_quantity.saturating_add(quantity);
+        }
+        Ok(())
+    })?;
+    order.reserved_until = Some(expires_at);
+    RESERVATIONS.with(|reservations| reservations.borrow_mut().insert(order.id, expires_at));
+    Ok(())
+}
+
+// Turns the reservation into a sale once the order is paid
+fn commit_reservation(order: &mut Order) -> Result<(), Error> {
+    if order.reserved_until.take().is_some() {
+        adjust_stock(&order.items, 0, |product, variant, quantity| {
+            product.reserved_quantity = product.reserved_quantity.saturating_sub(quantity);
+            if let Some(variant) = variant {
+                variant.reserved_quantity = variant.reserved_quantity.saturating_sub(quantity);
+            }
+            Ok(())
+        })?;
+        RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&order.id));
+    }
+    Ok(())
+}
+
+// Puts the order's items back on sale, whether they were still reserved or already sold
+fn restock_order(order: &mut Order) -> Result<(), Error> {
+    let order_id = order.id;
+    let reserved = order.reserved_until.is_some();
+    adjust_stock(&order.items, 1, |product, mut variant, quantity| {
+        let reason = if reserved {
+            product.reserved_quantity = product.reserved_quantity.saturating_sub(quantity);
+            if let Some(variant) = variant.as_deref_mut() {
+                variant.reserved_quantity = variant.reserved_quantity.saturating_sub(quantity);
+            }
+            StockMovementReason::OrderReleased
+        } else {
+            StockMovementReason::OrderReturned
+        };
+        record_stock_movement(product, variant, quantity as i64, reason, None, Some(order_id))?;
+        Ok(())
+    })?;
+    order.reserved_until = None;
+    RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&order.id));
+    Ok(())
+}
+
+// Applies `delta` to the available stock of the product, and of the variant if given, and
+// appends the change to the product's movement log. The caller persists both. Every change to
+// `stock_quantity` goes through here.
+fn record_stock_movement(
+    product: &mut Product,
+    variant: Option<&mut ProductVariant>,
+    delta: i64,
+    reason: StockMovementReason,
+    actor_id: Option<u64>,
+    order_id: Option<u64>,
+) -> Result<StockMovement, Error> {
+    let variant_id = variant.as_ref().map(|variant| variant.id);
+    let out_of_range = |stock: u32| {
+        if delta < 0 {
+            let requested = u32::try_from(delta.unsigned_abs()).unwrap_or(u32::MAX);
+            Error::insufficient_stock(product.id, variant_id, requested, stock)
+        } else {
+            Error::invalid_input(format!("Stock of product with id={} cannot grow by {} from {}", product.id, delta, stock))
+        }
+    };
+    let product_balance = u32::try_from(product.stock_quantity as i64 + delta).map_err(|_| out_of_range(product.stock_quantity))?;
+    let variant_balance = match &variant {
+        Some(variant) => Some(u32::try_from(variant.stock_quantity as i64 + delta).map_err(|_| out_of_range(variant.stock_quantity))?),
+        None => None,
+    };
+
+    let id = STOCK_MOVEMENT_ID_COUNTER.with(|counter| {
+        generate_id(counter)
+    })?;
+    let movement = StockMovement {
+        id,
+        product_id: product.id,
+        variant_id,
+        delta,
+        reason,
+        balance_after: variant_balance.unwrap_or(product_balance),
+        actor_id,
+        order_id,
+        created_at: time(),
+    };
+    STOCK_MOVEMENTS.with(|movements| movements.borrow_mut().insert((product.id, id), movement.clone()));
+    product.stock_quantity = product_balance;
+    if let (Some(variant), Some(balance)) = (variant, variant_balance) {
+        variant.stock_quantity = balance;
+    }
+    Ok(movement)
+}
+
+// Runs `adjust` on the product, and variant, of each item that still exists and saves them.
+// `direction` is the sign of the change to their available stock: every item's quantity is
+// checked to fit first, so that a short or overflowing item fails the whole call before any
+// item is touched.
+fn adjust_stock(
+    items: &[OrderItem],
+    direction: i64,
+    adjust: impl Fn(&mut Product, Option<&mut ProductVariant>, u32) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let listings: Vec<_> = items.iter().filter_map(item_listing).collect();
+    check_stock_change(&listings, direction)?;
+
+    // Looked up again, as earlier items may have changed the same product
+    for (mut product, mut variant, quantity) in items.iter().filter_map(item_listing) {
+        adjust(&mut product, variant.as_mut(), quantity)?;
+        product.updated_at = Some(time());
+        do_insert_product(&product);
+        if let Some(mut variant) = variant {
+            variant.updated_at = Some(time());
+            do_insert_variant(&variant);
+        }
+    }
+    Ok(())
+}
+
+// The product and variant an order item was bought as, unless they have been removed since,
+// in which case there is nothing left to put the units back on
+fn item_listing(item: &OrderItem) -> Option<(Product, Option<ProductVariant>, u32)> {
+    let product = _get_product(&item.product_id)?;
+    let variant = match item.variant_id {
+        Some(variant_id) => Some(_get_variant(item.product_id, variant_id)?),
+        None => None,
+    };
+    Some((product, variant, item.quantity))
+}
+
+// Checks that moving each listing's quantity in `direction` keeps the available stock of its
+// product and variant within range, adding up items that share a product or variant
+fn check_stock_change(listings: &[(Product, Option<ProductVariant>, u32)], direction: i64) -> Result<(), Error> {
+    if direction == 0 {
+        return Ok(());
+    }
+    let mut totals: BTreeMap<(u64, Option<u64>), (u32, i64)> = BTreeMap::new();
+    for (product, variant, quantity) in listings {
+        let product_total = totals.entry((product.id, None)).or_insert((product.stock_quantity, 0));
+        product_total.1 += *quantity as i64;
+        if let Some(variant) = variant {
+            let variant_total = totals.entry((product.id, Some(variant.id))).or_insert((variant.stock_quantity, 0));
+            variant_total.1 += *quantity as i64;
+        }
+    }
+    for ((product_id, variant_id), (stock, quantity)) in totals {
+        if u32::try_from(stock as i64 + direction * quantity).is_err() {
+            return Err(if direction < 0 {
+                Error::insufficient_stock(product_id, variant_id, u32::try_from(quantity).unwrap_or(u32::MAX), stock)
+            } else {
+                Error::invalid_input(format!("Stock of product with id={} cannot grow by {} from {}", product_id, quantity, stock))
+            });
+        }
+    }
+    Ok(())
+}
+
+fn schedule_reservation_expiry(order_id: u64, expires_at: u64) {
+    let delay = Duration::from_nanos(expires_at.saturating_sub(time()));
+    system::set_timer(delay, move || expire_reservation(order_id));
 }
 
-#[ic_cdk::update]
-fn delete_product(product_id: u64) -> Result<Product, Error> {
-    match PRODUCTS_STORAGE.with(|products| products.borrow_mut().remove(&product_id)) {
-        Some(product) => Ok(product),
-        None => Err(Error::NotFound {
-            msg: format!("Product with id={} not found", product_id),
-        }),
+// Cancels an order that was not paid within the reservation window
+fn expire_reservation(order_id: u64) {
+    let mut order = match _get_order(&order_id) {
+        Some(order) => order,
+        None => {
+            RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(&order_id));
+            return;
+        }
+    };
+
+    // Paid or cancelled in the meantime
+    if order.status != OrderStatus::Pending || order.reserved_until.is_none() {
+        return;
+    }
+
+    // A payment is in flight; check again once it has settled
+    if is_order_locked(&order.id) {
+        system::set_timer(Duration::from_secs(60), move || expire_reservation(order_id));
+        return;
+    }
+    // The funds may have left the buyer's account; the stock stays reserved until the buyer's
+    // retry of `pay_order` finds out
+    if order.funding_transfer.is_some() {
+        let window_secs = CONFIG.with(|config| config.borrow().get().reservation_window_secs);
+        system::set_timer(Duration::from_secs(window_secs), move || expire_reservation(order_id));
+        return;
+    }
+
+    // Left pending if the stock cannot take the units back, so nothing is lost
+    if transition_order(&mut order, OrderStatus::Cancelled, None).is_ok() && restock_order(&mut order).is_ok() {
+        do_insert_order(&order);
     }
 }
 
-// CRUD operations for Users
-#[ic_cdk::update]
-fn create_user(payload: UserPayload) -> Result<User, Error> {
-    // Validate inputs
-    validate_user_payload(&payload)?;
+fn schedule_auto_release(order_id: u64, release_at: u64) {
+    let delay = Duration::from_nanos(release_at.saturating_sub(time()));
+    system::set_timer(delay, move || ic_cdk::spawn(auto_release_escrow(order_id)));
+}
 
-    // Generate a new user ID
-    let id = USER_ID_COUNTER.with(|counter| {
-        generate_id(counter)
-    })?;
+// Releases the escrow of a delivered order the buyer neither confirmed nor disputed in time
+async fn auto_release_escrow(order_id: u64) {
+    let order = match _get_order(&order_id) {
+        Some(order) => order,
+        None => {
+            AUTO_RELEASES.with(|releases| releases.borrow_mut().remove(&order_id));
+            return;
+        }
+    };
 
-    // Create the user
-    let user = User {
-        id,
-        name: payload.name,
-        email: payload.email,
-        role: payload.role,
-        reputation: 100, // Start with a full reputation
-        created_at: time(),
-        updated_at: None,
+    // Completed, disputed or refunded in the meantime
+    if order.status != OrderStatus::Delivered || order.auto_release_at.is_none() {
+        return;
+    }
+
+    // A payment is in flight; check again once it has settled
+    if is_order_locked(&order.id) {
+        system::set_timer(Duration::from_secs(60), move || ic_cdk::spawn(auto_release_escrow(order_id)));
+        return;
+    }
+
+    // A failed payout, e.g. while the ledger is unavailable, is retried later
+    if settle_order(order.id, OrderStatus::Completed, Payout::ToSeller, None).await.is_err() {
+        system::set_timer(Duration::from_secs(AUTO_RELEASE_RETRY_SECS), move || ic_cdk::spawn(auto_release_escrow(order_id)));
+    }
+}
+
+// Computes a user's reputation from their counters and account age
+fn reputation_breakdown(user: &User) -> ReputationBreakdown {
+    let counters = REPUTATION_COUNTERS.with(|counters| counters.borrow().get(&user.id)).unwrap_or_default();
+    let ReputationCounters { total_orders, completed_orders, cancelled_orders, disputes_lost, review_count, rating_total } = counters;
+    let average_rating = match review_count {
+        0 => 0.0,
+        count => rating_total as f64 / count as f64,
     };
-    do_insert_user(&user);
-    Ok(user)
+
+    let cancellation_rate_bps = (cancelled_orders * 10_000).checked_div(total_orders).unwrap_or(0) as u32;
+    let account_age_days = time().saturating_sub(user.created_at) / (24 * 60 * 60 * 1_000_000_000);
+
+    let factors = vec![
+        ReputationFactor {
+            signal: ReputationSignal::Base,
+            points: REPUTATION_BASE,
+        },
+        ReputationFactor {
+            signal: ReputationSignal::CompletedOrders,
+            points: (completed_orders as i64 * POINTS_PER_COMPLETED_ORDER).min(MAX_COMPLETED_ORDER_POINTS),
+        },
+        ReputationFactor {
+            signal: ReputationSignal::AccountAge,
+            points: ((account_age_days / DAYS_PER_ACCOUNT_AGE_POINT) as i64).min(MAX_ACCOUNT_AGE_POINTS),
+        },
+        ReputationFactor {
+            signal: ReputationSignal::ReviewScores,
+            points: match review_count {
+                0 => 0,
+                _ => ((average_rating - 3.0) * POINTS_PER_REVIEW_STAR).round() as i64,
+            },
+        },
+        ReputationFactor {
+            signal: ReputationSignal::CancellationRate,
+            points: -(cancellation_rate_bps as i64 * MAX_CANCELLATION_PENALTY / 10_000),
+        },
+        ReputationFactor {
+            signal: ReputationSignal::DisputesLost,
+            points: -(disputes_lost as i64 * POINTS_PER_DISPUTE_LOST).min(MAX_DISPUTE_PENALTY),
+        },
+    ];
+    let score = factors.iter().map(|factor| factor.points).sum::<i64>().clamp(0, 100) as u8;
+
+    ReputationBreakdown {
+        user_id: user.id,
+        score,
+        completed_orders,
+        total_orders,
+        cancelled_orders,
+        cancellation_rate_bps,
+        disputes_lost,
+        review_count,
+        average_rating,
+        account_age_days,
+        factors,
+    }
 }
 
+// The user with their current reputation. Account age changes the score over time without
+// any event, so reads bring it up to date.
+fn with_reputation(mut user: User) -> User {
+    user.reputation = reputation_breakdown(&user).score;
+    user
+}
 
-#[ic_cdk::query]
-fn view_user(user_id: u64) -> Result<User, Error> {
-    match _get_user(&user_id) {
-        Some(user) => Ok(user),
-        None => Err(Error::NotFound {
-            msg: format!("User with id={} not found", user_id),
-        }),
+// Applies `update` to the reputation counters of `user_id` and stores the resulting score
+fn update_reputation(user_id: u64, update: impl FnOnce(&mut ReputationCounters)) {
+    let mut counters = REPUTATION_COUNTERS.with(|counters| counters.borrow().get(&user_id)).unwrap_or_default();
+    let before = counters.clone();
+    update(&mut counters);
+    if counters == before {
+        return;
+    }
+    REPUTATION_COUNTERS.with(|all| all.borrow_mut().insert(user_id, counters));
+    if let Some(user) = _get_user(&user_id) {
+        do_insert_user(&with_reputation(user));
     }
 }
 
-#[ic_cdk::update]
-fn update_user(user_id: u64, payload: UserPayload) -> Result<User, Error> {
-    // Validate inputs
-    validate_user_payload(&payload)?;
+// Moves what an order counts towards its parties' reputation from how it was to how it is.
+// `None` stands for an order that does not exist (yet, or any more).
+fn count_order_reputation(previous: Option<&Order>, current: Option<&Order>) {
+    // Orders, completed orders and own cancellations, per party
+    fn tally(order: &Order, sign: i64, totals: &mut BTreeMap<u64, [i64; 3]>) {
+        let cancelled_by = match order.status {
+            OrderStatus::Cancelled => order.status_history.last().and_then(|change| change.changed_by),
+            _ => None,
+        };
+        for user_id in [order.buyer_id, order.seller_id] {
+            let entry = totals.entry(user_id).or_default();
+            entry[0] += sign;
+            if order.status == OrderStatus::Completed {
+                entry[1] += sign;
+            }
+            if cancelled_by == Some(user_id) {
+                entry[2] += sign;
+            }
+        }
+    }
 
-    let mut user = match _get_user(&user_id) {
-        Some(user) => user,
-        None => return Err(Error::NotFound {
-            msg: format!("User with id={} not found", user_id),
-        }),
+    let mut totals = BTreeMap::new();
+    if let Some(order) = previous {
+        tally(order, -1, &mut totals);
+    }
+    if let Some(order) = current {
+        tally(order, 1, &mut totals);
+    }
+    for (user_id, [orders, completed, cancelled]) in totals {
+        update_reputation(user_id, |counters| {
+            counters.total_orders = counters.total_orders.saturating_add_signed(orders);
+            counters.completed_orders = counters.completed_orders.saturating_add_signed(completed);
+            counters.cancelled_orders = counters.cancelled_orders.saturating_add_signed(cancelled);
+        });
+    }
+}
+
+// Counts a resolved dispute against the party it was decided against
+fn count_dispute_reputation(dispute: &Dispute) {
+    let order = match _get_order(&dispute.order_id) {
+        Some(order) => order,
+        None => return,
     };
+    let loser = match dispute.resolution {
+        Some(DisputeResolution::ReleaseToSeller) => order.buyer_id,
+        Some(DisputeResolution::RefundBuyer) => order.seller_id,
+        _ => return,
+    };
+    update_reputation(loser, |counters| counters.disputes_lost += 1);
+}
 
-    // Update the user
-    user.name = payload.name;
-    user.email = payload.email;
-    user.role = payload.role;
-    user.updated_at = Some(time());
-    do_insert_user(&user);
-    Ok(user)
+// Stable stores whose records are rewritten when the schema version changes. Media chunks
+// hold raw bytes and are never migrated.
+const MIGRATED_STORES: [&str; 11] = [
+    "products",
+    "orders",
+    "users",
+    "escrows",
+    "categories",
+    "product_variants",
+    "media_assets",
+    "stock_movements",
+    "disputes",
+    "transactions",
+    "reviews",
+];
+
+const MIGRATION_BATCH_SIZE: usize = 100;
+// Instructions a single call may spend on the migration, well below the per-message limit
+const MIGRATION_INSTRUCTION_BUDGET: u64 = 2_000_000_000;
+
+// Keys of the migrated stores, as the cursor kept in `StoreMigration`
+trait MigrationKey: Storable + Ord + Clone {
+    fn to_cursor(&self) -> (u64, u64);
+    fn from_cursor(cursor: (u64, u64)) -> Self;
 }
 
-#[ic_cdk::update]
-fn delete_user(user_id: u64) -> Result<User, Error> {
-    match USERS_STORAGE.with(|users| users.borrow_mut().remove(&user_id)) {
-        Some(user) => Ok(user),
-        None => Err(Error::NotFound {
-            msg: format!("User with id={} not found", user_id),
-        }),
+impl MigrationKey for u64 {
+    fn to_cursor(&self) -> (u64, u64) {
+        (*self, 0)
+    }
+
+    fn from_cursor(cursor: (u64, u64)) -> Self {
+        cursor.0
     }
 }
 
-// CRUD operations for Orders
-#[ic_cdk::update]
-fn create_order(payload: OrderPayload) -> Result<Order, Error> {
-    // Validate order payload
-    validate_order_payload(&payload)?;
+impl MigrationKey for (u64, u64) {
+    fn to_cursor(&self) -> (u64, u64) {
+        *self
+    }
 
-    // Ensure the buyer and product exist
-    match _get_user(&payload.user_id) {
-        Some(user) => user,
-        None => return Err(Error::NotFound {
-            msg: format!("User with id={} not found", payload.user_id),
-        }),
+    fn from_cursor(cursor: (u64, u64)) -> Self {
+        cursor
+    }
+}
+
+fn schema_status() -> SchemaStatus {
+    SCHEMA_STATUS.with(|status| status.borrow().get().clone())
+}
+
+fn set_schema_status(status: SchemaStatus) {
+    SCHEMA_STATUS.with(|cell| cell.borrow_mut().set(status).expect("Cannot store the schema status"));
+}
+
+// Sets up the migration to the current schema version, or picks up one that an earlier
+// upgrade left unfinished. Returns whether there is anything left to migrate.
+fn start_migration() -> bool {
+    let status = schema_status();
+    if status.schema_version == schema::SCHEMA_VERSION {
+        return false;
+    }
+    if status.target_version == schema::SCHEMA_VERSION && !status.stores.is_empty() {
+        return true;
+    }
+
+    // The config is a single record and is rewritten at once
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        let current = config.get().clone();
+        config.set(current).expect("Cannot store the marketplace config")
+    });
+    set_schema_status(SchemaStatus {
+        schema_version: status.schema_version,
+        target_version: schema::SCHEMA_VERSION,
+        stores: MIGRATED_STORES
+            .iter()
+            .map(|store| StoreMigration {
+                store: store.to_string(),
+                migrated: 0,
+                total: store_len(store),
+                cursor: None,
+                done: false,
+            })
+            .collect(),
+        started_at: Some(time()),
+        finished_at: None,
+    });
+    true
+}
+
+// Rewrites records batch by batch until every store is done or this call's instruction
+// budget is spent, in which case a timer continues where it stopped
+fn run_migration_batch() {
+    let start = system::instruction_counter();
+    let mut status = schema_status();
+    let from_version = status.schema_version;
+    for store in status.stores.iter_mut() {
+        while !store.done {
+            if system::instruction_counter() - start > MIGRATION_INSTRUCTION_BUDGET {
+                set_schema_status(status);
+                system::set_timer(Duration::ZERO, run_migration_batch);
+                return;
+            }
+            let (cursor, count) = migrate_store(&store.store, store.cursor, from_version);
+            store.migrated += count as u64;
+            store.cursor = cursor.or(store.cursor);
+            store.done = count < MIGRATION_BATCH_SIZE;
+        }
+    }
+    status.schema_version = status.target_version;
+    status.finished_at = Some(time());
+    set_schema_status(status);
+}
+
+fn store_len(store: &str) -> u64 {
+    match store {
+        "products" => PRODUCTS_STORAGE.with(|store| store.borrow().len()),
+        "orders" => ORDERS_STORAGE.with(|store| store.borrow().len()),
+        "users" => USERS_STORAGE.with(|store| store.borrow().len()),
+        "escrows" => ESCROW_STORAGE.with(|store| store.borrow().len()),
+        "categories" => CATEGORIES.with(|store| store.borrow().len()),
+        "product_variants" => PRODUCT_VARIANTS.with(|store| store.borrow().len()),
+        "media_assets" => MEDIA_ASSETS.with(|store| store.borrow().len()),
+        "stock_movements" => STOCK_MOVEMENTS.with(|store| store.borrow().len()),
+        "disputes" => DISPUTES.with(|store| store.borrow().len()),
+        "transactions" => TRANSACTIONS.with(|store| store.borrow().len()),
+        "reviews" => REVIEWS.with(|store| store.borrow().len()),
+        _ => 0,
+    }
+}
+
+// Rewrites the next batch of `store` after `cursor`, returning the last key rewritten and the
+// number of records. Records from before the given schema version may need more than that.
+fn migrate_store(store: &str, cursor: Option<(u64, u64)>, from_version: u8) -> (Option<(u64, u64)>, usize) {
+    match store {
+        "products" => {
+            let (last, count) = rewrite_records(&PRODUCTS_STORAGE, cursor);
+            if from_version == 0 {
+                open_stock_balances(cursor.map(|cursor| cursor.0), last.map(|last| last.0));
+            }
+            (last, count)
+        }
+        "orders" => {
+            let (last, count) = rewrite_records(&ORDERS_STORAGE, cursor);
+            if from_version == 0 {
+                cancel_unpriced_orders(cursor.map(|cursor| cursor.0), last.map(|last| last.0));
+            }
+            (last, count)
+        }
+        "users" => rewrite_records(&USERS_STORAGE, cursor),
+        "escrows" => rewrite_records(&ESCROW_STORAGE, cursor),
+        "categories" => rewrite_records(&CATEGORIES, cursor),
+        "product_variants" => rewrite_records(&PRODUCT_VARIANTS, cursor),
+        "media_assets" => rewrite_records(&MEDIA_ASSETS, cursor),
+        "stock_movements" => rewrite_records(&STOCK_MOVEMENTS, cursor),
+        "disputes" => rewrite_records(&DISPUTES, cursor),
+        "transactions" => rewrite_records(&TRANSACTIONS, cursor),
+        "reviews" => rewrite_records(&REVIEWS, cursor),
+        _ => (None, 0),
+    }
+}
+
+// Logs the stock that products listed before the movement log existed started out with, as a
+// `Listed` movement dated to their listing, for the products after `after` up to `last`.
+// Movements logged for them since then are accounted for, so their log adds up afterwards.
+fn open_stock_balances(after: Option<u64>, last: Option<u64>) {
+    let Some(last) = last else {
+        return;
     };
+    let start = after.map_or(ops::Bound::Unbounded, ops::Bound::Excluded);
+    let products: Vec<Product> = PRODUCTS_STORAGE.with(|products| {
+        products.borrow().range((start, ops::Bound::Included(last))).map(|(_, product)| product).collect()
+    });
+    for product in products {
+        let (listed, logged_quantity) = STOCK_MOVEMENTS.with(|movements| {
+            movements
+                .borrow()
+                .range((product.id, 0)..=(product.id, u64::MAX))
+                .fold((false, 0i64), |(listed, logged), (_, movement)| {
+                    (listed || movement.reason == StockMovementReason::Listed, logged + movement.delta)
+                })
+        });
+        let opening = product.stock_quantity as i64 - logged_quantity;
+        if listed || opening == 0 {
+            continue;
+        }
+        let Ok(id) = STOCK_MOVEMENT_ID_COUNTER.with(generate_id) else {
+            return;
+        };
+        let movement = StockMovement {
+            id,
+            product_id: product.id,
+            variant_id: None,
+            delta: opening,
+            reason: StockMovementReason::Listed,
+            balance_after: product.stock_quantity,
+            actor_id: None,
+            order_id: None,
+            created_at: product.created_at,
+        };
+        STOCK_MOVEMENTS.with(|movements| movements.borrow_mut().insert((product.id, id), movement));
+    }
+}
 
-    let product = match _get_product(&payload.product_id) {
-        Some(product) => product,
-        None => return Err(Error::NotFound {
-            msg: format!("Product with id={} not found", payload.product_id),
-        }),
+// Cancels the pending orders of the first release after `after` up to `last`. Their buyers
+// set the total themselves and they carry no price breakdown, so they cannot be paid; the
+// stock they took out when placed is put back.
+fn cancel_unpriced_orders(after: Option<u64>, last: Option<u64>) {
+    let Some(last) = last else {
+        return;
     };
+    let start = after.map_or(ops::Bound::Unbounded, ops::Bound::Excluded);
+    let orders: Vec<Order> = ORDERS_STORAGE.with(|orders| {
+        orders.borrow().range((start, ops::Bound::Included(last))).map(|(_, order)| order).collect()
+    });
+    let unpriced = orders
+        .into_iter()
+        .filter(|order| order.status == OrderStatus::Pending && order.items.iter().all(|item| item.price_breakdown.total == 0));
+    for mut order in unpriced {
+        if transition_order(&mut order, OrderStatus::Cancelled, None).is_err() {
+            continue;
+        }
+        // Stock that no longer fits is left as it is rather than holding up the migration
+        restock_order(&mut order).ok();
+        do_insert_order(&order);
+    }
+}
 
-    // Check stock availability
-    if payload.quantity > product.stock_quantity {
-        return Err(Error::InvalidInput {
-            msg: format!(
-                "Requested quantity exceeds available stock. Available: {}",
-                product.stock_quantity
-            ),
+// Decoding reads any earlier layout and inserting encodes in the current one, so a record is
+// migrated by reading it and writing it back
+fn rewrite_records<K: MigrationKey, V: Storable>(
+    store: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    cursor: Option<(u64, u64)>,
+) -> (Option<(u64, u64)>, usize) {
+    store.with(|store| {
+        let mut store = store.borrow_mut();
+        let batch: Vec<(K, V)> = match cursor {
+            Some(cursor) => store
+                .range((ops::Bound::Excluded(K::from_cursor(cursor)), ops::Bound::Unbounded))
+                .take(MIGRATION_BATCH_SIZE)
+                .collect(),
+            None => store.iter().take(MIGRATION_BATCH_SIZE).collect(),
+        };
+        let last = batch.last().map(|(key, _)| key.to_cursor());
+        let count = batch.len();
+        for (key, value) in batch {
+            store.insert(key, value);
+        }
+        (last, count)
+    })
+}
+
+// What is derived from the stored records and built from them after an upgrade: the
+// certification tree, which lives on the heap, and the indexes and reputation counters that
+// releases before them did not keep. They run in this order.
+const BACKFILLS: [&str; 9] = [
+    "certified_products",
+    "certified_orders",
+    "order_indexes",
+    "search_index",
+    "product_indexes",
+    "cart_index",
+    "order_reputation",
+    "dispute_reputation",
+    "rating_reputation",
+];
+
+fn backfill_status() -> BackfillStatus {
+    BACKFILL_STATUS.with(|status| status.borrow().get().clone())
+}
+
+fn set_backfill_status(status: BackfillStatus) {
+    BACKFILL_STATUS.with(|cell| cell.borrow_mut().set(status).expect("Cannot store the backfill status"));
+}
+
+// Sets up the backfills of this upgrade. The certification tree is rebuilt every time; an
+// index is built once, by the first upgrade that keeps track of the backfills and finds it
+// empty, and one that an earlier upgrade left unfinished carries on where it stopped.
+fn start_backfills() {
+    let mut status = backfill_status();
+    if status.jobs.is_empty() {
+        status.jobs = BACKFILLS
+            .iter()
+            .map(|job| Backfill {
+                job: job.to_string(),
+                processed: 0,
+                cursor: None,
+                done: !backfill_needed(job),
+            })
+            .collect();
+    }
+    for job in status.jobs.iter_mut().filter(|job| job.job.starts_with("certified_")) {
+        job.processed = 0;
+        job.cursor = None;
+        job.done = false;
+    }
+    status.started_at = Some(time());
+    status.finished_at = None;
+    set_backfill_status(status);
+}
+
+fn backfill_needed(job: &str) -> bool {
+    match job {
+        "order_indexes" => {
+            ORDER_COUNTS.with(|counts| counts.borrow().is_empty()) || OPEN_ORDERS_BY_PRODUCT.with(|index| index.borrow().is_empty())
+        }
+        "search_index" => SEARCH_INDEX.with(|index| index.borrow().is_empty()),
+        "product_indexes" => {
+            PRODUCTS_BY_SORT_KEY.with(|index| index.borrow().is_empty()) || PRODUCTS_BY_SELLER.with(|index| index.borrow().is_empty())
+        }
+        "cart_index" => CART_ITEMS_BY_PRODUCT.with(|index| index.borrow().is_empty()),
+        "order_reputation" | "dispute_reputation" | "rating_reputation" => REPUTATION_COUNTERS.with(|counters| counters.borrow().is_empty()),
+        _ => true,
+    }
+}
+
+// Runs the backfills batch by batch until they are all done or this call's instruction budget
+// is spent, in which case a timer continues where it stopped. Building an index entry twice
+// changes nothing, so records changed in between are indexed correctly either way.
+fn run_backfill_batch() {
+    let start = system::instruction_counter();
+    let mut status = backfill_status();
+    for job in status.jobs.iter_mut() {
+        while !job.done {
+            if system::instruction_counter() - start > MIGRATION_INSTRUCTION_BUDGET {
+                set_backfill_status(status);
+                certified::publish();
+                system::set_timer(Duration::ZERO, run_backfill_batch);
+                return;
+            }
+            let (cursor, count) = backfill(&job.job, job.cursor);
+            job.processed += count as u64;
+            job.cursor = cursor.or(job.cursor);
+            job.done = count < MIGRATION_BATCH_SIZE;
+        }
+    }
+    status.finished_at = status.finished_at.or(Some(time()));
+    set_backfill_status(status);
+    certified::publish();
+}
+
+fn backfill_pending(job: &str) -> bool {
+    backfill_status().jobs.iter().any(|backfill| backfill.job == job && !backfill.done)
+}
+
+// Whether the reputation counters already include record `id` of the store that the backfill
+// `job` counts. Until the backfill gets to a record, changes to it are left for it to count.
+fn reputation_counts(job: &str, id: u64) -> bool {
+    backfill_status()
+        .jobs
+        .iter()
+        .find(|backfill| backfill.job == job)
+        .is_none_or(|backfill| backfill.done || backfill.cursor.is_some_and(|cursor| id <= cursor.0))
+}
+
+// Processes the next batch of `job` after `cursor`, returning the key of the last record
+// processed and the number of records
+fn backfill(job: &str, cursor: Option<(u64, u64, u64)>) -> (Option<(u64, u64, u64)>, usize) {
+    fn batch_end<V>(batch: &[(u64, V)]) -> (Option<(u64, u64, u64)>, usize) {
+        (batch.last().map(|(id, _)| (*id, 0, 0)), batch.len())
+    }
+
+    match job {
+        "certified_products" => {
+            let products = records_after(&PRODUCTS_STORAGE, cursor);
+            for (id, product) in &products {
+                certified::insert(certified::PRODUCTS, *id, product);
+            }
+            batch_end(&products)
+        }
+        "certified_orders" => {
+            let orders = records_after(&ORDERS_STORAGE, cursor);
+            for (id, order) in &orders {
+                certified::insert(certified::ORDERS, *id, order);
+            }
+            batch_end(&orders)
+        }
+        "order_indexes" => {
+            let orders = records_after(&ORDERS_STORAGE, cursor);
+            for (_, order) in &orders {
+                index_order(order, None);
+            }
+            batch_end(&orders)
+        }
+        "search_index" => {
+            let products = records_after(&PRODUCTS_STORAGE, cursor);
+            for (id, product) in &products {
+                update_search_index(*id, &BTreeMap::new(), &search_terms(product));
+            }
+            batch_end(&products)
+        }
+        "product_indexes" => {
+            let products = records_after(&PRODUCTS_STORAGE, cursor);
+            for (_, product) in &products {
+                index_product(product, None);
+            }
+            batch_end(&products)
+        }
+        "cart_index" => {
+            let lines: Vec<CartKey> = CART_ITEMS.with(|cart| {
+                let cart = cart.borrow();
+                let start = cursor.map_or(ops::Bound::Unbounded, |(buyer_id, product_id, variant_id)| {
+                    ops::Bound::Excluded(((buyer_id, product_id), variant_id))
+                });
+                cart.range((start, ops::Bound::Unbounded)).take(MIGRATION_BATCH_SIZE).map(|(key, _)| key).collect()
+            });
+            CART_ITEMS_BY_PRODUCT.with(|index| {
+                let mut index = index.borrow_mut();
+                for ((buyer_id, product_id), variant_id) in &lines {
+                    index.insert((*product_id, *buyer_id, *variant_id), ());
+                }
+            });
+            let last = lines.last().map(|((buyer_id, product_id), variant_id)| (*buyer_id, *product_id, *variant_id));
+            (last, lines.len())
+        }
+        "order_reputation" => {
+            let orders = records_after(&ORDERS_STORAGE, cursor);
+            for (_, order) in &orders {
+                count_order_reputation(None, Some(order));
+            }
+            batch_end(&orders)
+        }
+        "dispute_reputation" => {
+            let disputes = records_after(&DISPUTES, cursor);
+            for (_, dispute) in &disputes {
+                count_dispute_reputation(dispute);
+            }
+            batch_end(&disputes)
+        }
+        "rating_reputation" => {
+            let products = records_after(&PRODUCTS_STORAGE, cursor);
+            for (_, product) in products.iter().filter(|(_, product)| product.rating.count > 0) {
+                update_reputation(product.seller_id, |counters| {
+                    counters.review_count += product.rating.count;
+                    counters.rating_total += product.rating.total;
+                });
+            }
+            batch_end(&products)
+        }
+        _ => (None, 0),
+    }
+}
+
+// The next batch of records of `store` after the one whose id the cursor starts with
+fn records_after<V: Storable>(
+    store: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    cursor: Option<(u64, u64, u64)>,
+) -> Vec<(u64, V)> {
+    store.with(|store| {
+        let start = cursor.map_or(ops::Bound::Unbounded, |cursor| ops::Bound::Excluded(cursor.0));
+        store.borrow().range((start, ops::Bound::Unbounded)).take(MIGRATION_BATCH_SIZE).collect()
+    })
+}
+
+// Collects the rejected fields of a payload so they can all be reported at once
+#[derive(Default)]
+struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    fn add(&mut self, field: &str, msg: impl Into<String>) {
+        self.0.push(FieldError {
+            field: field.to_string(),
+            msg: msg.into(),
         });
     }
 
-    // Generate a new order ID using thread-local storage access
-    let id = ORDER_ID_COUNTER.with(|counter| {
-        generate_id(counter)
-    })?;
+    fn into_result(self) -> Result<(), Error> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::invalid_fields(self.0))
+        }
+    }
+}
 
-    // Create the order
-    let order = Order {
-        id,
-        product_id: payload.product_id,
-        buyer_id: payload.user_id,
-        quantity: payload.quantity,
-        total_price: payload.total_price,
-        status: "pending".to_string(),
-        created_at: time(),
-        updated_at: None,
-    };
-    do_insert_order(&order);
+// `assigned` are the categories the product is already in, which stay valid once archived
+fn validate_product_payload(payload: &ProductPayload, assigned: &BTreeSet<u64>) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_PRODUCT_NAME_BYTES {
+        errors.add("name", format!("Must be between 1 and {} bytes.", MAX_PRODUCT_NAME_BYTES));
+    }
+    if payload.description.trim().is_empty() || payload.description.len() > MAX_PRODUCT_DESCRIPTION_BYTES {
+        errors.add("description", format!("Must be between 1 and {} bytes.", MAX_PRODUCT_DESCRIPTION_BYTES));
+    }
+    if payload.price == 0 {
+        errors.add("price", "Must be greater than zero.");
+    }
+    if payload.discount_bps > 10_000 {
+        errors.add("discount_bps", "Cannot exceed 10000 basis points.");
+    }
+    if payload.category_id.is_none() && !payload.secondary_category_ids.is_empty() {
+        errors.add("secondary_category_ids", "Secondary categories require a primary category.");
+    }
+    if payload.secondary_category_ids.len() > MAX_SECONDARY_CATEGORIES {
+        errors.add("secondary_category_ids", format!("At most {} secondary categories are allowed.", MAX_SECONDARY_CATEGORIES));
+    }
+    let mut seen = BTreeSet::new();
+    for category_id in payload.category_id.iter().chain(&payload.secondary_category_ids) {
+        let field = if Some(*category_id) == payload.category_id { "category_id" } else { "secondary_category_ids" };
+        if !seen.insert(*category_id) {
+            errors.add(field, format!("Category with id={} is assigned more than once.", category_id));
+            continue;
+        }
+        match _get_category(category_id) {
+            Some(category) if !category.archived || assigned.contains(category_id) => {}
+            Some(_) => errors.add(field, format!("Category with id={} is archived.", category_id)),
+            None => errors.add(field, format!("Category with id={} not found.", category_id)),
+        }
+    }
+    errors.into_result()
+}
+
+// Checks a variant's own fields and that its SKU and attribute set are not already used by
+// another variant of the product (`variant_id` being the variant that is updated, if any)
+fn validate_variant_payload(payload: &VariantPayload, product_id: u64, variant_id: Option<u64>) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if payload.sku.trim().is_empty() || payload.sku.len() > MAX_SKU_BYTES {
+        errors.add("sku", format!("Must be between 1 and {} bytes.", MAX_SKU_BYTES));
+    }
+    if payload.attributes.is_empty() || payload.attributes.len() > MAX_VARIANT_ATTRIBUTES {
+        errors.add("attributes", format!("A variant must have between 1 and {} attributes.", MAX_VARIANT_ATTRIBUTES));
+    }
+    let mut names = BTreeSet::new();
+    for attribute in &payload.attributes {
+        if attribute.name.trim().is_empty() || attribute.value.trim().is_empty() {
+            errors.add("attributes", "Attribute names and values must be provided.");
+        } else if attribute.name.len() > MAX_ATTRIBUTE_BYTES || attribute.value.len() > MAX_ATTRIBUTE_BYTES {
+            errors.add("attributes", format!("Attribute names and values cannot exceed {} bytes.", MAX_ATTRIBUTE_BYTES));
+        } else if !names.insert(attribute.name.to_lowercase()) {
+            errors.add("attributes", format!("Attribute {} is given more than once.", attribute.name));
+        }
+    }
+    if payload.price == Some(0) {
+        errors.add("price", "A price override must be greater than zero.");
+    }
+    errors.into_result()?;
+
+    let mut attributes = payload.attributes.clone();
+    attributes.sort();
+    for other in _get_variants(product_id).into_iter().filter(|other| Some(other.id) != variant_id) {
+        if other.sku == payload.sku {
+            return Err(Error::Conflict {
+                msg: format!("Variant with id={} already uses SKU {}", other.id, payload.sku),
+            });
+        }
+        let mut other_attributes = other.attributes;
+        other_attributes.sort();
+        if other_attributes == attributes {
+            return Err(Error::Conflict {
+                msg: format!("Variant with id={} already has these attributes", other.id),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn validate_media_payload(payload: &MediaUploadPayload) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if !ALLOWED_MEDIA_TYPES.contains(&payload.content_type.as_str()) {
+        errors.add("content_type", format!("{} is not supported; use one of {}.", payload.content_type, ALLOWED_MEDIA_TYPES.join(", ")));
+    }
+    if payload.size == 0 || payload.size > MAX_MEDIA_BYTES {
+        errors.add("size", format!("Images must be between 1 and {} bytes.", MAX_MEDIA_BYTES));
+    }
+    if payload.width == 0 {
+        errors.add("width", "Must be greater than zero.");
+    }
+    if payload.height == 0 {
+        errors.add("height", "Must be greater than zero.");
+    }
+    if payload.alt_text.len() > MAX_ALT_TEXT_BYTES {
+        errors.add("alt_text", format!("Cannot exceed {} bytes.", MAX_ALT_TEXT_BYTES));
+    }
+    errors.into_result()
+}
 
-    // Deduct stock from product
-    let mut updated_product = product.clone();
-    updated_product.stock_quantity -= payload.quantity;
-    updated_product.updated_at = Some(time());
-    do_insert_product(&updated_product);
+// Whether `head`, the start of a file, carries the signature of `content_type`
+fn media_signature_matches(content_type: &str, head: &[u8]) -> bool {
+    match content_type {
+        "image/jpeg" => head.starts_with(&[0xFF, 0xD8, 0xFF]),
+        "image/png" => head.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]),
+        "image/gif" => head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a"),
+        "image/webp" => head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP",
+        _ => false,
+    }
+}
 
-    Ok(order)
+fn validate_user_payload(payload: &UserPayload) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_USER_NAME_BYTES {
+        errors.add("name", format!("Must be between 1 and {} bytes.", MAX_USER_NAME_BYTES));
+    }
+    let email_valid = Regex::new(r"^[^\s@]+@[^\s@]+\.[^\s@]+$").is_ok_and(|regex| regex.is_match(&payload.email));
+    if !email_valid || payload.email.len() > MAX_EMAIL_BYTES {
+        errors.add("email", format!("Must be a valid email address of at most {} bytes.", MAX_EMAIL_BYTES));
+    }
+    errors.into_result()
 }
 
+fn validate_review_payload(payload: &ReviewPayload) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if !(1..=5).contains(&payload.rating) {
+        errors.add("rating", "Must be between 1 and 5.");
+    }
+    if payload.text.trim().is_empty() || payload.text.len() > MAX_REVIEW_TEXT_BYTES {
+        errors.add("text", format!("Must be between 1 and {} bytes.", MAX_REVIEW_TEXT_BYTES));
+    }
+    errors.into_result()
+}
 
-#[ic_cdk::query]
-fn view_order(order_id: u64) -> Result<Order, Error> {
-    match _get_order(&order_id) {
-        Some(order) => Ok(order),
-        None => Err(Error::NotFound {
-            msg: format!("Order with id={} not found", order_id),
-        }),
+// Checks a dispute statement; `field` names the payload field holding the text
+fn validate_dispute_message(body: &str, evidence: &[String], field: &str) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if body.trim().is_empty() || body.len() > MAX_DISPUTE_MESSAGE_BYTES {
+        errors.add(field, format!("Must be between 1 and {} bytes.", MAX_DISPUTE_MESSAGE_BYTES));
+    }
+    if evidence.len() > MAX_DISPUTE_EVIDENCE {
+        errors.add("evidence", format!("Cannot hold more than {} items.", MAX_DISPUTE_EVIDENCE));
     }
+    if evidence.iter().any(|item| item.trim().is_empty() || item.len() > MAX_EVIDENCE_BYTES) {
+        errors.add("evidence", format!("Each item must be between 1 and {} bytes.", MAX_EVIDENCE_BYTES));
+    }
+    errors.into_result()
 }
 
-#[ic_cdk::update]
-fn update_order(order_id: u64, payload: OrderPayload) -> Result<Order, Error> {
-    // Validate order payload
-    validate_order_payload(&payload)?;
+fn validate_split_payload(payload: &SplitEscrowPayload, escrow_amount: u64) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if payload.buyer_amount == 0 {
+        errors.add("buyer_amount", "Must be greater than zero; release the escrow to pay the seller in full.");
+    }
+    if payload.seller_amount == 0 {
+        errors.add("seller_amount", "Must be greater than zero; refund the escrow to repay the buyer in full.");
+    }
+    if payload.buyer_amount.checked_add(payload.seller_amount) != Some(escrow_amount) {
+        errors.add("seller_amount", format!("The shares must add up to the escrowed amount {}.", escrow_amount));
+    }
+    if payload.reason.trim().is_empty() || payload.reason.len() > MAX_SPLIT_REASON_BYTES {
+        errors.add("reason", format!("Must be between 1 and {} bytes.", MAX_SPLIT_REASON_BYTES));
+    }
+    errors.into_result()
+}
 
-    let mut order = match _get_order(&order_id) {
-        Some(order) => order,
-        None => return Err(Error::NotFound {
-            msg: format!("Order with id={} not found", order_id),
-        }),
-    };
+fn validate_order_payload(payload: &OrderPayload) -> Result<(), Error> {
+    let mut errors = FieldErrors::default();
+    if payload.product_id == 0 {
+        errors.add("product_id", "Must be provided.");
+    }
+    if payload.quantity == 0 {
+        errors.add("quantity", "Must be greater than zero.");
+    }
+    errors.into_result()
+}
 
-    // Ensure the order status allows updates
-    if order.status != "pending" {
-        return Err(Error::InvalidInput {
-            msg: "Only pending orders can be updated.".to_string(),
-        });
+// Authorization layer
+//
+// Anonymous callers are rejected by the `caller_is_authenticated` guard on every update
+// endpoint but `http_request_update`, which HTTP gateways call anonymously. A guard can only reject the call, so those callers get a canister reject with the
+// guard's message rather than an `Error`. Everything below is checked inside the endpoint and
+// fails with `Error::Unauthorized`, including for anonymous callers of queries.
+//
+// | Endpoint                              | Allowed callers                                      |
+// |---------------------------------------|------------------------------------------------------|
+// | register                              | any unregistered principal, as Buyer or Seller only  |
+// | update_user                           | the user themself (may only switch Buyer <-> Seller) |
+// | set_user_role                         | Admin, or a canister controller                      |
+// | assign_user_principal                 | Admin, or a canister controller (unclaimed users)    |
+// | delete_user                           | the user themself, Admin                             |
+// | create_product                        | Seller                                               |
+// | update_product                        | the owning Seller                                    |
+// | delete_product                        | the owning Seller, Admin                             |
+// | manage_inventory                      | the owning Seller                                    |
+// | add/update/remove_variant             | the owning Seller                                    |
+// | begin/finish_media_upload             | the owning Seller                                    |
+// | upload_media_chunk                    | the owning Seller                                    |
+// | delete_media                          | the owning Seller, Admin                             |
+// | view_stock_movements, reconcile_stock | the owning Seller, Admin                             |
+// | create/rename/move/archive_category   | Admin                                                |
+// | create_order                          | Buyer                                                |
+// | *_cart*, checkout                     | Buyer, on their own cart                             |
+// | update_order                          | the buying Buyer                                     |
+// | cancel_order                          | the buying Buyer, the selling Seller, Admin          |
+// | delete_order                          | the buying Buyer while Pending; Admin if Cancelled   |
+// | pay_order                             | the buying Buyer (funds the order's escrow)          |
+// | ship_order                            | the selling Seller                                   |
+// | mark_order_delivered                  | the selling Seller, Admin                            |
+// | complete_order                        | the buying Buyer, Admin                              |
+// | release_escrow                        | the buying Buyer, Admin, Arbiter                     |
+// | refund_escrow                         | the selling Seller, Admin, Arbiter                   |
+// | split_escrow                          | the selling Seller, Admin, Arbiter                   |
+// | list_transactions                     | the user themself, Admin, Arbiter                    |
+// | create_review                         | the buying Buyer, once the order is completed        |
+// | reply_to_review                       | the selling Seller                                   |
+// | open_dispute                          | the buying Buyer, the selling Seller                 |
+// | add_dispute_message                   | the order's Buyer or Seller, Admin, Arbiter          |
+// | assign_dispute                        | Admin; Arbiter only to take a dispute themself       |
+// | resolve_dispute                       | Admin, the assigned (or any unassigned) Arbiter      |
+// | view_dispute, view_order_dispute      | the order's Buyer or Seller, Admin, Arbiter          |
+// | list_disputes                         | Admin, Arbiter                                       |
+// | set_tax_rate                          | Admin                                                |
+// | set_reservation_window                | Admin                                                |
+// | set_auto_release_window               | Admin                                                |
+// | mock_ledger_mint                      | a canister controller                                |
+// | mock_ledger_approve                   | any caller                                           |
+// | list_my_orders                        | any registered user, for the orders they placed      |
+// | list_seller_orders                    | Seller, for the orders placed with them              |
+// | list_orders_by_status                 | Admin, Arbiter                                       |
+// | whoami                                | any registered user, for their own user              |
+// | view_user                             | the user themself, Admin, Arbiter                    |
+// | view_reputation                       | anyone                                               |
+// | view_seller                           | anyone                                               |
+// | view_product, view_product_certified  | anyone                                               |
+// | view_stock                            | anyone                                               |
+// | view_media                            | anyone                                               |
+// | view_category                         | anyone                                               |
+// | view_review                           | anyone                                               |
+// | view_order, view_order_certified      | the order's Buyer or Seller, Admin, Arbiter          |
+// | view_escrow, view_order_escrow        | the order's Buyer or Seller, Admin, Arbiter          |
+// | view_schema_status                    | anyone                                               |
+// | view_backfill_status                  | anyone                                               |
+// | list_products, search_products        | anyone                                               |
+// | list_variants, list_product_media     | anyone                                               |
+// | list_categories, list_product_reviews | anyone                                               |
+// | get_media_chunk, http_request         | anyone                                               |
+// | http_request_update                   | anyone                                               |
+// | quote_order                           | anyone                                               |
+// | mock_ledger_balance_of                | anyone                                               |
+
+// Guard for `#[ic_cdk::update(guard = "caller_is_authenticated")]`. The call is rejected with
+// this message before the endpoint runs.
+fn caller_is_authenticated() -> Result<(), String> {
+    if caller() == Principal::anonymous() {
+        Err("Anonymous callers are not allowed.".to_string())
+    } else {
+        Ok(())
     }
+}
 
-    // Update the order
-    order.product_id = payload.product_id;
-    order.quantity = payload.quantity;
-    order.total_price = payload.total_price;
-    order.updated_at = Some(time());
-    do_insert_order(&order);
-    Ok(order)
+// Returns the caller's user if it holds one of `roles`
+fn authorize(roles: &[Role]) -> Result<User, Error> {
+    let user = _get_caller_user()?;
+    if roles.contains(&user.role) {
+        Ok(user)
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("User with id={} and role {:?} is not allowed to perform this action", user.id, user.role),
+        })
+    }
 }
 
-#[ic_cdk::update]
-fn delete_order(order_id: u64) -> Result<Order, Error> {
-    match ORDERS_STORAGE.with(|orders| orders.borrow_mut().remove(&order_id)) {
-        Some(order) => Ok(order),
-        None => Err(Error::NotFound {
-            msg: format!("Order with id={} not found", order_id),
-        }),
+// Returns the caller's user if it is the user `owner_id` or holds one of `roles`
+fn authorize_owner_or(owner_id: u64, roles: &[Role]) -> Result<User, Error> {
+    let user = _get_caller_user()?;
+    if user.id == owner_id || roles.contains(&user.role) {
+        Ok(user)
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("User with id={} is not allowed to perform this action", user.id),
+        })
     }
 }
 
-#[ic_cdk::update]
-fn complete_order(order_id: u64) -> Result<Order, Error> {
-    let order_opt = ORDERS_STORAGE.with(|storage| storage.borrow().get(&order_id));
-    let mut order = match order_opt {
-        Some(o) => o,
-        None => return Err(Error::NotFound {
-            msg: format!("Order with id={} not found", order_id),
-        }),
-    };
-    
-    if order.status != "Pending" {
-        return Err(Error::InvalidInput {
-            msg: "Order is not in a pending state.".to_string(),
-        });
+// Returns the caller's user if it is the order's buyer or seller, an admin, or an arbiter
+fn authorize_order_party(order: &Order) -> Result<User, Error> {
+    let user = _get_caller_user()?;
+    if user.id == order.buyer_id || user.id == order.seller_id || matches!(user.role, Role::Admin | Role::Arbiter) {
+        Ok(user)
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("User with id={} is not a party to order with id={}", user.id, order.id),
+        })
     }
-    
-    order.status = "Completed".to_string();
-    order.updated_at = Some(time());
-    
-    ORDERS_STORAGE.with(|storage| storage.borrow_mut().insert(order.id, order.clone()));
-    Ok(order)
 }
 
-#[ic_cdk::update]
-fn manage_inventory(product_id: u64, quantity: u32) -> Result<Product, Error> {
-    let product_opt = PRODUCTS_STORAGE.with(|storage| storage.borrow().get(&product_id));
-    let mut product = match product_opt {
-        Some(p) => p,
-        None => return Err(Error::NotFound {
-            msg: format!("Product with id={} not found", product_id),
-        }),
-    };
-    
-    if quantity == 0 {
-        return Err(Error::InvalidInput {
-            msg: "Product quantity must be greater than zero.".to_string(),
-        });
+// Returns the caller's user if it is the disputed order's buyer or seller, the dispute's
+// assignee, an admin, or any arbiter
+fn authorize_dispute_participant(dispute: &Dispute) -> Result<User, Error> {
+    let user = _get_caller_user()?;
+    let is_party = _get_order(&dispute.order_id).is_some_and(|order| user.id == order.buyer_id || user.id == order.seller_id);
+    if is_party || dispute.assigned_to == Some(user.id) || matches!(user.role, Role::Admin | Role::Arbiter) {
+        Ok(user)
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("User with id={} is not a party to dispute with id={}", user.id, dispute.id),
+        })
     }
-    
-    product.stock_quantity = quantity;
-    product.updated_at = Some(time());
-    PRODUCTS_STORAGE.with(|storage| storage.borrow_mut().insert(product.id, product.clone()));
-    Ok(product)
 }
 
-#[ic_cdk::update]
-fn handle_escrow(order_id: u64, amount: u64) -> Result<Escrow, Error> {
-    if amount == 0 {
-        return Err(Error::InvalidInput {
-            msg: "Amount must be greater than zero.".to_string(),
+// Orders in dispute are settled by `resolve_dispute` only
+fn ensure_not_disputed(order: &Order) -> Result<(), Error> {
+    if order.status == OrderStatus::InDispute {
+        return Err(Error::Conflict {
+            msg: format!("Order with id={} is in dispute and can only be settled by resolving it", order.id),
         });
     }
+    Ok(())
+}
 
-    let id = ESCROW_ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment escrow id counter");
+// Helper functions for inserting and retrieving entities
+fn do_insert_product(product: &Product) {
+    let previous = PRODUCTS_STORAGE.with(|products| products.borrow_mut().insert(product.id, product.clone()));
+    index_product(product, previous.as_ref());
+    certified::certify(certified::PRODUCTS, product.id, product);
+}
 
-    let escrow = Escrow {
-        id,
-        order_id,
-        amount,
-        status: "held".to_string(),
-        created_at: time(),
-        updated_at: None,
-    };
+// Moves the product to its current positions in `PRODUCTS_BY_SORT_KEY` and `PRODUCTS_BY_SELLER`
+fn index_product(product: &Product, previous: Option<&Product>) {
+    if let Some(previous) = previous {
+        unindex_product(previous);
+    }
+    PRODUCTS_BY_SORT_KEY.with(|index| {
+        let mut index = index.borrow_mut();
+        for field in ProductSortField::ALL {
+            index.insert((field.index_key(), field.sort_key(product), product.id), ());
+        }
+    });
+    PRODUCTS_BY_SELLER.with(|index| index.borrow_mut().insert((product.seller_id, product.id), ()));
+}
 
-    ESCROW_STORAGE.with(|storage| storage.borrow_mut().insert(escrow.id, escrow.clone()));
-    Ok(escrow)
+fn unindex_product(product: &Product) {
+    PRODUCTS_BY_SORT_KEY.with(|index| {
+        let mut index = index.borrow_mut();
+        for field in ProductSortField::ALL {
+            index.remove(&(field.index_key(), field.sort_key(product), product.id));
+        }
+    });
+    PRODUCTS_BY_SELLER.with(|index| index.borrow_mut().remove(&(product.seller_id, product.id)));
 }
 
-#[ic_cdk::update]
-fn release_escrow(escrow_id: u64) -> Result<Escrow, Error> {
-    let escrow_opt = ESCROW_STORAGE.with(|storage| storage.borrow().get(&escrow_id));
-    let mut escrow = match escrow_opt {
-        Some(e) => e,
-        None => return Err(Error::NotFound {
-            msg: format!("Escrow with id={} not found", escrow_id),
-        }),
-    };
+// Splits text into lowercase alphanumeric words, cut to the length the index stores
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut word = word.to_lowercase();
+            if word.len() > MAX_SEARCH_TERM_BYTES {
+                let mut end = MAX_SEARCH_TERM_BYTES;
+                while !word.is_char_boundary(end) {
+                    end -= 1;
+                }
+                word.truncate(end);
+            }
+            word
+        })
+        .collect()
+}
+
+// Words of a product with their weight; a word in the name counts three times as much as
+// one in the description
+fn search_terms(product: &Product) -> BTreeMap<String, u32> {
+    let mut terms: BTreeMap<String, u32> = BTreeMap::new();
+    for word in tokenize(&product.name) {
+        *terms.entry(word).or_insert(0) += 3;
+    }
+    for word in tokenize(&product.description) {
+        *terms.entry(word).or_insert(0) += 1;
+    }
+    terms
+}
+
+fn update_search_index(product_id: u64, previous: &BTreeMap<String, u32>, current: &BTreeMap<String, u32>) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for term in previous.keys().filter(|term| !current.contains_key(*term)) {
+            index.remove(&(SearchTerm(term.clone()), product_id));
+        }
+        for (term, weight) in current {
+            index.insert((SearchTerm(term.clone()), product_id), *weight);
+        }
+    });
+}
 
-    if escrow.status != "held" {
-        return Err(Error::InvalidInput {
-            msg: "Escrow is not in a held state.".to_string(),
+// Moves the order's index entries from how it was stored before, if it was. Besides the
+// status, `update_order` can change the seller when the order switches to another product.
+fn index_order(order: &Order, previous: Option<&Order>) {
+    if let Some(previous) = previous {
+        unindex_order(previous);
+    }
+    let status = order.status.index_key();
+    ORDERS_BY_BUYER.with(|index| index.borrow_mut().insert((order.buyer_id, order.id), ()));
+    ORDERS_BY_SELLER.with(|index| index.borrow_mut().insert((order.seller_id, order.id), ()));
+    if ORDERS_BY_STATUS.with(|index| index.borrow_mut().insert((status, order.id), ())).is_none() {
+        count_orders(OrderScope::All, status, 1);
+    }
+    if ORDERS_BY_BUYER_STATUS.with(|index| index.borrow_mut().insert((order.buyer_id, status, order.id), ())).is_none() {
+        count_orders(OrderScope::Buyer(order.buyer_id), status, 1);
+    }
+    if ORDERS_BY_SELLER_STATUS.with(|index| index.borrow_mut().insert((order.seller_id, status, order.id), ())).is_none() {
+        count_orders(OrderScope::Seller(order.seller_id), status, 1);
+    }
+    if order.status.is_open() {
+        OPEN_ORDERS_BY_PRODUCT.with(|index| {
+            let mut index = index.borrow_mut();
+            for item in &order.items {
+                index.insert((item.product_id, order.id), ());
+            }
         });
     }
+}
 
-    escrow.status = "released".to_string();
-    escrow.updated_at = Some(time());
+fn unindex_order(order: &Order) {
+    let status = order.status.index_key();
+    ORDERS_BY_BUYER.with(|index| index.borrow_mut().remove(&(order.buyer_id, order.id)));
+    ORDERS_BY_SELLER.with(|index| index.borrow_mut().remove(&(order.seller_id, order.id)));
+    if ORDERS_BY_STATUS.with(|index| index.borrow_mut().remove(&(status, order.id))).is_some() {
+        count_orders(OrderScope::All, status, -1);
+    }
+    if ORDERS_BY_BUYER_STATUS.with(|index| index.borrow_mut().remove(&(order.buyer_id, status, order.id))).is_some() {
+        count_orders(OrderScope::Buyer(order.buyer_id), status, -1);
+    }
+    if ORDERS_BY_SELLER_STATUS.with(|index| index.borrow_mut().remove(&(order.seller_id, status, order.id))).is_some() {
+        count_orders(OrderScope::Seller(order.seller_id), status, -1);
+    }
+    OPEN_ORDERS_BY_PRODUCT.with(|index| {
+        let mut index = index.borrow_mut();
+        for item in &order.items {
+            index.remove(&(item.product_id, order.id));
+        }
+    });
+}
 
-    ESCROW_STORAGE.with(|storage| storage.borrow_mut().insert(escrow.id, escrow.clone()));
-    Ok(escrow)
+fn count_orders(scope: OrderScope, status: u8, delta: i64) {
+    ORDER_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let (kind, user_id) = scope.key();
+        let key = (kind, user_id, status);
+        match counts.get(&key).unwrap_or(0).saturating_add_signed(delta) {
+            0 => counts.remove(&key),
+            count => counts.insert(key, count),
+        };
+    });
 }
 
-#[ic_cdk::update]
-fn refund_escrow(escrow_id: u64) -> Result<Escrow, Error> {
-    let escrow_opt = ESCROW_STORAGE.with(|storage| storage.borrow().get(&escrow_id));
-    let mut escrow = match escrow_opt {
-        Some(e) => e,
-        None => return Err(Error::NotFound {
-            msg: format!("Escrow with id={} not found", escrow_id),
-        }),
-    };
+// An order of the user, as buyer or seller, that is still open or whose escrow is still held
+fn unsettled_order_of(user_id: u64) -> Option<u64> {
+    let mut order_ids: BTreeSet<u64> = BTreeSet::new();
+    ORDERS_BY_BUYER.with(|index| {
+        order_ids.extend(index.borrow().range((user_id, 0)..=(user_id, u64::MAX)).map(|((_, order_id), _)| order_id))
+    });
+    ORDERS_BY_SELLER.with(|index| {
+        order_ids.extend(index.borrow().range((user_id, 0)..=(user_id, u64::MAX)).map(|((_, order_id), _)| order_id))
+    });
+    order_ids.into_iter().filter_map(|order_id| _get_order(&order_id)).find_map(|order| {
+        let settled = !order.status.is_open();
+        let escrow_held = _get_escrow_id_by_order(&order.id)
+            .and_then(|escrow_id| _get_escrow(&escrow_id))
+            .is_some_and(|escrow| escrow.status == EscrowStatus::Held);
+        (!settled || escrow_held).then_some(order.id)
+    })
+}
 
-    if escrow.status != "held" {
-        return Err(Error::InvalidInput {
-            msg: "Escrow is not in a held state.".to_string(),
-        });
-    }
+// Whose orders a page lists
+#[derive(Clone, Copy)]
+enum OrderScope {
+    Buyer(u64),
+    Seller(u64),
+    All,
+}
 
-    escrow.status = "refunded".to_string();
-    escrow.updated_at = Some(time());
+impl OrderScope {
+    // Key of the scope in `ORDER_COUNTS`
+    fn key(&self) -> (u8, u64) {
+        match self {
+            OrderScope::Buyer(user_id) => (0, *user_id),
+            OrderScope::Seller(user_id) => (1, *user_id),
+            OrderScope::All => (2, 0),
+        }
+    }
 
-    ESCROW_STORAGE.with(|storage| storage.borrow_mut().insert(escrow.id, escrow.clone()));
-    Ok(escrow)
+    // Up to `limit` ids of the scope's orders in `status`, ascending from `start`
+    fn order_ids(&self, status: u8, start: u64, limit: usize) -> Vec<u64> {
+        match *self {
+            OrderScope::Buyer(user_id) => ORDERS_BY_BUYER_STATUS.with(|index| {
+                index.borrow().range((user_id, status, start)..=(user_id, status, u64::MAX)).take(limit).map(|((_, _, id), _)| id).collect()
+            }),
+            OrderScope::Seller(user_id) => ORDERS_BY_SELLER_STATUS.with(|index| {
+                index.borrow().range((user_id, status, start)..=(user_id, status, u64::MAX)).take(limit).map(|((_, _, id), _)| id).collect()
+            }),
+            OrderScope::All => ORDERS_BY_STATUS.with(|index| {
+                index.borrow().range((status, start)..=(status, u64::MAX)).take(limit).map(|((_, id), _)| id).collect()
+            }),
+        }
+    }
 }
 
-#[ic_cdk::update]
-fn resolve_dispute(order_id: u64, resolution: String) -> Result<Order, Error> {
-    let order_opt = ORDERS_STORAGE.with(|storage| storage.borrow().get(&order_id));
-    let mut order = match order_opt {
-        Some(o) => o,
-        None => return Err(Error::NotFound {
-            msg: format!("Order with id={} not found", order_id),
-        }),
+// Pages through the ascending order ids of `scope` in one of `statuses` (all if empty). Each
+// status contributes at most one page plus one id, so a page costs O(statuses * limit).
+fn order_page(scope: OrderScope, statuses: &[OrderStatus], start_after: Option<u64>, limit: u32) -> OrderPage {
+    let statuses: BTreeSet<u8> = match statuses {
+        [] => OrderStatus::ALL.iter().map(OrderStatus::index_key).collect(),
+        statuses => statuses.iter().map(OrderStatus::index_key).collect(),
     };
+    let total = ORDER_COUNTS.with(|counts| {
+        let counts = counts.borrow();
+        let (kind, user_id) = scope.key();
+        statuses.iter().map(|status| counts.get(&(kind, user_id, *status)).unwrap_or(0)).sum()
+    });
 
-    if order.status != "Pending" && order.status != "In Dispute" {
-        return Err(Error::InvalidInput {
-            msg: "Order is not in a disputable state.".to_string(),
-        });
-    }
+    let start = start_after.map_or(0, |id| id.saturating_add(1));
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut order_ids: Vec<u64> = statuses.iter().flat_map(|status| scope.order_ids(*status, start, limit + 1)).collect();
+    order_ids.sort_unstable();
+    let next_cursor = match order_ids.len() > limit {
+        true => Some(order_ids[limit - 1]),
+        false => None,
+    };
+    order_ids.truncate(limit);
 
-    match resolution.as_str() {
-        "Complete" => order.status = "Completed".to_string(),
-        "Refund" => {
-            order.status = "Refunded".to_string();
-            // Refund logic here, potentially interacting with escrow.
-        },
-        _ => return Err(Error::InvalidInput {
-            msg: "Invalid resolution type.".to_string(),
-        }),
+    OrderPage {
+        orders: order_ids.iter().filter_map(_get_order).collect(),
+        total,
+        next_cursor,
     }
+}
 
-    order.updated_at = Some(time());
-    ORDERS_STORAGE.with(|storage| storage.borrow_mut().insert(order.id, order.clone()));
-    Ok(order)
+fn do_insert_category(category: &Category) {
+    CATEGORIES.with(|categories| categories.borrow_mut().insert(category.id, category.clone()));
 }
 
-fn generate_id(counter: &RefCell<IdCell>) -> Result<u64, Error> {
-    // Borrow the `IdCell` from the `RefCell` for mutable access
-    let mut counter_borrow = counter.borrow_mut();
+// The category and all of its descendants
+fn category_subtree(category_id: u64) -> BTreeSet<u64> {
+    let mut children: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    CATEGORIES.with(|categories| {
+        for (id, category) in categories.borrow().iter() {
+            if let Some(parent_id) = category.parent_id {
+                children.entry(parent_id).or_default().push(id);
+            }
+        }
+    });
 
-    // Get the current value and increment it
-    let current_value = *counter_borrow.get();
-    let _ = counter_borrow.set(current_value + 1);
+    let mut subtree = BTreeSet::new();
+    let mut pending = vec![category_id];
+    while let Some(id) = pending.pop() {
+        if subtree.insert(id) {
+            pending.extend(children.get(&id).into_iter().flatten());
+        }
+    }
+    subtree
+}
 
-    // Return the new value
-    Ok(current_value + 1)
+fn products_in_categories(category_ids: &BTreeSet<u64>) -> BTreeSet<u64> {
+    PRODUCTS_BY_CATEGORY.with(|index| {
+        let index = index.borrow();
+        category_ids
+            .iter()
+            .flat_map(|category_id| {
+                index
+                    .range((*category_id, 0)..=(*category_id, u64::MAX))
+                    .map(|((_, product_id), _)| product_id)
+                    .collect::<Vec<u64>>()
+            })
+            .collect()
+    })
 }
 
+fn product_categories(product: &Product) -> BTreeSet<u64> {
+    product.category_id.iter().chain(&product.secondary_category_ids).copied().collect()
+}
 
-fn validate_product_payload(payload: &ProductPayload) -> Result<(), Error> {
-    if payload.name.is_empty() || payload.description.is_empty() || payload.price <= 0 || payload.stock_quantity <= 0 || payload.seller_id <= 0 {
-        return Err(Error::InvalidInput {
-            msg: "Product name, description, price, stock_quantity, and seller_id must be provided.".to_string(),
-        });
-    }
-    Ok(())
+fn update_category_index(product_id: u64, previous: &BTreeSet<u64>, current: &BTreeSet<u64>) {
+    PRODUCTS_BY_CATEGORY.with(|index| {
+        let mut index = index.borrow_mut();
+        for category_id in previous.difference(current) {
+            index.remove(&(*category_id, product_id));
+        }
+        for category_id in current {
+            index.insert((*category_id, product_id), ());
+        }
+    });
 }
 
-fn validate_user_payload(payload: &UserPayload) -> Result<(), Error> {
-    let email_regex = Regex::new(r"^[^\s@]+@[^\s@]+\.[^\s@]+$").unwrap();
-    if payload.name.is_empty() || payload.email.is_empty() || !email_regex.is_match(&payload.email) || payload.role.is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Valid name, email, and role must be provided.".to_string(),
-        });
+fn validate_category_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() || name.len() > MAX_CATEGORY_NAME_BYTES {
+        return Err(Error::invalid_field("name", format!("Must be between 1 and {} bytes.", MAX_CATEGORY_NAME_BYTES)));
     }
     Ok(())
 }
 
-fn validate_order_payload(payload: &OrderPayload) -> Result<(), Error> {
-    if payload.user_id == 0 || payload.product_id == 0 || payload.quantity == 0 || payload.total_price == 0 {
-        return Err(Error::InvalidInput {
-            msg: "User ID, product ID, quantity, and total price must be provided.".to_string(),
-        });
+// Sibling categories must have distinct names (ignoring case)
+fn ensure_unique_sibling_name(name: &str, parent_id: Option<u64>, category_id: Option<u64>) -> Result<(), Error> {
+    let clash = CATEGORIES.with(|categories| {
+        categories.borrow().iter().find(|(id, category)| {
+            Some(*id) != category_id && category.parent_id == parent_id && category.name.eq_ignore_ascii_case(name)
+        })
+    });
+    match clash {
+        Some((id, _)) => Err(Error::Conflict {
+            msg: format!("Category with id={} already uses the name {}", id, name),
+        }),
+        None => Ok(()),
     }
-    Ok(())
 }
 
-// Helper functions for inserting and retrieving entities
-fn do_insert_product(product: &Product) {
-    PRODUCTS_STORAGE.with(|products| products.borrow_mut().insert(product.id, product.clone()));
+fn do_insert_variant(variant: &ProductVariant) {
+    PRODUCT_VARIANTS.with(|variants| variants.borrow_mut().insert((variant.product_id, variant.id), variant.clone()));
+}
+
+fn do_insert_media(asset: &MediaAsset) {
+    MEDIA_ASSETS.with(|assets| assets.borrow_mut().insert(asset.id, asset.clone()));
+    PRODUCT_MEDIA.with(|index| index.borrow_mut().insert((asset.product_id, asset.id), ()));
+}
+
+// Removes the asset record and its chunks; the caller updates `Product.media_ids`
+fn remove_media_asset(asset: &MediaAsset) {
+    MEDIA_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..media_chunk_count(asset.size) {
+            chunks.remove(&(asset.id, index));
+        }
+    });
+    MEDIA_ASSETS.with(|assets| assets.borrow_mut().remove(&asset.id));
+    PRODUCT_MEDIA.with(|index| index.borrow_mut().remove(&(asset.product_id, asset.id)));
+}
+
+fn media_chunk_count(size: u64) -> u32 {
+    size.div_ceil(MEDIA_CHUNK_BYTES as u64) as u32
+}
+
+fn authorize_media_owner(asset: &MediaAsset) -> Result<User, Error> {
+    match _get_product(&asset.product_id) {
+        Some(product) => authorize_owner_or(product.seller_id, &[]),
+        None => Err(Error::not_found(EntityKind::Product, asset.product_id)),
+    }
 }
 
 fn do_insert_user(user: &User) {
@@ -664,7 +6046,36 @@ fn do_insert_user(user: &User) {
 }
 
 fn do_insert_order(order: &Order) {
-    ORDERS_STORAGE.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+    let previous = ORDERS_STORAGE.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+    index_order(order, previous.as_ref());
+    if reputation_counts("order_reputation", order.id) {
+        count_order_reputation(previous.as_ref(), Some(order));
+    }
+    certified::certify(certified::ORDERS, order.id, order);
+}
+
+fn do_insert_dispute(dispute: &Dispute) {
+    DISPUTES.with(|disputes| disputes.borrow_mut().insert(dispute.id, dispute.clone()));
+}
+
+fn do_insert_escrow(escrow: &Escrow) {
+    ESCROW_STORAGE.with(|escrows| escrows.borrow_mut().insert(escrow.id, escrow.clone()));
+}
+
+fn _get_category(category_id: &u64) -> Option<Category> {
+    CATEGORIES.with(|categories| categories.borrow().get(category_id))
+}
+
+fn _get_media(media_id: &u64) -> Option<MediaAsset> {
+    MEDIA_ASSETS.with(|assets| assets.borrow().get(media_id))
+}
+
+// All assets of a product, pending uploads included
+fn _get_product_media(product_id: u64) -> Vec<MediaAsset> {
+    let media_ids: Vec<u64> = PRODUCT_MEDIA.with(|index| {
+        index.borrow().range((product_id, 0)..=(product_id, u64::MAX)).map(|((_, media_id), _)| media_id).collect()
+    });
+    media_ids.iter().filter_map(_get_media).collect()
 }
 
 fn _get_product(product_id: &u64) -> Option<Product> {
@@ -679,12 +6090,211 @@ fn _get_order(order_id: &u64) -> Option<Order> {
     ORDERS_STORAGE.with(|orders| orders.borrow().get(order_id))
 }
 
-// Error enum for error handling
-#[derive(candid::CandidType, Deserialize, Serialize)]
+// (product id, variant id, quantity) triples in a buyer's cart
+fn _get_cart_items(buyer_id: u64) -> Vec<(u64, Option<u64>, u32)> {
+    CART_ITEMS.with(|cart| {
+        cart.borrow()
+            .range(((buyer_id, 0), 0)..=((buyer_id, u64::MAX), u64::MAX))
+            .map(|(((_, product_id), variant_id), quantity)| {
+                (product_id, Some(variant_id).filter(|id| *id != NO_VARIANT), quantity)
+            })
+            .collect()
+    })
+}
+
+fn cart_key(buyer_id: u64, product_id: u64, variant_id: Option<u64>) -> CartKey {
+    ((buyer_id, product_id), variant_id.unwrap_or(NO_VARIANT))
+}
+
+fn do_insert_cart_item(key: CartKey, quantity: u32) {
+    let ((buyer_id, product_id), variant_id) = key;
+    CART_ITEMS.with(|cart| cart.borrow_mut().insert(key, quantity));
+    CART_ITEMS_BY_PRODUCT.with(|index| index.borrow_mut().insert((product_id, buyer_id, variant_id), ()));
+}
+
+fn do_remove_cart_item(key: &CartKey) -> Option<u32> {
+    let ((buyer_id, product_id), variant_id) = *key;
+    CART_ITEMS_BY_PRODUCT.with(|index| index.borrow_mut().remove(&(product_id, buyer_id, variant_id)));
+    CART_ITEMS.with(|cart| cart.borrow_mut().remove(key))
+}
+
+fn _get_variant(product_id: u64, variant_id: u64) -> Option<ProductVariant> {
+    PRODUCT_VARIANTS.with(|variants| variants.borrow().get(&(product_id, variant_id)))
+}
+
+fn _get_variants(product_id: u64) -> Vec<ProductVariant> {
+    PRODUCT_VARIANTS.with(|variants| {
+        variants
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|(_, variant)| variant)
+            .collect()
+    })
+}
+
+fn has_variants(product_id: u64) -> bool {
+    PRODUCT_VARIANTS.with(|variants| variants.borrow().range((product_id, 0)..=(product_id, u64::MAX)).next().is_some())
+}
+
+fn _get_escrow(escrow_id: &u64) -> Option<Escrow> {
+    ESCROW_STORAGE.with(|escrows| escrows.borrow().get(escrow_id))
+}
+
+fn _get_review(review_id: &u64) -> Option<Review> {
+    REVIEWS.with(|reviews| reviews.borrow().get(review_id))
+}
+
+fn _get_dispute(dispute_id: &u64) -> Option<Dispute> {
+    DISPUTES.with(|disputes| disputes.borrow().get(dispute_id))
+}
+
+fn _get_escrow_id_by_order(order_id: &u64) -> Option<u64> {
+    ORDER_ESCROWS.with(|index| index.borrow().get(order_id))
+}
+
+fn _get_user_id_by_principal(principal: &Principal) -> Option<u64> {
+    USER_PRINCIPALS.with(|index| index.borrow().get(&StorablePrincipal(*principal)))
+}
+
+// Resolves the registered user behind `ic_cdk::caller()`
+fn _get_caller_user() -> Result<User, Error> {
+    let principal = caller();
+    if principal == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous callers are not allowed".to_string(),
+        });
+    }
+    _get_user_id_by_principal(&principal)
+        .and_then(|user_id| _get_user(&user_id))
+        .ok_or_else(|| Error::Unauthorized {
+            msg: format!("Caller {} is not a registered user", principal),
+        })
+}
+
+// Error enum for error handling. The variant names are the stable, machine-readable error
+// codes clients match on (see `Error::code`); messages are meant for people and may change.
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
 enum Error {
     Unauthorized { msg: String },
-    NotFound { msg: String },
-    InvalidInput { msg: String },
+    NotFound { entity: EntityKind, id: u64, msg: String },
+    InvalidInput { msg: String, fields: Vec<FieldError> }, // `fields` lists each rejected field
+    Conflict { msg: String }, // The request clashes with the current state, e.g. a duplicate
+    InsufficientStock { product_id: u64, variant_id: Option<u64>, requested: u32, available: u32, msg: String },
+    InvalidStateTransition { entity: EntityKind, id: u64, from: String, to: String, msg: String },
+    PaymentFailed { msg: String },
+    RateLimited { retry_after_secs: u64, msg: String },
+    Internal { msg: String },
+}
+
+// Kinds of records an error can refer to
+#[derive(candid::CandidType, Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq)]
+enum EntityKind {
+    Product,
+    Variant,
+    Media,
+    MediaChunk, // Looked up by media id
+    Category,
+    User,
+    Seller,
+    Order,
+    Escrow,
+    OrderEscrow, // The escrow of an order, looked up by order id
+    CartItem,    // Looked up by product id
+    Dispute,
+    OrderDispute, // The dispute of an order, looked up by order id
+    Review,
+}
+
+// A single rejected input field, named as in the request payload
+#[derive(candid::CandidType, Clone, Deserialize, Serialize, Debug)]
+struct FieldError {
+    field: String,
+    msg: String,
+}
+
+impl Error {
+    fn not_found(entity: EntityKind, id: u64) -> Self {
+        Error::NotFound {
+            entity,
+            id,
+            msg: format!("{:?} with id={} not found", entity, id),
+        }
+    }
+
+    fn invalid_input(msg: impl Into<String>) -> Self {
+        Error::InvalidInput {
+            msg: msg.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    fn invalid_field(field: &str, msg: impl Into<String>) -> Self {
+        Error::invalid_fields(vec![FieldError {
+            field: field.to_string(),
+            msg: msg.into(),
+        }])
+    }
+
+    // Reports every rejected field at once, so a client can flag them all in one go
+    fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        let msg = fields
+            .iter()
+            .map(|error| format!("{}: {}", error.field, error.msg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        Error::InvalidInput { msg, fields }
+    }
+
+    fn insufficient_stock(product_id: u64, variant_id: Option<u64>, requested: u32, available: u32) -> Self {
+        Error::InsufficientStock {
+            product_id,
+            variant_id,
+            requested,
+            available,
+            msg: format!(
+                "Requested {} units of product with id={} but only {} are available",
+                requested, product_id, available
+            ),
+        }
+    }
+
+    fn invalid_transition(entity: EntityKind, id: u64, from: impl std::fmt::Debug, to: impl std::fmt::Debug) -> Self {
+        Error::InvalidStateTransition {
+            entity,
+            id,
+            from: format!("{:?}", from),
+            to: format!("{:?}", to),
+            msg: format!("{:?} with id={} cannot move from {:?} to {:?}", entity, id, from, to),
+        }
+    }
+
+    // Stable code of the error, as returned by the HTTP API
+    fn code(&self) -> &'static str {
+        match self {
+            Error::Unauthorized { .. } => "UNAUTHORIZED",
+            Error::NotFound { .. } => "NOT_FOUND",
+            Error::InvalidInput { .. } => "INVALID_INPUT",
+            Error::Conflict { .. } => "CONFLICT",
+            Error::InsufficientStock { .. } => "INSUFFICIENT_STOCK",
+            Error::InvalidStateTransition { .. } => "INVALID_STATE_TRANSITION",
+            Error::PaymentFailed { .. } => "PAYMENT_FAILED",
+            Error::RateLimited { .. } => "RATE_LIMITED",
+            Error::Internal { .. } => "INTERNAL",
+        }
+    }
+
+    // Status of the HTTP response carrying this error
+    fn http_status_code(&self) -> u16 {
+        match self {
+            Error::InvalidInput { .. } => 400,
+            Error::Unauthorized { .. } => 403,
+            Error::NotFound { .. } => 404,
+            Error::Conflict { .. } | Error::InsufficientStock { .. } | Error::InvalidStateTransition { .. } => 409,
+            Error::RateLimited { .. } => 429,
+            Error::Internal { .. } => 500,
+            Error::PaymentFailed { .. } => 502,
+        }
+    }
 }
 
 // Export candid for the canister
@@ -0,0 +1,55 @@
+// Versioned envelopes for the records kept in stable memory.
+//
+// Every record is stored as `[ENVELOPE_MAGIC, version]` followed by its Candid encoding, so a
+// decoder knows which layout it is reading. Records written before envelopes existed are
+// plain Candid, which always starts with the "DIDL" magic; they are reported as version 0.
+// Bump `SCHEMA_VERSION` whenever a stored layout changes, teach the affected `from_bytes` to
+// read the previous version, and `post_upgrade` rewrites the old records in the new layout.
+
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+
+pub const SCHEMA_VERSION: u8 = 3;
+
+const ENVELOPE_MAGIC: u8 = 0xEE;
+
+pub fn seal(payload: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 2);
+    bytes.push(ENVELOPE_MAGIC);
+    bytes.push(SCHEMA_VERSION);
+    bytes.extend(payload);
+    bytes
+}
+
+// Splits stored bytes into the layout version and the Candid payload
+pub fn open(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes {
+        [ENVELOPE_MAGIC, version, payload @ ..] => (*version, payload),
+        _ => (0, bytes),
+    }
+}
+
+// Candid encoding of a record, sealed in the current envelope
+pub fn encode<T: CandidType>(record: &T) -> Vec<u8> {
+    seal(candid::encode_one(record).expect("Records always encode to Candid"))
+}
+
+// Decodes the Candid payload of a stored record. Storable decoding cannot return an error, so
+// a record that does not decode traps with a message naming it, which rolls the call back.
+pub fn decode<T: CandidType + DeserializeOwned>(payload: &[u8]) -> T {
+    candid::decode_one(payload).unwrap_or_else(|err| {
+        ic_cdk::trap(&format!("Cannot decode stored {}: {}", std::any::type_name::<T>(), err))
+    })
+}
+
+// Decodes a payload written in either of two layouts of a record, trying `A` first
+pub fn decode_either<A, B, T>(payload: &[u8]) -> T
+where
+    A: CandidType + DeserializeOwned + Into<T>,
+    B: CandidType + DeserializeOwned + Into<T>,
+{
+    match candid::decode_one::<A>(payload) {
+        Ok(record) => record.into(),
+        Err(_) => decode::<B>(payload).into(),
+    }
+}
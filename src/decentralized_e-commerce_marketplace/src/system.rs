@@ -0,0 +1,78 @@
+// The parts of the canister system API the marketplace calls. Inside a canister they go
+// straight to `ic_cdk`. Unit tests run natively, where those calls trap, so under `cfg(test)`
+// they read a simulated clock and caller instead, and timers are never run.
+#[cfg(not(test))]
+pub use ic_cdk::api::{caller, id, instruction_counter, is_controller, set_certified_data, time};
+
+#[cfg(test)]
+pub use simulated::*;
+
+#[cfg(not(test))]
+pub fn set_timer(delay: std::time::Duration, func: impl FnOnce() + 'static) {
+    ic_cdk_timers::set_timer(delay, func);
+}
+
+#[cfg(test)]
+mod simulated {
+    use candid::Principal;
+    use std::cell::{Cell, RefCell};
+    use std::time::Duration;
+
+    thread_local! {
+        static TIME: Cell<u64> = const { Cell::new(1_700_000_000_000_000_000) };
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static CERTIFIED_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+        static INSTRUCTION_STEP: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub const CANISTER_ID: Principal = Principal::from_slice(&[0xCA, 0x01]);
+    pub const CONTROLLER: Principal = Principal::from_slice(&[0xC0, 0x01]);
+
+    pub fn time() -> u64 {
+        TIME.with(Cell::get)
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.with(Cell::get)
+    }
+
+    pub fn id() -> Principal {
+        CANISTER_ID
+    }
+
+    pub fn is_controller(principal: &Principal) -> bool {
+        *principal == CONTROLLER
+    }
+
+    pub fn instruction_counter() -> u64 {
+        INSTRUCTIONS.with(|counter| {
+            counter.set(counter.get() + INSTRUCTION_STEP.with(Cell::get));
+            counter.get()
+        })
+    }
+
+    pub fn set_certified_data(data: &[u8]) {
+        CERTIFIED_DATA.with(|certified| *certified.borrow_mut() = data.to_vec());
+    }
+
+    pub fn certified_data() -> Vec<u8> {
+        CERTIFIED_DATA.with(|certified| certified.borrow().clone())
+    }
+
+    pub fn set_timer(_delay: Duration, _func: impl FnOnce() + 'static) {}
+
+    pub fn set_caller(principal: Principal) {
+        CALLER.with(|caller| caller.set(principal));
+    }
+
+    // Makes every read of the instruction counter advance it by `step`, as if that much work
+    // was done since the last read. It stands still by default.
+    pub fn set_instruction_step(step: u64) {
+        INSTRUCTION_STEP.with(|instruction_step| instruction_step.set(step));
+    }
+
+    pub fn advance_time(duration: Duration) {
+        TIME.with(|time| time.set(time.get() + duration.as_nanos() as u64));
+    }
+}
@@ -0,0 +1,1956 @@
+// Unit tests. Each test runs on its own thread and so against its own stable memory, with the
+// clock and caller simulated by `system`.
+use super::*;
+use candid::CandidType;
+use ledger::MOCK_LEDGER_FEE;
+use sha2::Digest;
+use std::future::Future;
+use std::task::{Context, Poll, Waker};
+
+const SELLER: Principal = Principal::from_slice(&[0x5E, 0x11]);
+const BUYER: Principal = Principal::from_slice(&[0xB0, 0x01]);
+
+// The mock ledger answers without awaiting anything, so a single poll completes a call
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("Call awaited something other than the mock ledger"),
+    }
+}
+
+fn as_caller<T>(principal: Principal, call: impl FnOnce() -> T) -> T {
+    system::set_caller(principal);
+    call()
+}
+
+fn account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+fn register_as(principal: Principal, role: Role) -> User {
+    as_caller(principal, || {
+        register(UserPayload {
+            name: format!("{:?}", role),
+            email: "user@example.com".to_string(),
+            role,
+        })
+    })
+    .expect("registration succeeds")
+}
+
+// A mock-ledger marketplace with one seller listing one product at `price`, and a buyer
+// holding `balance` tokens who approved the canister for `allowance` of them
+struct Market {
+    order_id: u64,
+    total: u64,
+}
+
+fn market(price: u64, balance: u64, allowance: u64) -> Market {
+    init(InitArgs { ledger: LedgerConfig::Mock });
+    register_as(SELLER, Role::Seller);
+    register_as(BUYER, Role::Buyer);
+    let product = list_product(SELLER, price, 0);
+
+    MockLedger::mint(account(BUYER), balance).expect("balance fits");
+    let order = as_caller(BUYER, || {
+        mock_ledger_approve(allowance).expect("approval succeeds");
+        create_order(OrderPayload {
+            product_id: product.id,
+            variant_id: None,
+            quantity: 1,
+            expected_total: None,
+        })
+    })
+    .expect("order is placed");
+    Market { order_id: order.id, total: order.total_price }
+}
+
+fn pay(market: &Market) -> Result<Order, Error> {
+    system::set_caller(BUYER);
+    block_on(pay_order(market.order_id))
+}
+
+fn deliver(market: &Market) {
+    as_caller(SELLER, || {
+        ship_order(market.order_id).expect("shipping succeeds");
+        mark_order_delivered(market.order_id).expect("delivery succeeds");
+    });
+}
+
+fn order_escrow(market: &Market) -> Escrow {
+    as_caller(BUYER, || view_order_escrow(market.order_id)).expect("order has an escrow")
+}
+
+#[test]
+fn paying_moves_the_total_and_fee_into_escrow() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+
+    let order = pay(&market).expect("payment succeeds");
+    assert_eq!(order.status, OrderStatus::Paid);
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Held);
+    assert_eq!(MockLedger::balance_of(&escrow_account(market.order_id)), market.total);
+    assert_eq!(MockLedger::balance_of(&account(BUYER)), 5_000_000 - market.total - MOCK_LEDGER_FEE);
+}
+
+#[test]
+fn order_is_escrowed_once() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    let escrow = order_escrow(&market);
+    let charged = MockLedger::balance_of(&account(BUYER));
+
+    // Paying again is refused, even for an order put back to pending behind the escrow's back
+    assert!(pay(&market).is_err());
+    let mut order = _get_order(&market.order_id).expect("order exists");
+    order.status = OrderStatus::Pending;
+    do_insert_order(&order);
+    assert!(matches!(pay(&market), Err(Error::Conflict { .. })));
+
+    assert_eq!(ESCROW_STORAGE.with(|escrows| escrows.borrow().len()), 1);
+    assert_eq!(_get_escrow_id_by_order(&market.order_id), Some(escrow.id));
+    assert_eq!(MockLedger::balance_of(&account(BUYER)), charged);
+    assert_eq!(MockLedger::balance_of(&escrow_account(market.order_id)), market.total);
+}
+
+#[test]
+fn orders_move_only_along_the_lifecycle() {
+    use OrderStatus::*;
+    let moves: Vec<(OrderStatus, OrderStatus)> = OrderStatus::ALL
+        .iter()
+        .flat_map(|from| OrderStatus::ALL.iter().filter(|to| from.can_transition_to(**to)).map(move |to| (*from, *to)))
+        .collect();
+    let expected = [
+        (Pending, Paid),
+        (Pending, Cancelled),
+        (Paid, Shipped),
+        (Paid, Cancelled),
+        (Paid, InDispute),
+        (Paid, Refunded),
+        (Shipped, Delivered),
+        (Shipped, InDispute),
+        (Shipped, Refunded),
+        (Delivered, Completed),
+        (Delivered, InDispute),
+        (Delivered, Refunded),
+        (InDispute, Completed),
+        (InDispute, Refunded),
+    ];
+    assert_eq!(moves, expected);
+    assert!(OrderStatus::ALL.iter().filter(|status| !status.is_open()).all(|from| OrderStatus::ALL.iter().all(|to| !from.can_transition_to(*to))));
+
+    // An illegal move is refused and leaves the order as it was
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    let state = || {
+        let order = _get_order(&market.order_id).expect("order exists");
+        (order.status, order.status_history.len())
+    };
+    let refused = |result: Result<Order, Error>| matches!(result, Err(Error::InvalidStateTransition { .. }));
+    assert!(refused(as_caller(SELLER, || ship_order(market.order_id))));
+    assert!(refused(as_caller(SELLER, || mark_order_delivered(market.order_id))));
+    assert!(refused(as_caller(BUYER, || block_on(complete_order(market.order_id)))));
+    assert_eq!(state(), (Pending, 0));
+
+    // Each move records who made it and when
+    pay(&market).expect("payment succeeds");
+    let cancelled = as_caller(BUYER, || block_on(cancel_order(market.order_id))).expect("order is cancelled");
+    let change = cancelled.status_history.last().expect("change is recorded");
+    assert_eq!((change.from, change.to, change.changed_by, change.changed_at), (Paid, Cancelled, Some(cancelled.buyer_id), system::time()));
+    assert!(refused(as_caller(BUYER, || block_on(complete_order(market.order_id)))));
+    assert!(refused(as_caller(SELLER, || ship_order(market.order_id))));
+    assert!(pay(&market).is_err());
+    assert_eq!(state(), (Cancelled, 2));
+
+    let mut order = _get_order(&market.order_id).expect("order exists");
+    assert!(transition_order(&mut order, Shipped, None).is_err());
+    assert_eq!((order.status, order.status_history.len()), (Cancelled, 2));
+}
+
+#[test]
+fn completing_releases_the_escrow_to_the_seller() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+
+    let order = as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("completion succeeds");
+    assert_eq!(order.status, OrderStatus::Completed);
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Released);
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), market.total - MOCK_LEDGER_FEE);
+    assert_eq!(MockLedger::balance_of(&escrow_account(market.order_id)), 0);
+}
+
+#[test]
+fn refunding_returns_the_escrow_to_the_buyer() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    let escrow_id = order_escrow(&market).id;
+
+    let escrow = as_caller(SELLER, || block_on(refund_escrow(escrow_id))).expect("refund succeeds");
+    assert_eq!(escrow.status, EscrowStatus::Refunded);
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::Refunded));
+    assert_eq!(MockLedger::balance_of(&account(BUYER)), 5_000_000 - 2 * MOCK_LEDGER_FEE);
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), 0);
+}
+
+#[test]
+fn payout_that_does_not_cover_the_fee_leaves_the_escrow_held() {
+    let market = market(MOCK_LEDGER_FEE / 2, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+
+    let result = as_caller(BUYER, || block_on(complete_order(market.order_id)));
+    assert!(matches!(result, Err(Error::PaymentFailed { .. })));
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Held);
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::Delivered));
+    assert_eq!(MockLedger::balance_of(&escrow_account(market.order_id)), market.total);
+    assert!(!is_order_locked(&market.order_id));
+}
+
+#[test]
+fn retried_payout_resends_the_transfer_it_first_attempted() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+
+    // The payout lands, but its answer is lost
+    MockLedger::lose_next_reply();
+    let result = as_caller(BUYER, || block_on(complete_order(market.order_id)));
+    assert!(matches!(result, Err(Error::PaymentFailed { .. })));
+    let attempted = order_escrow(&market).split.and_then(|split| split.seller_transfer).expect("transfer is stored");
+    assert_eq!(attempted.amount, market.total - MOCK_LEDGER_FEE);
+    assert_eq!(attempted.memo, market.order_id.to_be_bytes().to_vec());
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), market.total - MOCK_LEDGER_FEE);
+
+    // The retry is answered as a duplicate and pays nothing more
+    system::advance_time(Duration::from_secs(60 * 60));
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("retry succeeds");
+    let escrow = order_escrow(&market);
+    let split = escrow.split.expect("escrow is paid out");
+    assert_eq!(escrow.status, EscrowStatus::Released);
+    assert!(split.seller_block.is_some());
+    assert_eq!(split.seller_transfer, None);
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), market.total - MOCK_LEDGER_FEE);
+}
+
+#[test]
+fn rejected_payout_is_sent_afresh_after_the_deduplication_window() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+
+    // Empty the escrow so that the ledger turns the first payout down
+    let escrow_funds = market.total - MOCK_LEDGER_FEE;
+    let drained = MockLedger.transfer(Some(escrow_subaccount(market.order_id)), account(BUYER), escrow_funds, MOCK_LEDGER_FEE, Vec::new(), time());
+    block_on(drained).expect("escrow is drained");
+    let result = as_caller(BUYER, || block_on(complete_order(market.order_id)));
+    assert!(matches!(result, Err(Error::PaymentFailed { .. })));
+    assert_eq!(order_escrow(&market).split.and_then(|split| split.seller_transfer), None);
+
+    MockLedger::mint(escrow_account(market.order_id), market.total).expect("balance fits");
+    system::advance_time(Duration::from_secs(25 * 60 * 60));
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("retry succeeds");
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Released);
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), market.total - MOCK_LEDGER_FEE);
+}
+
+#[test]
+fn payout_that_landed_is_not_repeated_once_too_old() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+
+    MockLedger::lose_next_reply();
+    assert!(as_caller(BUYER, || block_on(complete_order(market.order_id))).is_err());
+    system::advance_time(Duration::from_secs(25 * 60 * 60));
+
+    // The ledger refuses the stored transfer as too old and the escrow account shows it landed
+    let result = as_caller(BUYER, || block_on(complete_order(market.order_id)));
+    assert!(matches!(result, Err(Error::PaymentFailed { .. })));
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), market.total - MOCK_LEDGER_FEE);
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Held);
+}
+
+#[test]
+fn failed_transfer_leaves_the_order_unpaid() {
+    // The allowance covers the total but not the ledger fee on top of it
+    let market = market(1_000_000, 5_000_000, 1_000_000);
+
+    let result = pay(&market);
+    assert!(matches!(result, Err(Error::PaymentFailed { .. })));
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::Pending));
+    assert!(_get_escrow_id_by_order(&market.order_id).is_none());
+    assert_eq!(MockLedger::balance_of(&account(BUYER)), 5_000_000);
+    assert!(!is_order_locked(&market.order_id));
+
+    // Once the buyer approves enough the same order can be paid
+    as_caller(BUYER, || mock_ledger_approve(5_000_000)).expect("approval succeeds");
+    assert!(pay(&market).is_ok());
+}
+
+#[test]
+fn retried_payment_charges_the_buyer_once() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+
+    // The funds reach the escrow, but the answer is lost
+    MockLedger::lose_next_reply();
+    assert!(matches!(pay(&market), Err(Error::PaymentFailed { .. })));
+    let charged = 5_000_000 - market.total - MOCK_LEDGER_FEE;
+    assert_eq!(MockLedger::balance_of(&account(BUYER)), charged);
+    let order = _get_order(&market.order_id).expect("order exists");
+    assert_eq!(order.status, OrderStatus::Pending);
+    assert!(order.funding_transfer.is_some());
+
+    // The order cannot be cancelled until the retry settles what happened
+    let result = as_caller(BUYER, || block_on(cancel_order(market.order_id)));
+    assert!(matches!(result, Err(Error::Conflict { .. })));
+
+    let order = pay(&market).expect("retry succeeds");
+    assert_eq!(order.status, OrderStatus::Paid);
+    assert!(order.funding_transfer.is_none());
+    assert_eq!(order_escrow(&market).amount, market.total);
+    assert_eq!(MockLedger::balance_of(&account(BUYER)), charged);
+    assert_eq!(MockLedger::balance_of(&escrow_account(market.order_id)), market.total);
+}
+
+#[test]
+fn products_with_open_orders_cannot_be_deleted_and_leave_no_cart_lines() {
+    const SHOPPER: Principal = Principal::from_slice(&[0xB0, 0x02]);
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    let product_id = _get_order(&market.order_id).expect("order exists").items[0].product_id;
+    register_as(SHOPPER, Role::Buyer);
+    as_caller(SHOPPER, || add_to_cart(product_id, None, 2)).expect("product is added");
+
+    // Reserved by the pending order, then still part of the paid one
+    assert!(matches!(as_caller(SELLER, || delete_product(product_id)), Err(Error::Conflict { .. })));
+    pay(&market).expect("payment succeeds");
+    assert_eq!(_get_product(&product_id).map(|product| product.reserved_quantity), Some(0));
+    assert!(matches!(as_caller(SELLER, || delete_product(product_id)), Err(Error::Conflict { .. })));
+
+    deliver(&market);
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("order completes");
+    as_caller(SELLER, || delete_product(product_id)).expect("deletion succeeds");
+    let shopper_id = _get_user_id_by_principal(&SHOPPER).expect("shopper is registered");
+    assert!(_get_cart_items(shopper_id).is_empty());
+    assert!(CART_ITEMS_BY_PRODUCT.with(|index| index.borrow().is_empty()));
+}
+
+#[test]
+fn users_act_as_the_principal_that_registered_them() {
+    const STRANGER: Principal = Principal::from_slice(&[0x57, 0x01]);
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    let seller = as_caller(SELLER, whoami).expect("seller is registered");
+    let buyer = as_caller(BUYER, whoami).expect("buyer is registered");
+    assert_eq!((seller.principal, buyer.principal), (SELLER, BUYER));
+    assert_eq!(_get_user_id_by_principal(&SELLER), Some(seller.id));
+
+    // Listings and orders belong to whoever made the call
+    let order = _get_order(&market.order_id).expect("order exists");
+    assert_eq!((order.buyer_id, order.seller_id), (buyer.id, seller.id));
+    let product = _get_product(&order.items[0].product_id).expect("product exists");
+    assert_eq!(product.seller_id, seller.id);
+
+    // A principal owns one user, and one without a user cannot act
+    let again = UserPayload { name: "Seller".to_string(), email: "other@example.com".to_string(), role: Role::Buyer };
+    assert!(matches!(as_caller(SELLER, || register(again)), Err(Error::Conflict { .. })));
+    assert_eq!(as_caller(SELLER, whoami).map(|user| user.id).ok(), Some(seller.id));
+    let listing = ProductPayload { name: "Lamp".to_string(), description: "A desk lamp".to_string(), price: 1_000, ..ProductPayload::default() };
+    assert!(matches!(as_caller(STRANGER, || create_product(listing, 1)), Err(Error::Unauthorized { .. })));
+    assert!(as_caller(Principal::anonymous(), caller_is_authenticated).is_err());
+    assert!(matches!(as_caller(Principal::anonymous(), whoami), Err(Error::Unauthorized { .. })));
+}
+
+#[test]
+fn orders_escrows_and_users_are_private_to_their_parties() {
+    const STRANGER: Principal = Principal::from_slice(&[0x57, 0x01]);
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    register_admin();
+    register_as(STRANGER, Role::Buyer);
+    let escrow_id = order_escrow(&market).id;
+    let buyer_id = _get_user_id_by_principal(&BUYER).expect("buyer is registered");
+
+    for principal in [BUYER, SELLER, ADMIN] {
+        system::set_caller(principal);
+        assert!(view_order(market.order_id).is_ok());
+        assert!(view_escrow(escrow_id).is_ok());
+        assert!(view_order_escrow(market.order_id).is_ok());
+    }
+    system::set_caller(STRANGER);
+    assert!(matches!(view_order(market.order_id), Err(Error::Unauthorized { .. })));
+    assert!(matches!(view_escrow(escrow_id), Err(Error::Unauthorized { .. })));
+    assert!(matches!(view_order_escrow(market.order_id), Err(Error::Unauthorized { .. })));
+
+    assert!(matches!(as_caller(STRANGER, || view_user(buyer_id)), Err(Error::Unauthorized { .. })));
+    assert!(matches!(as_caller(SELLER, || view_user(buyer_id)), Err(Error::Unauthorized { .. })));
+    assert!(as_caller(BUYER, || view_user(buyer_id)).is_ok());
+    assert!(as_caller(ADMIN, || view_user(buyer_id)).is_ok());
+}
+
+#[test]
+fn parties_cannot_leave_while_their_escrow_is_held() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    let seller_id = _get_user_id_by_principal(&SELLER).expect("seller is registered");
+    let buyer_id = _get_user_id_by_principal(&BUYER).expect("buyer is registered");
+
+    assert!(matches!(as_caller(SELLER, || delete_user(seller_id)), Err(Error::Conflict { .. })));
+    assert!(matches!(as_caller(BUYER, || delete_user(buyer_id)), Err(Error::Conflict { .. })));
+
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("completion succeeds");
+    assert!(as_caller(SELLER, || delete_user(seller_id)).is_ok());
+    assert!(as_caller(BUYER, || delete_user(buyer_id)).is_ok());
+}
+
+#[test]
+fn accounts_round_trip_through_their_stable_encoding() {
+    let owner = Account { owner: SELLER, subaccount: Some([7; 32]) };
+    assert_eq!(Account::from_bytes(owner.to_bytes()), owner);
+    assert_eq!(Account::from_bytes(account(BUYER).to_bytes()), account(BUYER));
+    assert_eq!(owner.to_bytes().len(), 63);
+}
+
+#[test]
+fn upgrade_configures_the_ledger_of_a_canister_without_one() {
+    assert!(matches!(configured_ledger(), Err(Error::PaymentFailed { .. })));
+    post_upgrade(None);
+    assert!(configured_ledger().is_err());
+
+    post_upgrade(Some(InitArgs { ledger: LedgerConfig::Mock }));
+    assert!(matches!(configured_ledger(), Ok(ConfiguredLedger::Mock(_))));
+    post_upgrade(Some(InitArgs { ledger: LedgerConfig::Mock }));
+    post_upgrade(None);
+    assert!(matches!(configured_ledger(), Ok(ConfiguredLedger::Mock(_))));
+
+    // A ledger that is set cannot be swapped for another
+    let other = InitArgs { ledger: LedgerConfig::Icrc { canister_id: SELLER } };
+    assert!(std::panic::catch_unwind(|| post_upgrade(Some(other))).is_err());
+    assert!(matches!(configured_ledger(), Ok(ConfiguredLedger::Mock(_))));
+}
+
+#[test]
+fn mock_ledger_rejects_amounts_that_overflow() {
+    let spender = Account { owner: system::id(), subaccount: None };
+    MockLedger::mint(account(BUYER), 5_000_000).expect("balance fits");
+    MockLedger::mint(account(SELLER), u64::MAX).expect("balance fits");
+    MockLedger::approve(account(BUYER), spender, u64::MAX);
+
+    assert!(MockLedger::mint(account(SELLER), 1).is_err());
+    assert!(block_on(MockLedger.transfer_from(account(BUYER), account(SELLER), u64::MAX, MOCK_LEDGER_FEE, Vec::new(), time())).is_err());
+    assert!(block_on(MockLedger.transfer_from(account(BUYER), account(SELLER), 1, MOCK_LEDGER_FEE, Vec::new(), time())).is_err());
+    assert_eq!(MockLedger::balance_of(&account(BUYER)), 5_000_000);
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), u64::MAX);
+}
+
+fn set_tax_rate_bps(tax_rate_bps: u32) {
+    CONFIG.with(|config| {
+        let mut updated = config.borrow().get().clone();
+        updated.tax_rate_bps = tax_rate_bps;
+        config.borrow_mut().set(updated).expect("config is stored");
+    });
+}
+
+#[test]
+fn price_applies_discount_then_tax_and_adds_shipping_once() {
+    set_tax_rate_bps(1_000);
+    let product = Product {
+        price: 1_000,
+        shipping_fee: 500,
+        discount_bps: 2_000,
+        ..Product::default()
+    };
+
+    let price = compute_price(&product, None, 3, product.shipping_fee).expect("price fits");
+    assert_eq!(price.subtotal, 3_000);
+    assert_eq!(price.discount, 600);
+    assert_eq!(price.tax, 240);
+    assert_eq!(price.shipping, 500);
+    assert_eq!(price.total, 3_000 - 600 + 240 + 500);
+
+    let without_shipping = compute_price(&product, None, 3, 0).expect("price fits");
+    assert_eq!(without_shipping.total, price.total - 500);
+}
+
+#[test]
+fn variant_price_overrides_product_price() {
+    let product = Product { price: 1_000, ..Product::default() };
+    let variant = ProductVariant {
+        id: 1,
+        product_id: 1,
+        sku: "LAMP-RED".to_string(),
+        attributes: Vec::new(),
+        price: Some(1_500),
+        stock_quantity: 0,
+        reserved_quantity: 0,
+        created_at: 0,
+        updated_at: None,
+    };
+
+    let price = compute_price(&product, Some(&variant), 2, 0).expect("price fits");
+    assert_eq!(price.unit_price, 1_500);
+    assert_eq!(price.total, 3_000);
+}
+
+#[test]
+fn price_too_large_for_u64_is_rejected() {
+    let product = Product { price: u64::MAX, ..Product::default() };
+    assert!(matches!(compute_price(&product, None, 2, 0), Err(Error::InvalidInput { .. })));
+}
+
+fn list_product(seller: Principal, price: u64, shipping_fee: u64) -> Product {
+    as_caller(seller, || {
+        let payload = ProductPayload {
+            name: "Lamp".to_string(),
+            description: "A desk lamp".to_string(),
+            price,
+            shipping_fee,
+            ..ProductPayload::default()
+        };
+        create_product(payload, 10)
+    })
+    .expect("product is listed")
+}
+
+#[test]
+fn checkout_charges_shipping_once_per_seller() {
+    const OTHER_SELLER: Principal = Principal::from_slice(&[0x5E, 0x12]);
+    register_as(SELLER, Role::Seller);
+    register_as(OTHER_SELLER, Role::Seller);
+    register_as(BUYER, Role::Buyer);
+    let lamp = list_product(SELLER, 1_000, 300);
+    let desk = list_product(SELLER, 5_000, 500);
+    let chair = list_product(OTHER_SELLER, 2_000, 200);
+
+    system::set_caller(BUYER);
+    for product in [&lamp, &desk, &chair] {
+        add_to_cart(product.id, None, 2).expect("line is added");
+    }
+    let cart_total = view_cart().expect("cart is readable").total;
+
+    let orders = checkout(None).expect("checkout succeeds");
+    let totals: Vec<_> = orders.iter().map(|order| (order.seller_id, order.total_price)).collect();
+    assert_eq!(totals, vec![(lamp.seller_id, 2_000 + 10_000 + 500), (chair.seller_id, 4_000 + 200)]);
+    let shipping: Vec<_> = orders[0].items.iter().map(|item| item.price_breakdown.shipping).collect();
+    assert_eq!(shipping, vec![0, 500]);
+    assert_eq!(cart_total, 12_500 + 4_200);
+}
+
+#[test]
+fn checkout_that_comes_up_short_places_no_order() {
+    const OTHER_SELLER: Principal = Principal::from_slice(&[0x5E, 0x12]);
+    register_as(SELLER, Role::Seller);
+    register_as(OTHER_SELLER, Role::Seller);
+    register_as(BUYER, Role::Buyer);
+    let lamp = list_product(SELLER, 1_000, 0);
+    let shirt = as_caller(OTHER_SELLER, || {
+        let payload = ProductPayload {
+            name: "Shirt".to_string(),
+            description: "A cotton shirt".to_string(),
+            price: 2_000,
+            ..ProductPayload::default()
+        };
+        create_product(payload, 0)
+    })
+    .expect("product is listed");
+    let sizes: Vec<ProductVariant> = ["S", "M"]
+        .iter()
+        .map(|size| {
+            let variant = VariantPayload {
+                sku: format!("SHIRT-{}", size),
+                attributes: vec![VariantAttribute { name: "size".to_string(), value: size.to_string() }],
+                price: None,
+            };
+            as_caller(OTHER_SELLER, || add_variant(shirt.id, variant, 5)).expect("variant is added")
+        })
+        .collect();
+
+    // Each size has the stock its line asks for, but the shirt as a whole has less
+    let mut short = _get_product(&shirt.id).expect("product exists");
+    short.stock_quantity = 6;
+    do_insert_product(&short);
+
+    system::set_caller(BUYER);
+    add_to_cart(lamp.id, None, 2).expect("line is added");
+    for size in &sizes {
+        add_to_cart(shirt.id, Some(size.id), 5).expect("line is added");
+    }
+    assert!(matches!(checkout(None), Err(Error::InsufficientStock { .. })));
+
+    assert!(list_my_orders(Vec::new(), None, 10).expect("buyers can list").orders.is_empty());
+    let unchanged = _get_product(&lamp.id).expect("product exists");
+    assert_eq!((unchanged.stock_quantity, unchanged.reserved_quantity), (10, 0));
+    assert_eq!(view_cart().expect("cart is readable").lines.len(), 3);
+}
+
+fn product_payload(product: &Product) -> ProductPayload {
+    ProductPayload {
+        name: product.name.clone(),
+        description: product.description.clone(),
+        price: product.price,
+        shipping_fee: product.shipping_fee,
+        discount_bps: product.discount_bps,
+        category_id: product.category_id,
+        secondary_category_ids: product.secondary_category_ids.clone(),
+    }
+}
+
+#[test]
+fn updating_a_variant_product_leaves_its_stock_alone() {
+    register_as(SELLER, Role::Seller);
+    let product = as_caller(SELLER, || {
+        let payload = ProductPayload {
+            name: "Shirt".to_string(),
+            description: "A cotton shirt".to_string(),
+            price: 2_000,
+            ..ProductPayload::default()
+        };
+        create_product(payload, 0)
+    })
+    .expect("product is listed");
+    let variant = VariantPayload {
+        sku: "SHIRT-M".to_string(),
+        attributes: vec![VariantAttribute { name: "size".to_string(), value: "M".to_string() }],
+        price: None,
+    };
+    as_caller(SELLER, || add_variant(product.id, variant, 5)).expect("variant is added");
+
+    let payload = ProductPayload { price: 2_500, ..product_payload(&product) };
+    let updated = as_caller(SELLER, || update_product(product.id, payload)).expect("update succeeds");
+    assert_eq!(updated.price, 2_500);
+    assert_eq!(updated.stock_quantity, 5);
+}
+
+#[test]
+fn each_field_limit_is_reported_as_invalid_input() {
+    let text = |bytes: usize| "a".repeat(bytes);
+    register_admin();
+    register_as(SELLER, Role::Seller);
+
+    // Records have no size bound of their own: a listing at the limits is stored whole
+    let listing = ProductPayload {
+        name: text(MAX_PRODUCT_NAME_BYTES),
+        description: text(MAX_PRODUCT_DESCRIPTION_BYTES),
+        price: 1_000,
+        ..ProductPayload::default()
+    };
+    let product = as_caller(SELLER, || create_product(listing, 1)).expect("product is listed");
+    assert_eq!(_get_product(&product.id).map(|stored| stored.description.len()), Some(MAX_PRODUCT_DESCRIPTION_BYTES));
+    let too_long = ProductPayload {
+        name: text(MAX_PRODUCT_NAME_BYTES + 1),
+        description: text(MAX_PRODUCT_DESCRIPTION_BYTES + 1),
+        price: 1_000,
+        ..ProductPayload::default()
+    };
+    assert_eq!(rejected_fields(as_caller(SELLER, || create_product(too_long, 1))), vec!["name", "description"]);
+
+    let email = format!("{}@example.com", text(MAX_EMAIL_BYTES));
+    let user = UserPayload { name: text(MAX_USER_NAME_BYTES + 1), email, role: Role::Buyer };
+    assert_eq!(rejected_fields(as_caller(BUYER, || register(user))), vec!["name", "email"]);
+
+    let attribute = |name: &str, value: String| VariantAttribute { name: name.to_string(), value };
+    let variant = VariantPayload {
+        sku: text(MAX_SKU_BYTES + 1),
+        attributes: vec![attribute("size", text(MAX_ATTRIBUTE_BYTES + 1))],
+        price: None,
+    };
+    assert_eq!(rejected_fields(as_caller(SELLER, || add_variant(product.id, variant, 1))), vec!["sku", "attributes"]);
+    let attributes = (0..=MAX_VARIANT_ATTRIBUTES).map(|index| attribute(&format!("a{}", index), "b".to_string())).collect();
+    let variant = VariantPayload { sku: "SKU".to_string(), attributes, price: None };
+    assert_eq!(rejected_fields(as_caller(SELLER, || add_variant(product.id, variant, 1))), vec!["attributes"]);
+
+    let media = MediaUploadPayload {
+        content_type: "image/png".to_string(),
+        size: MAX_MEDIA_BYTES + 1,
+        width: 1,
+        height: 1,
+        alt_text: text(MAX_ALT_TEXT_BYTES + 1),
+        thumbnail_of: None,
+    };
+    assert_eq!(rejected_fields(as_caller(SELLER, || begin_media_upload(product.id, media))), vec!["size", "alt_text"]);
+
+    assert_eq!(rejected_fields(as_caller(ADMIN, || create_category(text(MAX_CATEGORY_NAME_BYTES + 1), None))), vec!["name"]);
+    let secondary = (1..=MAX_SECONDARY_CATEGORIES as u64 + 1).collect();
+    let categorised = ProductPayload { category_id: Some(100), secondary_category_ids: secondary, ..product_payload(&product) };
+    assert!(rejected_fields(as_caller(SELLER, || update_product(product.id, categorised))).contains(&"secondary_category_ids".to_string()));
+
+    let review = ReviewPayload { rating: 5, text: text(MAX_REVIEW_TEXT_BYTES + 1), ..ReviewPayload::default() };
+    assert_eq!(rejected_fields(validate_review_payload(&review)), vec!["text"]);
+    let evidence = vec![text(MAX_EVIDENCE_BYTES + 1); MAX_DISPUTE_EVIDENCE + 1];
+    let statement = validate_dispute_message(&text(MAX_DISPUTE_MESSAGE_BYTES + 1), &evidence, "description");
+    assert_eq!(rejected_fields(statement), vec!["description", "evidence", "evidence"]);
+    let split = SplitEscrowPayload { buyer_amount: 1, seller_amount: 1, reason: text(MAX_SPLIT_REASON_BYTES + 1) };
+    assert_eq!(rejected_fields(validate_split_payload(&split, 2)), vec!["reason"]);
+}
+
+fn order_of(items: &[(u64, u32)]) -> Order {
+    let items = items
+        .iter()
+        .map(|&(product_id, quantity)| OrderItem { product_id, quantity, ..OrderItem::default() })
+        .collect();
+    Order { id: 1, items, ..Order::default() }
+}
+
+#[test]
+fn stock_movement_applies_delta_and_logs_balance() {
+    let mut product = Product { id: 1, stock_quantity: 5, ..Product::default() };
+
+    let movement = record_stock_movement(&mut product, None, -3, StockMovementReason::OrderReserved, None, Some(7)).expect("stock suffices");
+    assert_eq!(product.stock_quantity, 2);
+    assert_eq!((movement.delta, movement.balance_after, movement.order_id), (-3, 2, Some(7)));
+
+    let result = record_stock_movement(&mut product, None, -3, StockMovementReason::OrderReserved, None, Some(7));
+    assert!(matches!(result, Err(Error::InsufficientStock { .. })));
+    assert_eq!(product.stock_quantity, 2);
+    assert_eq!(STOCK_MOVEMENTS.with(|movements| movements.borrow().len()), 1);
+}
+
+#[test]
+fn stock_movement_cannot_overflow() {
+    let mut product = Product { id: 1, stock_quantity: u32::MAX, ..Product::default() };
+    let result = record_stock_movement(&mut product, None, 1, StockMovementReason::Restock, None, None);
+    assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    assert_eq!(product.stock_quantity, u32::MAX);
+}
+
+#[test]
+fn reservation_takes_exactly_the_ordered_quantity() {
+    register_as(SELLER, Role::Seller);
+    let lamp = list_product(SELLER, 1_000, 0);
+
+    let mut order = order_of(&[(lamp.id, 4)]);
+    reserve_stock(&mut order, 0).expect("stock suffices");
+    let reserved = _get_product(&lamp.id).expect("product exists");
+    assert_eq!((reserved.stock_quantity, reserved.reserved_quantity), (6, 4));
+
+    restock_order(&mut order).expect("stock fits");
+    let restocked = _get_product(&lamp.id).expect("product exists");
+    assert_eq!((restocked.stock_quantity, restocked.reserved_quantity), (10, 0));
+    let reconciliation = as_caller(SELLER, || reconcile_stock(lamp.id)).expect("owner can reconcile");
+    assert!(reconciliation.consistent);
+}
+
+#[test]
+fn upgrade_logs_the_opening_stock_of_products_listed_before_the_log() {
+    let seller = register_as(SELLER, Role::Seller);
+    let logged = list_product(SELLER, 1_000, 0);
+    let unlogged = |id| Product {
+        id,
+        name: "Desk".to_string(),
+        seller_id: seller.id,
+        price: 5_000,
+        stock_quantity: 10,
+        created_at: 7,
+        ..Product::default()
+    };
+    // One product untouched since, one restocked after the log was introduced
+    do_insert_product(&unlogged(100));
+    do_insert_product(&unlogged(101));
+    as_caller(SELLER, || manage_inventory(101, None, 2, StockAdjustmentReason::Restock)).expect("restock succeeds");
+
+    set_schema_status(SchemaStatus::default());
+    assert!(start_migration());
+    run_migration_batch();
+
+    system::set_caller(SELLER);
+    for product_id in [logged.id, 100, 101] {
+        assert!(reconcile_stock(product_id).expect("owner can reconcile").consistent);
+    }
+    let movements = view_stock_movements(101, None, 10).expect("owner can view");
+    let opening = movements.iter().find(|movement| movement.reason == StockMovementReason::Listed).expect("opening is logged");
+    assert_eq!((opening.delta, opening.created_at), (10, 7));
+    let listed = |product_id| {
+        let movements = view_stock_movements(product_id, None, 10).expect("owner can view");
+        movements.iter().filter(|movement| movement.reason == StockMovementReason::Listed).count()
+    };
+    assert_eq!((listed(logged.id), listed(100)), (1, 1));
+}
+
+#[test]
+fn expired_cancelled_and_refunded_orders_put_their_stock_back() {
+    let market = market(100_000, 5_000_000, 5_000_000);
+    let product_id = _get_order(&market.order_id).expect("order exists").items[0].product_id;
+    let place = || {
+        as_caller(BUYER, || create_order(OrderPayload { product_id, variant_id: None, quantity: 2, expected_total: None }))
+            .expect("order is placed")
+    };
+    let stock = || {
+        let level = view_stock(product_id).expect("product exists");
+        (level.available, level.reserved)
+    };
+    // The last movement logged for the order, as (delta, reason)
+    let last_movement = |order_id| {
+        let movements = as_caller(SELLER, || view_stock_movements(product_id, None, 100)).expect("owner can view");
+        movements.iter().rev().find(|movement| movement.order_id == Some(order_id)).map(|movement| (movement.delta, movement.reason))
+    };
+
+    // Reserved units come back when the reservation runs out, and only for unpaid orders
+    let expired = place();
+    assert_eq!(stock(), (7, 3));
+    pay(&market).expect("payment succeeds");
+    assert_eq!(stock(), (7, 2));
+    expire_reservation(market.order_id);
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::Paid));
+    expire_reservation(expired.id);
+    assert_eq!(_get_order(&expired.id).map(|order| order.status), Some(OrderStatus::Cancelled));
+    assert_eq!(stock(), (9, 0));
+    assert_eq!(last_movement(expired.id), Some((2, StockMovementReason::OrderReleased)));
+
+    // Cancelling releases a reservation; cancelling a paid order returns what was sold
+    let cancelled = place();
+    as_caller(BUYER, || block_on(cancel_order(cancelled.id))).expect("order is cancelled");
+    assert_eq!(stock(), (9, 0));
+    assert_eq!(last_movement(cancelled.id), Some((2, StockMovementReason::OrderReleased)));
+    let paid = place();
+    as_caller(BUYER, || block_on(pay_order(paid.id))).expect("payment succeeds");
+    assert_eq!(stock(), (7, 0));
+    as_caller(BUYER, || block_on(cancel_order(paid.id))).expect("order is cancelled");
+    assert_eq!(stock(), (9, 0));
+    assert_eq!(last_movement(paid.id), Some((2, StockMovementReason::OrderReturned)));
+
+    // So does refunding one
+    let escrow = order_escrow(&market);
+    as_caller(SELLER, || block_on(refund_escrow(escrow.id))).expect("refund succeeds");
+    assert_eq!(stock(), (10, 0));
+    assert_eq!(last_movement(market.order_id), Some((1, StockMovementReason::OrderReturned)));
+    assert!(as_caller(SELLER, || reconcile_stock(product_id)).expect("owner can reconcile").consistent);
+}
+
+#[test]
+fn short_reservation_changes_nothing() {
+    register_as(SELLER, Role::Seller);
+    let lamp = list_product(SELLER, 1_000, 0);
+    let desk = list_product(SELLER, 5_000, 0);
+
+    // Each line fits on its own, but the two lamp lines together do not
+    let mut order = order_of(&[(desk.id, 2), (lamp.id, 6), (lamp.id, 6)]);
+    let result = reserve_stock(&mut order, 0);
+    assert!(matches!(result, Err(Error::InsufficientStock { .. })));
+    assert!(order.reserved_until.is_none());
+    for product in [&lamp, &desk] {
+        let unchanged = _get_product(&product.id).expect("product exists");
+        assert_eq!((unchanged.stock_quantity, unchanged.reserved_quantity), (10, 0));
+    }
+}
+
+#[test]
+fn switching_an_order_to_another_seller_moves_it_between_seller_lists() {
+    const OTHER_SELLER: Principal = Principal::from_slice(&[0x5E, 0x12]);
+    let seller = register_as(SELLER, Role::Seller);
+    let other_seller = register_as(OTHER_SELLER, Role::Seller);
+    register_as(BUYER, Role::Buyer);
+    let lamp = list_product(SELLER, 1_000, 0);
+    let chair = list_product(OTHER_SELLER, 2_000, 0);
+
+    let payload = |product_id| OrderPayload { product_id, variant_id: None, quantity: 1, expected_total: None };
+    let order = as_caller(BUYER, || create_order(payload(lamp.id))).expect("order is placed");
+    as_caller(BUYER, || update_order(order.id, payload(chair.id))).expect("order is updated");
+
+    let seller_orders = |principal| as_caller(principal, || list_seller_orders(Vec::new(), None, 10)).expect("sellers can list");
+    assert!(seller_orders(SELLER).orders.is_empty());
+    assert_eq!(seller_orders(OTHER_SELLER).orders.iter().map(|order| order.id).collect::<Vec<_>>(), vec![order.id]);
+    assert!(!ORDERS_BY_SELLER.with(|index| index.borrow().contains_key(&(seller.id, order.id))));
+    assert!(ORDERS_BY_SELLER.with(|index| index.borrow().contains_key(&(other_seller.id, order.id))));
+}
+
+#[test]
+fn order_pages_follow_the_status_indexes_and_their_counts() {
+    register_as(SELLER, Role::Seller);
+    register_as(BUYER, Role::Buyer);
+    register_admin();
+    let lamp = list_product(SELLER, 1_000, 0);
+    let ids: Vec<u64> = (0..5)
+        .map(|_| {
+            let payload = OrderPayload { product_id: lamp.id, variant_id: None, quantity: 1, expected_total: None };
+            as_caller(BUYER, || create_order(payload)).expect("order is placed").id
+        })
+        .collect();
+    for order_id in [ids[1], ids[3]] {
+        as_caller(BUYER, || block_on(cancel_order(order_id))).expect("order is cancelled");
+    }
+
+    let page_ids = |page: &OrderPage| page.orders.iter().map(|order| order.id).collect::<Vec<_>>();
+    let check = || {
+        let first = as_caller(BUYER, || list_my_orders(vec![OrderStatus::Pending], None, 2)).expect("buyers can list");
+        assert_eq!((page_ids(&first), first.total, first.next_cursor), (vec![ids[0], ids[2]], 3, Some(ids[2])));
+        let second = as_caller(BUYER, || list_my_orders(vec![OrderStatus::Pending], first.next_cursor, 2)).expect("buyers can list");
+        assert_eq!((page_ids(&second), second.total, second.next_cursor), (vec![ids[4]], 3, None));
+
+        let all = as_caller(SELLER, || list_seller_orders(Vec::new(), Some(ids[0]), 3)).expect("sellers can list");
+        assert_eq!((page_ids(&all), all.total, all.next_cursor), (vec![ids[1], ids[2], ids[3]], 5, Some(ids[3])));
+        let cancelled = as_caller(ADMIN, || list_orders_by_status(OrderStatus::Cancelled, None, 10)).expect("admins can list");
+        assert_eq!((page_ids(&cancelled), cancelled.total), (vec![ids[1], ids[3]], 2));
+    };
+    check();
+
+    // Canisters upgraded from before the status indexes existed fill them in once
+    ORDERS_BY_BUYER_STATUS.with(|index| index.borrow_mut().clear_new());
+    ORDERS_BY_SELLER_STATUS.with(|index| index.borrow_mut().clear_new());
+    ORDERS_BY_STATUS.with(|index| index.borrow_mut().clear_new());
+    ORDER_COUNTS.with(|counts| counts.borrow_mut().clear_new());
+    set_backfill_status(BackfillStatus::default());
+    post_upgrade(None);
+    check();
+}
+
+#[test]
+fn upgrade_builds_missing_indexes_in_batches_across_calls() {
+    let market = market(1_000, 0, 0);
+    register_admin();
+    let order = _get_order(&market.order_id).expect("order exists");
+    let product_id = order.items[0].product_id;
+    let payload = OrderPayload { product_id, variant_id: None, quantity: 1, expected_total: None };
+    let second = as_caller(BUYER, || create_order(payload)).expect("order is placed");
+    let counters = |user_id| REPUTATION_COUNTERS.with(|counters| counters.borrow().get(&user_id)).unwrap_or_default();
+    let before = counters(order.buyer_id);
+
+    // An upgrade from before the status indexes and the reputation counters, with each call
+    // having room for two batches
+    ORDERS_BY_BUYER_STATUS.with(|index| index.borrow_mut().clear_new());
+    ORDERS_BY_SELLER_STATUS.with(|index| index.borrow_mut().clear_new());
+    ORDERS_BY_STATUS.with(|index| index.borrow_mut().clear_new());
+    ORDER_COUNTS.with(|counts| counts.borrow_mut().clear_new());
+    REPUTATION_COUNTERS.with(|counters| counters.borrow_mut().clear_new());
+    set_backfill_status(BackfillStatus::default());
+    system::set_instruction_step(MIGRATION_INSTRUCTION_BUDGET / 2);
+    post_upgrade(None);
+
+    // The upgrade itself only got to rebuild the certification tree
+    let total = |status| as_caller(ADMIN, || list_orders_by_status(status, None, 10)).expect("admins can list").total;
+    assert!(view_backfill_status().finished_at.is_none());
+    assert_eq!(total(OrderStatus::Pending), 0);
+    assert!(matches!(as_caller(SELLER, || delete_product(product_id)), Err(Error::Conflict { .. })));
+
+    // What changes in the meantime is indexed and counted once
+    as_caller(BUYER, || block_on(cancel_order(second.id))).expect("order is cancelled");
+    let mut calls = 0;
+    while view_backfill_status().finished_at.is_none() {
+        run_backfill_batch();
+        calls += 1;
+    }
+    assert!(calls > 1);
+    assert!(view_backfill_status().jobs.iter().all(|job| job.done));
+    assert_eq!((total(OrderStatus::Pending), total(OrderStatus::Cancelled)), (1, 1));
+    let after = counters(order.buyer_id);
+    assert_eq!((after.total_orders, after.cancelled_orders), (before.total_orders, before.cancelled_orders + 1));
+}
+
+const ADMIN: Principal = Principal::from_slice(&[0xAD, 0x01]);
+
+// Admins cannot register themselves, so the first one is promoted by a controller
+fn register_admin() -> User {
+    let admin = register_as(ADMIN, Role::Buyer);
+    as_caller(system::CONTROLLER, || set_user_role(admin.id, Role::Admin)).expect("controllers can grant roles")
+}
+
+#[test]
+fn archived_categories_keep_their_products_but_take_no_new_ones() {
+    register_admin();
+    register_as(SELLER, Role::Seller);
+    let (lamps, desks) = as_caller(ADMIN, || {
+        let lamps = create_category("Lamps".to_string(), None).expect("category is created");
+        let desks = create_category("Desks".to_string(), None).expect("category is created");
+        (lamps, desks)
+    });
+    let lamp = as_caller(SELLER, || {
+        let payload = ProductPayload {
+            name: "Lamp".to_string(),
+            description: "A desk lamp".to_string(),
+            price: 1_000,
+            category_id: Some(lamps.id),
+            ..ProductPayload::default()
+        };
+        create_product(payload, 10)
+    })
+    .expect("product is listed");
+    let other = list_product(SELLER, 2_000, 0);
+    as_caller(ADMIN, || archive_category(lamps.id)).expect("category is archived");
+
+    system::set_caller(SELLER);
+    let renamed = ProductPayload { name: "Reading lamp".to_string(), ..product_payload(&lamp) };
+    assert!(update_product(lamp.id, renamed).is_ok());
+    let moved_in = ProductPayload { category_id: Some(lamps.id), ..product_payload(&other) };
+    assert!(matches!(update_product(other.id, moved_in), Err(Error::InvalidInput { .. })));
+
+    system::set_caller(ADMIN);
+    assert!(matches!(move_category(desks.id, Some(lamps.id)), Err(Error::InvalidInput { .. })));
+    assert!(move_category(desks.id, None).is_ok());
+}
+
+#[test]
+fn category_counts_cover_subtrees_and_count_each_product_once() {
+    register_admin();
+    register_as(SELLER, Role::Seller);
+    let (furniture, lamps, desk_lamps, desks) = as_caller(ADMIN, || {
+        let furniture = create_category("Furniture".to_string(), None).expect("category is created");
+        let lamps = create_category("Lamps".to_string(), Some(furniture.id)).expect("category is created");
+        let desk_lamps = create_category("Desk lamps".to_string(), Some(lamps.id)).expect("category is created");
+        let desks = create_category("Desks".to_string(), Some(furniture.id)).expect("category is created");
+        (furniture, lamps, desk_lamps, desks)
+    });
+    let listed = |category_id, secondary_category_ids| {
+        let payload = ProductPayload {
+            name: "Lamp".to_string(),
+            description: "A lamp".to_string(),
+            price: 1_000,
+            category_id: Some(category_id),
+            secondary_category_ids,
+            ..ProductPayload::default()
+        };
+        as_caller(SELLER, || create_product(payload, 1)).expect("product is listed")
+    };
+    listed(desk_lamps.id, vec![desks.id]);
+    listed(lamps.id, Vec::new());
+    as_caller(ADMIN, || archive_category(desks.id)).expect("category is archived");
+
+    let counts = |include_archived| {
+        list_categories(include_archived).into_iter().map(|summary| (summary.category.id, summary.product_count)).collect::<Vec<_>>()
+    };
+    assert_eq!(counts(true), vec![(furniture.id, 2), (lamps.id, 2), (desk_lamps.id, 1), (desks.id, 1)]);
+    assert_eq!(counts(false), vec![(furniture.id, 2), (lamps.id, 2), (desk_lamps.id, 1)]);
+}
+
+// Layouts of the first release, which stored statuses and roles as text
+#[derive(candid::CandidType)]
+struct FirstReleaseUser {
+    id: u64,
+    name: String,
+    email: String,
+    role: String,
+    reputation: u8,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[derive(candid::CandidType)]
+struct FirstReleaseOrder {
+    id: u64,
+    product_id: u64,
+    buyer_id: u64,
+    quantity: u32,
+    total_price: u64,
+    status: String,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[test]
+fn first_release_orders_read_their_text_status_and_product_seller() {
+    let seller = register_as(SELLER, Role::Seller);
+    let lamp = list_product(SELLER, 1_000, 0);
+    let stored = |status: &str| {
+        let order = FirstReleaseOrder {
+            id: 1,
+            product_id: lamp.id,
+            buyer_id: 2,
+            quantity: 3,
+            total_price: 3_000,
+            status: status.to_string(),
+            created_at: 0,
+            updated_at: None,
+        };
+        Order::from_bytes(Cow::Owned(candid::encode_one(order).expect("order encodes")))
+    };
+
+    let order = stored("pending");
+    assert_eq!(order.status, OrderStatus::Pending);
+    assert_eq!(order.seller_id, seller.id);
+    assert_eq!((order.items[0].product_id, order.items[0].quantity), (lamp.id, 3));
+    assert_eq!(stored("In Dispute").status, OrderStatus::InDispute);
+    assert_eq!(stored("canceled").status, OrderStatus::Cancelled);
+    assert_eq!(stored("lost in the mail").status, OrderStatus::Cancelled);
+}
+
+#[test]
+fn upgrade_cancels_first_release_orders_still_pending_and_restocks_them() {
+    let seller = register_as(SELLER, Role::Seller);
+    let buyer = register_as(BUYER, Role::Buyer);
+    // The first release took the stock out when the order was placed
+    let desk = Product { id: 100, name: "Desk".to_string(), seller_id: seller.id, price: 5_000, stock_quantity: 7, ..Product::default() };
+    do_insert_product(&desk);
+    let stored = |id, status: &str| {
+        let order = FirstReleaseOrder {
+            id,
+            product_id: desk.id,
+            buyer_id: buyer.id,
+            quantity: 3,
+            total_price: 1,
+            status: status.to_string(),
+            created_at: 0,
+            updated_at: None,
+        };
+        let order = Order::from_bytes(Cow::Owned(candid::encode_one(order).expect("order encodes")));
+        ORDERS_STORAGE.with(|orders| orders.borrow_mut().insert(id, order));
+    };
+    stored(100, "pending");
+    stored(101, "shipped");
+    let priced = as_caller(BUYER, || {
+        create_order(OrderPayload { product_id: desk.id, variant_id: None, quantity: 1, expected_total: None })
+    })
+    .expect("order is placed");
+
+    set_schema_status(SchemaStatus::default());
+    assert!(start_migration());
+    run_migration_batch();
+
+    let cancelled = _get_order(&100).expect("order is kept");
+    assert_eq!(cancelled.status, OrderStatus::Cancelled);
+    assert_eq!(cancelled.status_history.last().map(|change| change.changed_by), Some(None));
+    assert_eq!(_get_order(&101).expect("order is kept").status, OrderStatus::Shipped);
+    assert_eq!(_get_order(&priced.id).expect("order is kept").status, OrderStatus::Pending);
+    let stock = view_stock(desk.id).expect("product exists");
+    assert_eq!((stock.available, stock.reserved), (9, 1));
+    assert!(as_caller(SELLER, || reconcile_stock(desk.id)).expect("owner can reconcile").consistent);
+}
+
+#[test]
+fn first_release_users_wait_for_a_principal() {
+    register_admin();
+    let legacy = FirstReleaseUser {
+        id: 42,
+        name: "Legacy".to_string(),
+        email: "legacy@example.com".to_string(),
+        role: "seller".to_string(),
+        reputation: 100,
+        created_at: 0,
+        updated_at: None,
+    };
+    let user = User::from_bytes(Cow::Owned(candid::encode_one(legacy).expect("user encodes")));
+    assert_eq!((user.role, user.principal), (Role::Seller, Principal::anonymous()));
+    do_insert_user(&user);
+
+    assert!(matches!(as_caller(SELLER, || assign_user_principal(42, SELLER)), Err(Error::Unauthorized { .. })));
+    let claimed = as_caller(ADMIN, || assign_user_principal(42, SELLER)).expect("admins can assign principals");
+    assert_eq!(claimed.principal, SELLER);
+    assert_eq!(_get_user_id_by_principal(&SELLER), Some(42));
+    assert!(matches!(as_caller(ADMIN, || assign_user_principal(42, BUYER)), Err(Error::Conflict { .. })));
+}
+
+// Split escrows as stored before payout transfers were kept on them
+#[derive(candid::CandidType)]
+struct UntrackedSplit {
+    buyer_amount: u64,
+    seller_amount: u64,
+    buyer_block: Option<u64>,
+    seller_block: Option<u64>,
+    reason: Option<String>,
+    decided_by: Option<u64>,
+}
+
+#[derive(candid::CandidType)]
+struct UntrackedSplitEscrow {
+    id: u64,
+    order_id: u64,
+    amount: u64,
+    status: EscrowStatus,
+    funding_block: Option<u64>,
+    settlement_block: Option<u64>,
+    split: Option<UntrackedSplit>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+#[test]
+fn escrows_split_before_transfers_were_kept_still_read() {
+    let stored = UntrackedSplitEscrow {
+        id: 1,
+        order_id: 2,
+        amount: 1_000_000,
+        status: EscrowStatus::Held,
+        funding_block: Some(3),
+        settlement_block: None,
+        split: Some(UntrackedSplit {
+            buyer_amount: 400_000,
+            seller_amount: 600_000,
+            buyer_block: Some(4),
+            seller_block: None,
+            reason: Some("Missing item".to_string()),
+            decided_by: Some(5),
+        }),
+        created_at: 0,
+        updated_at: None,
+    };
+    let escrow = Escrow::from_bytes(Cow::Owned(schema::encode(&stored)));
+    let split = escrow.split.expect("split is kept");
+    assert_eq!((split.buyer_amount, split.buyer_block, split.seller_block), (400_000, Some(4), None));
+    assert_eq!((split.buyer_transfer, split.seller_transfer), (None, None));
+}
+
+#[test]
+fn catalog_pages_follow_the_sort_index() {
+    const OTHER_SELLER: Principal = Principal::from_slice(&[0x5E, 0x12]);
+    register_as(SELLER, Role::Seller);
+    let other_seller = register_as(OTHER_SELLER, Role::Seller);
+    let prices = [3_000, 1_000, 2_000, 1_000, 5_000];
+    let products: Vec<Product> = prices.iter().map(|price| list_product(SELLER, *price, 0)).collect();
+    let chair = list_product(OTHER_SELLER, 4_000, 0);
+
+    let page = |filter: ProductFilter, direction, cursor| {
+        list_products(ProductQuery { filter, sort_by: ProductSortField::Price, direction, cursor, limit: 2 }).expect("listing succeeds")
+    };
+    let mut ids = Vec::new();
+    let mut cursor = None;
+    loop {
+        let listed = page(ProductFilter::default(), SortDirection::Ascending, cursor);
+        assert_eq!(listed.total, Some(6));
+        ids.extend(listed.products.iter().map(|product| product.id));
+        cursor = listed.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    let by_price = |id: &u64| _get_product(id).map(|product| (product.price, product.id));
+    let mut expected = ids.clone();
+    expected.sort_by_key(by_price);
+    assert_eq!(ids, expected);
+    assert_eq!(ids.len(), 6);
+
+    // Repricing moves a product, and deleting it drops it
+    let payload = ProductPayload { price: 9_000, ..product_payload(&products[1]) };
+    as_caller(SELLER, || update_product(products[1].id, payload)).expect("update succeeds");
+    as_caller(SELLER, || delete_product(products[4].id)).expect("deletion succeeds");
+    let first = page(ProductFilter::default(), SortDirection::Descending, None);
+    assert_eq!(first.products.iter().map(|product| product.price).collect::<Vec<_>>(), vec![9_000, 4_000]);
+    assert_eq!(first.total, Some(5));
+
+    let filtered = page(ProductFilter { seller_id: Some(other_seller.id), ..ProductFilter::default() }, SortDirection::Descending, None);
+    assert_eq!(filtered.products.iter().map(|product| product.id).collect::<Vec<_>>(), vec![chair.id]);
+    assert_eq!((filtered.total, filtered.next_cursor), (Some(1), None));
+
+    // Price ranges are counted from the price index; stock has no index to count from
+    let priced = || ProductFilter { min_price: Some(2_000), max_price: Some(4_000), ..ProductFilter::default() };
+    assert_eq!(page(priced(), SortDirection::Ascending, None).total, Some(3));
+    let priced_seller = ProductFilter { seller_id: Some(other_seller.id), ..priced() };
+    assert_eq!(page(priced_seller, SortDirection::Ascending, None).total, None);
+    let stocked = ProductFilter { in_stock: Some(true), ..ProductFilter::default() };
+    assert_eq!(page(stocked, SortDirection::Ascending, None).total, None);
+}
+
+fn list_described(seller: Principal, name: &str, description: &str) -> Product {
+    as_caller(seller, || {
+        let payload = ProductPayload {
+            name: name.to_string(),
+            description: description.to_string(),
+            price: 1_000,
+            ..ProductPayload::default()
+        };
+        create_product(payload, 10)
+    })
+    .expect("product is listed")
+}
+
+fn search_ids(query: &str) -> Vec<u64> {
+    let page = search_products(query.to_string(), None, 10).expect("search succeeds");
+    page.products.iter().map(|product| product.id).collect()
+}
+
+#[test]
+fn search_needs_every_term_and_matches_prefixes() {
+    register_as(SELLER, Role::Seller);
+    let red_lamp = list_described(SELLER, "Red desk lamp", "Bright and adjustable");
+    let red_chair = list_described(SELLER, "Red chair", "Solid oak");
+    let lampshade = list_described(SELLER, "Lampshade", "Blue linen");
+
+    assert_eq!(search_ids("red lamp"), vec![red_lamp.id]);
+    assert_eq!(search_ids("RED"), {
+        let mut ids = vec![red_lamp.id, red_chair.id];
+        ids.sort_by(|a, b| b.cmp(a));
+        ids
+    });
+    assert!(search_ids("lamp").contains(&lampshade.id));
+    assert_eq!(search_ids("lin blu"), vec![lampshade.id]);
+    assert!(search_ids("red sofa").is_empty());
+    assert!(matches!(search_products("  ".to_string(), None, 10), Err(Error::InvalidInput { .. })));
+
+    // Renaming takes the old words out of the index
+    let payload = ProductPayload { name: "Green chair".to_string(), ..product_payload(&red_chair) };
+    as_caller(SELLER, || update_product(red_chair.id, payload)).expect("update succeeds");
+    assert_eq!(search_ids("red"), vec![red_lamp.id]);
+}
+
+#[test]
+fn short_and_overly_broad_search_terms_are_bounded() {
+    register_as(SELLER, Role::Seller);
+    let tv = list_described(SELLER, "Tv stand", "Oak");
+    list_described(SELLER, "Tvsets", "Refurbished");
+
+    // Terms below the prefix length only match whole words
+    assert_eq!(search_ids("tv"), vec![tv.id]);
+    assert!(search_ids("st").is_empty());
+    assert_eq!(search_ids("sta"), vec![tv.id]);
+
+    // A term matching more index entries than may be scanned is refused
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for product_id in 1_000..1_000 + MAX_SEARCH_TERM_SCAN as u64 {
+            index.insert((SearchTerm("standard".to_string()), product_id), 1);
+        }
+    });
+    let result = search_products("sta".to_string(), None, 10);
+    assert!(matches!(result, Err(Error::InvalidInput { fields, .. }) if fields[0].field == "query"));
+}
+
+#[test]
+fn search_ranks_names_and_whole_words_first() {
+    register_as(SELLER, Role::Seller);
+    let described = list_described(SELLER, "Reading light", "A small lamp for the nightstand");
+    let prefixed = list_described(SELLER, "Lampshade", "Blue linen");
+    let named = list_described(SELLER, "Lamp", "Brass");
+
+    // Whole word in the name, then a prefix of a word in the name, then the description
+    assert_eq!(search_ids("lamp"), vec![named.id, prefixed.id, described.id]);
+    let page = search_products("lamp".to_string(), None, 2).expect("search succeeds");
+    assert_eq!(page.total, Some(3));
+    let rest = search_products("lamp".to_string(), page.next_cursor, 2).expect("search succeeds");
+    assert_eq!(rest.products.iter().map(|product| product.id).collect::<Vec<_>>(), vec![described.id]);
+    assert_eq!(rest.next_cursor, None);
+}
+
+// Fetches `url` the way an HTTP gateway does, repeating the request as an update call when
+// the query asks for it
+fn get(url: &str) -> HttpResponse {
+    let request = || HttpRequest { method: "GET".to_string(), url: url.to_string(), headers: vec![], body: vec![] };
+    let response = http_request(request());
+    match response.upgrade {
+        Some(true) => http_request_update(request()),
+        _ => response,
+    }
+}
+
+fn json_body(response: &HttpResponse) -> serde_json::Value {
+    serde_json::from_slice(&response.body).expect("body is JSON")
+}
+
+#[test]
+fn http_routes_reads_and_maps_errors_to_status_codes() {
+    let seller = register_as(SELLER, Role::Seller);
+    let product = list_product(SELLER, 1_000, 0);
+    list_product(SELLER, 2_000, 0);
+
+    let response = get(&format!("/products/{}", product.id));
+    assert_eq!(response.status_code, 200);
+    assert_eq!(json_body(&response)["id"], product.id);
+
+    let response = get(&format!("/sellers/{}", seller.id));
+    assert_eq!(response.status_code, 200);
+    assert_eq!(json_body(&response)["product_count"], 2);
+
+    let response = get("/products?sort=price&order=asc&limit=1");
+    assert_eq!(response.status_code, 200);
+    let page = json_body(&response);
+    assert_eq!(page["products"][0]["id"], product.id);
+    assert_eq!(page["total"], 2);
+    assert!(page["next_cursor"].is_string());
+
+    let response = get("/products/999");
+    assert_eq!(response.status_code, 404);
+    assert_eq!(json_body(&response)["code"], "NOT_FOUND");
+    let response = get("/products/abc");
+    assert_eq!(response.status_code, 400);
+    assert_eq!(json_body(&response)["code"], "INVALID_INPUT");
+    assert_eq!(get("/products?sort=name").status_code, 400);
+    assert_eq!(get("/products?limit=ten").status_code, 400);
+    assert_eq!(get(&format!("/sellers/{}", register_as(BUYER, Role::Buyer).id)).status_code, 404);
+    assert_eq!(get("/media/1").status_code, 404);
+    assert_eq!(get("/orders").status_code, 404);
+
+    let post = http_request(HttpRequest { method: "POST".to_string(), url: "/products".to_string(), headers: vec![], body: vec![] });
+    assert_eq!(post.status_code, 405);
+
+    // JSON is only served by the update call, whose response the subnet signs; images are not
+    let query = |url: &str| http_request(HttpRequest { method: "GET".to_string(), url: url.to_string(), headers: vec![], body: vec![] });
+    for url in ["/products", &format!("/products/{}", product.id), "/categories", "/orders"] {
+        let response = query(url);
+        assert_eq!((response.upgrade, response.body.is_empty()), (Some(true), true));
+    }
+    assert_eq!(query("/media/1").upgrade, None);
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// A PNG file of `size` bytes: the signature followed by filler
+fn png_bytes(size: usize) -> Vec<u8> {
+    let mut bytes = PNG_SIGNATURE.to_vec();
+    bytes.resize(size, 0x42);
+    bytes
+}
+
+fn begin_upload(product: &Product, content_type: &str, size: usize) -> MediaAsset {
+    let payload = MediaUploadPayload {
+        content_type: content_type.to_string(),
+        size: size as u64,
+        width: 64,
+        height: 64,
+        alt_text: "Front view".to_string(),
+        thumbnail_of: None,
+    };
+    as_caller(SELLER, || begin_media_upload(product.id, payload)).expect("upload begins")
+}
+
+#[test]
+fn media_is_uploaded_in_chunks_and_served_whole() {
+    register_as(SELLER, Role::Seller);
+    let product = list_product(SELLER, 1_000, 0);
+    let bytes = png_bytes(MEDIA_CHUNK_BYTES + 10);
+    let asset = begin_upload(&product, "image/png", bytes.len());
+    let (first, last) = bytes.split_at(MEDIA_CHUNK_BYTES);
+
+    as_caller(SELLER, || {
+        assert!(matches!(upload_media_chunk(asset.id, 0, last.to_vec()), Err(Error::InvalidInput { .. })));
+        assert!(matches!(upload_media_chunk(asset.id, 2, last.to_vec()), Err(Error::InvalidInput { .. })));
+        upload_media_chunk(asset.id, 1, last.to_vec()).expect("last chunk is stored");
+        assert!(matches!(finish_media_upload(asset.id), Err(Error::InvalidInput { .. })));
+    });
+    assert!(matches!(as_caller(BUYER, || upload_media_chunk(asset.id, 0, first.to_vec())), Err(Error::Unauthorized { .. })));
+    assert!(matches!(get_media_chunk(asset.id, 1), Err(Error::NotFound { .. })));
+
+    let ready = as_caller(SELLER, || {
+        upload_media_chunk(asset.id, 0, first.to_vec()).expect("first chunk is stored");
+        finish_media_upload(asset.id)
+    })
+    .expect("upload finishes");
+    assert_eq!(ready.status, MediaStatus::Ready);
+    assert_eq!(view_product(product.id).expect("product exists").media_ids, vec![asset.id]);
+    assert_eq!(get_media_chunk(asset.id, 1).expect("chunk exists"), last);
+
+    let response = get(&format!("/media/{}", asset.id));
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.body, bytes);
+    assert!(matches!(as_caller(SELLER, || upload_media_chunk(asset.id, 1, last.to_vec())), Err(Error::Conflict { .. })));
+}
+
+#[test]
+fn media_must_carry_the_signature_of_its_declared_type() {
+    register_as(SELLER, Role::Seller);
+    let product = list_product(SELLER, 1_000, 0);
+    let bytes = png_bytes(100);
+
+    let mislabeled = begin_upload(&product, "image/jpeg", bytes.len());
+    let result = as_caller(SELLER, || {
+        upload_media_chunk(mislabeled.id, 0, bytes.clone()).expect("chunk is stored");
+        finish_media_upload(mislabeled.id)
+    });
+    assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    assert_eq!(view_media(mislabeled.id).expect("asset exists").status, MediaStatus::Uploading);
+    assert_eq!(get(&format!("/media/{}", mislabeled.id)).status_code, 404);
+
+    let png = begin_upload(&product, "image/png", bytes.len());
+    let result = as_caller(SELLER, || {
+        upload_media_chunk(png.id, 0, bytes.clone()).expect("chunk is stored");
+        finish_media_upload(png.id)
+    });
+    assert_eq!(result.expect("upload finishes").status, MediaStatus::Ready);
+
+    let payload = MediaUploadPayload { content_type: "image/svg+xml".to_string(), size: 100, ..MediaUploadPayload::default() };
+    assert!(matches!(as_caller(SELLER, || begin_media_upload(product.id, payload)), Err(Error::InvalidInput { .. })));
+}
+
+fn cbor_bytes(value: &serde_cbor::Value) -> &[u8] {
+    match value {
+        serde_cbor::Value::Bytes(bytes) => bytes,
+        other => panic!("Expected bytes in the witness, got {:?}", other),
+    }
+}
+
+fn hash_node(domain: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// Root hash of a witness, computed as a verifier would, together with the leaf it proves at
+// `path` if there is one
+fn reconstruct(tree: &serde_cbor::Value, path: &[&[u8]]) -> ([u8; 32], Option<Vec<u8>>) {
+    use serde_cbor::Value;
+    let Value::Array(node) = tree else {
+        panic!("Expected a hash tree node, got {:?}", tree);
+    };
+    match node.as_slice() {
+        [Value::Integer(0)] => (hash_node("ic-hashtree-empty", &[]), None),
+        [Value::Integer(1), left, right] => {
+            let (left_hash, left_leaf) = reconstruct(left, path);
+            let (right_hash, right_leaf) = reconstruct(right, path);
+            (hash_node("ic-hashtree-fork", &[&left_hash, &right_hash]), left_leaf.or(right_leaf))
+        }
+        [Value::Integer(2), label, subtree] => {
+            let label = cbor_bytes(label);
+            let rest = match path.split_first() {
+                Some((first, rest)) if *first == label => Some(rest),
+                _ => None,
+            };
+            let (hash, leaf) = reconstruct(subtree, rest.unwrap_or(&[b"" as &[u8], b""]));
+            (hash_node("ic-hashtree-labeled", &[label, &hash]), rest.and(leaf))
+        }
+        [Value::Integer(3), value] => {
+            let value = cbor_bytes(value);
+            (hash_node("ic-hashtree-leaf", &[value]), path.is_empty().then(|| value.to_vec()))
+        }
+        [Value::Integer(4), hash] => (cbor_bytes(hash).try_into().expect("pruned hashes are 32 bytes"), None),
+        other => panic!("Unknown hash tree node {:?}", other),
+    }
+}
+
+// The witness of record `id` under `label` proves `record`, or its absence, against the
+// published root
+fn assert_certified<T: CandidType>(label: &'static str, id: u64, record: Option<&T>) {
+    let witness = certified::witness(label, id).expect("witness encodes");
+    assert!(witness.starts_with(&[0xD9, 0xD9, 0xF7]), "witness is self-described CBOR");
+    let tree = serde_cbor::from_slice(&witness).expect("witness is CBOR");
+    let (root, leaf) = reconstruct(&tree, &[label.as_bytes(), &id.to_be_bytes()]);
+    assert_eq!(root.to_vec(), system::certified_data(), "root of {} {}", label, id);
+    let expected = record.map(|record| sha2::Sha256::digest(candid::encode_one(record).expect("record encodes")).to_vec());
+    assert_eq!(leaf, expected, "leaf of {} {}", label, id);
+}
+
+fn assert_product_certified(product_id: u64) {
+    assert_certified(certified::PRODUCTS, product_id, _get_product(&product_id).as_ref());
+}
+
+fn assert_order_certified(order_id: u64) {
+    assert_certified(certified::ORDERS, order_id, _get_order(&order_id).as_ref());
+}
+
+#[test]
+fn every_product_write_keeps_its_witness_current() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    let product_id = _get_order(&market.order_id).expect("order exists").items[0].product_id;
+    assert_product_certified(product_id);
+
+    let product = _get_product(&product_id).expect("product exists");
+    let payload = ProductPayload { name: "Floor lamp".to_string(), ..product_payload(&product) };
+    as_caller(SELLER, || update_product(product_id, payload)).expect("update succeeds");
+    assert_product_certified(product_id);
+
+    as_caller(SELLER, || manage_inventory(product_id, None, 5, StockAdjustmentReason::Restock)).expect("restock succeeds");
+    assert_product_certified(product_id);
+
+    let bytes = png_bytes(100);
+    let asset = begin_upload(&product, "image/png", bytes.len());
+    as_caller(SELLER, || {
+        upload_media_chunk(asset.id, 0, bytes).expect("chunk is stored");
+        finish_media_upload(asset.id).expect("upload finishes");
+    });
+    assert_product_certified(product_id);
+    as_caller(SELLER, || delete_media(asset.id)).expect("media is deleted");
+    assert_product_certified(product_id);
+
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("order completes");
+    let review = ReviewPayload { order_id: market.order_id, product_id, variant_id: None, rating: 4, text: "Bright".to_string() };
+    as_caller(BUYER, || create_review(review)).expect("review is posted");
+    assert_product_certified(product_id);
+
+    let second = as_caller(BUYER, || {
+        create_order(OrderPayload { product_id, variant_id: None, quantity: 2, expected_total: None })
+    })
+    .expect("order is placed");
+    assert_product_certified(product_id);
+    as_caller(BUYER, || block_on(cancel_order(second.id))).expect("order is cancelled");
+    assert_product_certified(product_id);
+
+    let variant_payload = VariantPayload {
+        sku: "LAMP-RED".to_string(),
+        attributes: vec![VariantAttribute { name: "colour".to_string(), value: "red".to_string() }],
+        price: None,
+    };
+    let stock = view_stock(product_id).expect("product exists").available as i64;
+    as_caller(SELLER, || manage_inventory(product_id, None, -stock, StockAdjustmentReason::Shrinkage)).expect("shrinkage succeeds");
+    assert_product_certified(product_id);
+    let variant = as_caller(SELLER, || add_variant(product_id, variant_payload, 3)).expect("variant is added");
+    assert_product_certified(product_id);
+    as_caller(SELLER, || remove_variant(product_id, variant.id)).expect("variant is removed");
+    assert_product_certified(product_id);
+
+    as_caller(SELLER, || delete_product(product_id)).expect("product is deleted");
+    assert_product_certified(product_id);
+}
+
+#[test]
+fn every_order_write_keeps_its_witness_current() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    assert_order_certified(market.order_id);
+    pay(&market).expect("payment succeeds");
+    assert_order_certified(market.order_id);
+    as_caller(SELLER, || ship_order(market.order_id)).expect("shipping succeeds");
+    assert_order_certified(market.order_id);
+    as_caller(SELLER, || mark_order_delivered(market.order_id)).expect("delivery succeeds");
+    assert_order_certified(market.order_id);
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("order completes");
+    assert_order_certified(market.order_id);
+
+    let product_id = _get_order(&market.order_id).expect("order exists").items[0].product_id;
+    let place = || {
+        as_caller(BUYER, || create_order(OrderPayload { product_id, variant_id: None, quantity: 1, expected_total: None }))
+            .expect("order is placed")
+    };
+    let cancelled = place();
+    as_caller(BUYER, || block_on(cancel_order(cancelled.id))).expect("order is cancelled");
+    assert_order_certified(cancelled.id);
+
+    let expired = place();
+    system::advance_time(Duration::from_secs(24 * 60 * 60));
+    expire_reservation(expired.id);
+    assert_order_certified(expired.id);
+
+    let deleted = place();
+    as_caller(BUYER, || delete_order(deleted.id)).expect("order is deleted");
+    assert_order_certified(deleted.id);
+    assert_order_certified(market.order_id);
+}
+
+const ARBITER: Principal = Principal::from_slice(&[0xA5, 0x01]);
+
+fn register_arbiter() -> User {
+    let arbiter = register_as(ARBITER, Role::Buyer);
+    as_caller(system::CONTROLLER, || set_user_role(arbiter.id, Role::Arbiter)).expect("controllers can grant roles")
+}
+
+// A paid and shipped order over which the buyer opened a dispute
+fn disputed_market() -> (Market, Dispute) {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    as_caller(SELLER, || ship_order(market.order_id)).expect("shipping succeeds");
+    let payload = DisputePayload {
+        order_id: market.order_id,
+        reason: DisputeReason::NotReceived,
+        description: "Nothing arrived".to_string(),
+        evidence: vec!["https://example.com/tracking".to_string()],
+    };
+    let dispute = as_caller(BUYER, || open_dispute(payload)).expect("dispute is opened");
+    (market, dispute)
+}
+
+fn message(body: &str) -> DisputeMessagePayload {
+    DisputeMessagePayload { body: body.to_string(), evidence: vec![] }
+}
+
+#[test]
+fn dispute_holds_the_order_until_it_is_split() {
+    let (market, dispute) = disputed_market();
+    register_arbiter();
+    assert_eq!(dispute.status, DisputeStatus::AwaitingResponse);
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::InDispute));
+    let again = DisputePayload { order_id: market.order_id, reason: DisputeReason::Other, description: "Again".to_string(), evidence: vec![] };
+    assert!(matches!(as_caller(SELLER, || open_dispute(again)), Err(Error::Conflict { .. })));
+    assert!(as_caller(BUYER, || block_on(complete_order(market.order_id))).is_err());
+
+    // Nothing is decided before the seller had their say
+    let result = as_caller(ARBITER, || block_on(resolve_dispute(dispute.id, DisputeResolution::RefundBuyer)));
+    assert!(matches!(result, Err(Error::Conflict { .. })));
+    let answered = as_caller(SELLER, || add_dispute_message(dispute.id, message("Shipped on Monday"))).expect("seller responds");
+    assert_eq!(answered.status, DisputeStatus::UnderReview);
+    assert_eq!(answered.messages.len(), 2);
+
+    let escrow_amount = order_escrow(&market).amount;
+    for refund_amount in [0, escrow_amount] {
+        let result = as_caller(ARBITER, || block_on(resolve_dispute(dispute.id, DisputeResolution::Split { refund_amount })));
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+    let refund_amount = escrow_amount / 4;
+    let resolved = as_caller(ARBITER, || block_on(resolve_dispute(dispute.id, DisputeResolution::Split { refund_amount })))
+        .expect("dispute is resolved");
+    assert_eq!(resolved.status, DisputeStatus::Resolved);
+    assert_eq!(resolved.resolution, Some(DisputeResolution::Split { refund_amount }));
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::Completed));
+
+    let escrow = order_escrow(&market);
+    assert_eq!(escrow.status, EscrowStatus::Split);
+    let split = escrow.split.expect("split is recorded");
+    assert_eq!((split.buyer_amount, split.seller_amount), (refund_amount, escrow_amount - refund_amount));
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), escrow_amount - refund_amount - MOCK_LEDGER_FEE);
+    assert_eq!(MockLedger::balance_of(&escrow_account(market.order_id)), 0);
+    let result = as_caller(BUYER, || add_dispute_message(dispute.id, message("Thanks")));
+    assert!(matches!(result, Err(Error::Conflict { .. })));
+}
+
+#[test]
+fn silent_party_is_decided_without_once_the_response_window_passes() {
+    let (market, dispute) = disputed_market();
+    register_admin();
+    let outsider = Principal::from_slice(&[0x0E, 0x01]);
+    register_as(outsider, Role::Buyer);
+    assert!(matches!(as_caller(outsider, || add_dispute_message(dispute.id, message("Me too"))), Err(Error::Unauthorized { .. })));
+
+    system::advance_time(Duration::from_secs(DISPUTE_RESPONSE_WINDOW_SECS + 1));
+    let resolved = as_caller(ADMIN, || block_on(resolve_dispute(dispute.id, DisputeResolution::RefundBuyer))).expect("dispute is resolved");
+    assert_eq!(resolved.status, DisputeStatus::Resolved);
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::Refunded));
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Refunded);
+    let result = as_caller(ADMIN, || block_on(resolve_dispute(dispute.id, DisputeResolution::ReleaseToSeller)));
+    assert!(matches!(result, Err(Error::Conflict { .. })));
+}
+
+#[test]
+fn disputes_past_their_resolution_deadline_are_listed_as_overdue() {
+    let (_, dispute) = disputed_market();
+    let arbiter = register_arbiter();
+    let overdue_ids = |overdue| {
+        let page = as_caller(ARBITER, || list_disputes(None, None, overdue, None, 10)).expect("arbiters list disputes");
+        page.disputes.iter().map(|dispute| dispute.id).collect::<Vec<_>>()
+    };
+    assert_eq!(overdue_ids(Some(true)), Vec::<u64>::new());
+    assert_eq!(overdue_ids(Some(false)), vec![dispute.id]);
+
+    as_caller(ARBITER, || assign_dispute(dispute.id, arbiter.id)).expect("arbiter takes the dispute");
+    system::advance_time(Duration::from_secs(DISPUTE_RESOLUTION_WINDOW_SECS + 1));
+    assert_eq!(overdue_ids(Some(true)), vec![dispute.id]);
+    assert_eq!(overdue_ids(None), vec![dispute.id]);
+    let page = as_caller(ARBITER, || list_disputes(None, Some(arbiter.id + 1), Some(true), None, 10)).expect("arbiters list disputes");
+    assert!(page.disputes.is_empty());
+
+    as_caller(ARBITER, || block_on(resolve_dispute(dispute.id, DisputeResolution::ReleaseToSeller))).expect("dispute is resolved");
+    assert_eq!(overdue_ids(Some(true)), Vec::<u64>::new());
+    assert!(matches!(as_caller(BUYER, || list_disputes(None, None, None, None, 10)), Err(Error::Unauthorized { .. })));
+}
+
+fn rejected_fields<T>(result: Result<T, Error>) -> Vec<String> {
+    match result {
+        Err(Error::InvalidInput { fields, .. }) => fields.into_iter().map(|field| field.field).collect(),
+        Err(other) => panic!("Expected rejected fields, got {:?}", other),
+        Ok(_) => panic!("Expected rejected fields, got a success"),
+    }
+}
+
+#[test]
+fn split_shares_must_be_positive_and_add_up_to_the_escrow() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    let escrow = order_escrow(&market);
+    let split = |buyer_amount, seller_amount, reason: &str| {
+        let payload = SplitEscrowPayload { buyer_amount, seller_amount, reason: reason.to_string() };
+        as_caller(SELLER, || block_on(split_escrow(escrow.id, payload)))
+    };
+
+    assert_eq!(rejected_fields(split(0, escrow.amount, "Missing item")), vec!["buyer_amount"]);
+    assert_eq!(rejected_fields(split(escrow.amount, 0, "Missing item")), vec!["seller_amount"]);
+    assert_eq!(rejected_fields(split(1, escrow.amount, "Missing item")), vec!["seller_amount"]);
+    assert_eq!(rejected_fields(split(u64::MAX, 1, "Missing item")), vec!["seller_amount"]);
+    assert_eq!(rejected_fields(split(1, escrow.amount - 1, " ")), vec!["reason"]);
+    let payload = SplitEscrowPayload { buyer_amount: 1, seller_amount: escrow.amount - 1, reason: "Mine".to_string() };
+    assert!(matches!(as_caller(BUYER, || block_on(split_escrow(escrow.id, payload))), Err(Error::Unauthorized { .. })));
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Held);
+
+    let buyer_amount = escrow.amount / 2;
+    let settled = split(buyer_amount, escrow.amount - buyer_amount, "One lamp arrived broken").expect("split succeeds");
+    assert_eq!(settled.status, EscrowStatus::Split);
+    let recorded = settled.split.expect("split is recorded");
+    assert_eq!(recorded.reason.as_deref(), Some("One lamp arrived broken"));
+    assert!(recorded.buyer_block.is_some() && recorded.seller_block.is_some());
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::Completed));
+    assert!(matches!(split(buyer_amount, escrow.amount - buyer_amount, "Again"), Err(Error::InvalidStateTransition { .. })));
+}
+
+#[test]
+fn transaction_history_records_funding_and_each_share() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    let escrow = order_escrow(&market);
+    let buyer_amount = 300_000;
+    let payload = SplitEscrowPayload { buyer_amount, seller_amount: escrow.amount - buyer_amount, reason: "Missing item".to_string() };
+    as_caller(SELLER, || block_on(split_escrow(escrow.id, payload))).expect("split succeeds");
+
+    let history = |principal, user_id, start_after, limit| {
+        as_caller(principal, || list_transactions(user_id, start_after, limit)).expect("history is readable")
+    };
+    let buyer_id = _get_order(&market.order_id).expect("order exists").buyer_id;
+    let seller_id = _get_order(&market.order_id).expect("order exists").seller_id;
+
+    let first = history(BUYER, buyer_id, None, 1);
+    assert_eq!(first.transactions.len(), 1);
+    let funded = &first.transactions[0];
+    assert_eq!((funded.kind, funded.amount, funded.fee), (TransactionKind::EscrowFunded, escrow.amount, None));
+    assert_eq!(funded.ledger_block, escrow.funding_block.expect("escrow is funded"));
+    let rest = history(BUYER, buyer_id, first.next_cursor, 10);
+    assert_eq!(rest.next_cursor, None);
+    let refund = &rest.transactions;
+    assert_eq!(refund.len(), 1);
+    assert_eq!((refund[0].kind, refund[0].amount, refund[0].fee), (TransactionKind::Refund, buyer_amount, Some(MOCK_LEDGER_FEE)));
+
+    let payouts = history(SELLER, seller_id, None, 10).transactions;
+    assert_eq!(payouts.len(), 1);
+    assert_eq!((payouts[0].kind, payouts[0].amount, payouts[0].order_id), (TransactionKind::Payout, escrow.amount - buyer_amount, market.order_id));
+    assert!(matches!(as_caller(SELLER, || list_transactions(buyer_id, None, 10)), Err(Error::Unauthorized { .. })));
+}
+
+#[test]
+fn delivered_order_is_released_to_the_seller_when_the_buyer_stays_silent() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    let release_at = _get_order(&market.order_id).and_then(|order| order.auto_release_at).expect("release is scheduled");
+    assert_eq!(AUTO_RELEASES.with(|releases| releases.borrow().get(&market.order_id)), Some(release_at));
+
+    system::advance_time(Duration::from_nanos(release_at - time()));
+    block_on(auto_release_escrow(market.order_id));
+    let order = _get_order(&market.order_id).expect("order exists");
+    assert_eq!(order.status, OrderStatus::Completed);
+    assert_eq!(order.auto_release_at, None);
+    assert_eq!(order.status_history.last().map(|change| change.changed_by), Some(None));
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Released);
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), market.total - MOCK_LEDGER_FEE);
+    assert!(AUTO_RELEASES.with(|releases| releases.borrow().is_empty()));
+
+    // A timer firing again finds nothing left to release
+    block_on(auto_release_escrow(market.order_id));
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), market.total - MOCK_LEDGER_FEE);
+}
+
+#[test]
+fn dispute_opened_before_the_deadline_stops_the_auto_release() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    let payload = DisputePayload {
+        order_id: market.order_id,
+        reason: DisputeReason::Damaged,
+        description: "The shade is cracked".to_string(),
+        evidence: vec![],
+    };
+    as_caller(BUYER, || open_dispute(payload)).expect("dispute is opened");
+    let order = _get_order(&market.order_id).expect("order exists");
+    assert_eq!(order.auto_release_at, None);
+    assert!(AUTO_RELEASES.with(|releases| releases.borrow().is_empty()));
+
+    system::advance_time(Duration::from_secs(DEFAULT_AUTO_RELEASE_WINDOW_SECS + 1));
+    block_on(auto_release_escrow(market.order_id));
+    assert_eq!(_get_order(&market.order_id).map(|order| order.status), Some(OrderStatus::InDispute));
+    assert_eq!(order_escrow(&market).status, EscrowStatus::Held);
+    assert_eq!(MockLedger::balance_of(&account(SELLER)), 0);
+}
+
+fn review_of(market: &Market, rating: u8) -> ReviewPayload {
+    let product_id = _get_order(&market.order_id).expect("order exists").items[0].product_id;
+    ReviewPayload { order_id: market.order_id, product_id, variant_id: None, rating, text: "Bright and sturdy".to_string() }
+}
+
+#[test]
+fn only_buyers_of_completed_orders_review_and_once_per_item() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    let outsider = Principal::from_slice(&[0x0E, 0x02]);
+    register_as(outsider, Role::Buyer);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    assert!(matches!(as_caller(BUYER, || create_review(review_of(&market, 5))), Err(Error::Conflict { .. })));
+
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("order completes");
+    assert!(matches!(as_caller(outsider, || create_review(review_of(&market, 1))), Err(Error::Unauthorized { .. })));
+    assert!(matches!(as_caller(BUYER, || create_review(review_of(&market, 6))), Err(Error::InvalidInput { .. })));
+    let other_item = ReviewPayload { product_id: 999, ..review_of(&market, 4) };
+    assert!(matches!(as_caller(BUYER, || create_review(other_item)), Err(Error::InvalidInput { .. })));
+
+    let review = as_caller(BUYER, || create_review(review_of(&market, 4))).expect("review is posted");
+    assert!(matches!(as_caller(BUYER, || create_review(review_of(&market, 5))), Err(Error::Conflict { .. })));
+    let rating = view_product(review.product_id).expect("product exists").rating;
+    assert_eq!((rating.count, rating.total, rating.histogram), (1, 4, [0, 0, 0, 1, 0]));
+    let page = list_product_reviews(review.product_id, None, 10).expect("reviews are listed");
+    assert_eq!(page.reviews.iter().map(|review| review.id).collect::<Vec<_>>(), vec![review.id]);
+}
+
+#[test]
+fn seller_replies_to_a_review_once() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("order completes");
+    let review = as_caller(BUYER, || create_review(review_of(&market, 2))).expect("review is posted");
+
+    assert!(matches!(as_caller(BUYER, || reply_to_review(review.id, "Me again".to_string())), Err(Error::Unauthorized { .. })));
+    assert!(matches!(as_caller(SELLER, || reply_to_review(review.id, " ".to_string())), Err(Error::InvalidInput { .. })));
+    let replied = as_caller(SELLER, || reply_to_review(review.id, "Sorry, a replacement is on its way".to_string())).expect("seller replies");
+    assert_eq!(replied.reply.as_ref().map(|reply| reply.text.as_str()), Some("Sorry, a replacement is on its way"));
+    let result = as_caller(SELLER, || reply_to_review(review.id, "And a voucher".to_string()));
+    assert!(matches!(result, Err(Error::Conflict { .. })));
+    let stored = view_review(review.id).expect("review exists");
+    assert_eq!(stored.reply.map(|reply| reply.text), Some("Sorry, a replacement is on its way".to_string()));
+}
+
+fn points(user_id: u64, signal: ReputationSignal) -> i64 {
+    let breakdown = view_reputation(user_id).expect("user exists");
+    breakdown.factors.iter().find(|factor| factor.signal == signal).map_or(0, |factor| factor.points)
+}
+
+#[test]
+fn reputation_counts_completed_orders_reviews_and_account_age() {
+    let market = market(1_000_000, 5_000_000, 5_000_000);
+    let order = _get_order(&market.order_id).expect("order exists");
+    let (buyer_id, seller_id) = (order.buyer_id, order.seller_id);
+    assert_eq!(view_reputation(seller_id).expect("user exists").score, 50);
+
+    pay(&market).expect("payment succeeds");
+    deliver(&market);
+    as_caller(BUYER, || block_on(complete_order(market.order_id))).expect("order completes");
+    as_caller(BUYER, || create_review(review_of(&market, 5))).expect("review is posted");
+    assert_eq!(points(seller_id, ReputationSignal::Base), 50);
+    assert_eq!(points(seller_id, ReputationSignal::CompletedOrders), 2);
+    assert_eq!(points(seller_id, ReputationSignal::ReviewScores), 20);
+    assert_eq!(points(buyer_id, ReputationSignal::ReviewScores), 0);
+    assert_eq!(view_reputation(seller_id).expect("user exists").score, 72);
+    assert_eq!(view_seller(seller_id).expect("seller exists").reputation, 72);
+
+    // Account age counts without anything being written in the meantime
+    system::advance_time(Duration::from_secs(90 * 24 * 60 * 60));
+    assert_eq!(points(seller_id, ReputationSignal::AccountAge), 3);
+    assert_eq!(view_seller(seller_id).expect("seller exists").reputation, 75);
+    assert_eq!(as_caller(BUYER, whoami).expect("buyer is registered").reputation, 55);
+    system::advance_time(Duration::from_secs(1_000 * 24 * 60 * 60));
+    assert_eq!(points(buyer_id, ReputationSignal::AccountAge), 10);
+}
+
+#[test]
+fn reputation_penalises_own_cancellations_and_lost_disputes() {
+    let (market, dispute) = disputed_market();
+    let order = _get_order(&market.order_id).expect("order exists");
+    let (buyer_id, seller_id) = (order.buyer_id, order.seller_id);
+    register_admin();
+    system::advance_time(Duration::from_secs(DISPUTE_RESPONSE_WINDOW_SECS + 1));
+    as_caller(ADMIN, || block_on(resolve_dispute(dispute.id, DisputeResolution::RefundBuyer))).expect("dispute is resolved");
+    assert_eq!(points(seller_id, ReputationSignal::DisputesLost), -10);
+    assert_eq!(points(buyer_id, ReputationSignal::DisputesLost), 0);
+
+    // The buyer backs out of one of their two orders; the seller is not to blame
+    let product_id = order.items[0].product_id;
+    let second = as_caller(BUYER, || {
+        create_order(OrderPayload { product_id, variant_id: None, quantity: 1, expected_total: None })
+    })
+    .expect("order is placed");
+    as_caller(BUYER, || block_on(cancel_order(second.id))).expect("order is cancelled");
+    let breakdown = view_reputation(buyer_id).expect("user exists");
+    assert_eq!((breakdown.cancelled_orders, breakdown.total_orders, breakdown.cancellation_rate_bps), (1, 2, 5_000));
+    assert_eq!(points(buyer_id, ReputationSignal::CancellationRate), -15);
+    assert_eq!(points(seller_id, ReputationSignal::CancellationRate), 0);
+    assert_eq!(view_reputation(seller_id).expect("user exists").score, 40);
+}
+
+#[test]
+fn reputation_counters_are_stored_and_rebuilt_after_an_upgrade() {
+    let (market, dispute) = disputed_market();
+    let order = _get_order(&market.order_id).expect("order exists");
+    let (buyer_id, seller_id) = (order.buyer_id, order.seller_id);
+    register_admin();
+    system::advance_time(Duration::from_secs(DISPUTE_RESPONSE_WINDOW_SECS + 1));
+    as_caller(ADMIN, || block_on(resolve_dispute(dispute.id, DisputeResolution::ReleaseToSeller))).expect("dispute is resolved");
+    as_caller(BUYER, || create_review(review_of(&market, 1))).expect("review is posted");
+
+    // The stored scores follow each event
+    let seller_score = view_reputation(seller_id).expect("user exists").score;
+    assert_eq!(_get_user(&seller_id).map(|user| user.reputation), Some(seller_score));
+    assert_eq!(_get_user(&buyer_id).map(|user| user.reputation), Some(view_reputation(buyer_id).expect("user exists").score));
+    assert_eq!(points(buyer_id, ReputationSignal::DisputesLost), -10);
+    assert_eq!(points(seller_id, ReputationSignal::ReviewScores), -20);
+
+    // Canisters upgraded from before the counters existed count everything once
+    let counters = |user_id| REPUTATION_COUNTERS.with(|counters| counters.borrow().get(&user_id));
+    let (buyer_counters, seller_counters) = (counters(buyer_id), counters(seller_id));
+    REPUTATION_COUNTERS.with(|counters| counters.borrow_mut().clear_new());
+    set_backfill_status(BackfillStatus::default());
+    post_upgrade(None);
+    assert_eq!(counters(buyer_id), buyer_counters);
+    assert_eq!(counters(seller_id), seller_counters);
+    assert_eq!(view_reputation(seller_id).expect("user exists").score, seller_score);
+}